@@ -0,0 +1,8 @@
+// Post-sampling analyses computed from the final model state.
+
+pub mod deduplication;
+pub mod hexbin;
+pub mod matching;
+pub mod outliers;
+pub mod ripleys;
+pub mod spatial;
@@ -0,0 +1,112 @@
+// Detecting transcripts that are likely repeated detections of the same
+// molecule.
+
+use ndarray::Array1;
+use std::collections::{HashMap, HashSet};
+
+use super::super::sampler::transcripts::Transcript;
+use super::super::sampler::voxelsampler::Voxel;
+
+// Flag transcripts that have an earlier transcript of the same gene in the
+// same voxel, where `voxels` gives each transcript's voxel, or closer than
+// `max_distance`. Transcripts are binned into cubes of that width so only
+// neighboring bins need to be compared. The first transcript of each group of
+// duplicates is left unflagged.
+pub fn find_duplicate_transcripts(
+    transcripts: &[Transcript],
+    voxels: &[Voxel],
+    max_distance: f32,
+) -> Array1<bool> {
+    let mut duplicates = Array1::from_elem(transcripts.len(), false);
+
+    let mut occupied_voxels: HashSet<(u32, Voxel)> = HashSet::new();
+    for (i, (t, &voxel)) in transcripts.iter().zip(voxels).enumerate() {
+        if !occupied_voxels.insert((t.gene, voxel)) {
+            duplicates[i] = true;
+        }
+    }
+
+    if max_distance <= 0.0 {
+        return duplicates;
+    }
+
+    let max_distance_squared = max_distance * max_distance;
+    let bin = |v: f32| (v / max_distance).floor() as i32;
+
+    let mut bins: HashMap<(u32, i32, i32, i32), Vec<usize>> = HashMap::new();
+    for (i, t) in transcripts.iter().enumerate() {
+        let (bx, by, bz) = (bin(t.x), bin(t.y), bin(t.z));
+
+        'search: for dx in -1..=1 {
+            for dy in -1..=1 {
+                for dz in -1..=1 {
+                    if let Some(others) = bins.get(&(t.gene, bx + dx, by + dy, bz + dz)) {
+                        for &j in others {
+                            let u = &transcripts[j];
+                            let d2 =
+                                (t.x - u.x).powi(2) + (t.y - u.y).powi(2) + (t.z - u.z).powi(2);
+                            if d2 < max_distance_squared {
+                                duplicates[i] = true;
+                                break 'search;
+                            }
+                        }
+                    }
+                }
+            }
+        }
+
+        bins.entry((t.gene, bx, by, bz)).or_default().push(i);
+    }
+
+    duplicates
+}
+
+#[test]
+fn duplicates_require_same_gene_and_proximity() {
+    let transcript = |x: f32, gene: u32| Transcript {
+        transcript_id: 0,
+        x,
+        y: 0.0,
+        z: 0.0,
+        gene,
+        fov: 0,
+    };
+    let transcripts = vec![
+        transcript(0.0, 0),
+        transcript(0.5, 0), // duplicate of the first
+        transcript(0.6, 1), // different gene
+        transcript(1.9, 0), // crosses a bin boundary, but close to the second
+        transcript(5.0, 0),
+    ];
+    let voxels = (0..transcripts.len() as i32)
+        .map(|i| Voxel::new(i, 0, 0))
+        .collect::<Vec<_>>();
+    let duplicates = find_duplicate_transcripts(&transcripts, &voxels, 1.5);
+    assert_eq!(duplicates.to_vec(), vec![false, true, false, true, false]);
+}
+
+#[test]
+fn duplicates_include_same_gene_in_same_voxel() {
+    let transcript = |x: f32, gene: u32| Transcript {
+        transcript_id: 0,
+        x,
+        y: 0.0,
+        z: 0.0,
+        gene,
+        fov: 0,
+    };
+    let transcripts = vec![
+        transcript(0.0, 0),
+        transcript(3.0, 1),
+        transcript(3.0, 0), // far from the first, but in the same voxel
+        transcript(5.0, 0), // in the next voxel
+    ];
+    let voxels = vec![
+        Voxel::new(0, 0, 0),
+        Voxel::new(0, 0, 0),
+        Voxel::new(0, 0, 0),
+        Voxel::new(1, 0, 0),
+    ];
+    let duplicates = find_duplicate_transcripts(&transcripts, &voxels, 1.0);
+    assert_eq!(duplicates.to_vec(), vec![false, false, true, false]);
+}
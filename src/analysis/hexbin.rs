@@ -0,0 +1,97 @@
+// Binning of 2D positions onto a regular grid of pointy-top hexagons, using
+// axial (q, r) coordinates.
+
+const SQRT3: f32 = 1.732_050_8_f32;
+
+#[derive(Clone, Copy, Debug)]
+pub struct HexBinner {
+    // Distance from hexagon center to corner.
+    size: f32,
+}
+
+impl HexBinner {
+    pub fn new(size: f32) -> Self {
+        assert!(size > 0.0, "Hex bin size must be positive");
+        HexBinner { size }
+    }
+
+    // Axial coordinates of the hexagon containing (x, y).
+    pub fn bin(&self, x: f32, y: f32) -> (i32, i32) {
+        let q = (SQRT3 / 3.0 * x - y / 3.0) / self.size;
+        let r = (2.0 / 3.0 * y) / self.size;
+        axial_round(q, r)
+    }
+
+    pub fn center(&self, q: i32, r: i32) -> (f32, f32) {
+        let (q, r) = (q as f32, r as f32);
+        (
+            self.size * (SQRT3 * q + SQRT3 / 2.0 * r),
+            self.size * (1.5 * r),
+        )
+    }
+
+    // A single integer identifying the hexagon at (q, r).
+    pub fn bin_id(q: i32, r: i32) -> u64 {
+        ((q as u32 as u64) << 32) | (r as u32 as u64)
+    }
+
+    pub fn area(&self) -> f32 {
+        1.5 * SQRT3 * self.size * self.size
+    }
+}
+
+// Round fractional axial coordinates to the nearest hexagon via cube
+// coordinates.
+fn axial_round(q: f32, r: f32) -> (i32, i32) {
+    let s = -q - r;
+    let (mut rq, mut rr, rs) = (q.round(), r.round(), s.round());
+    let (dq, dr, ds) = ((rq - q).abs(), (rr - r).abs(), (rs - s).abs());
+    if dq > dr && dq > ds {
+        rq = -rr - rs;
+    } else if dr > ds {
+        rr = -rq - rs;
+    }
+    (rq as i32, rr as i32)
+}
+
+#[test]
+fn hex_bins_round_to_nearest_center() {
+    let binner = HexBinner::new(2.5);
+    for q in -5..=5 {
+        for r in -5..=5 {
+            let (x, y) = binner.center(q, r);
+            assert_eq!(binner.bin(x, y), (q, r));
+        }
+    }
+
+    // The edge between (0, 0) and (1, 0) is halfway between their centers.
+    let (x1, _) = binner.center(1, 0);
+    assert_eq!(binner.bin(0.49 * x1, 0.0), (0, 0));
+    assert_eq!(binner.bin(0.51 * x1, 0.0), (1, 0));
+
+    // Every point is binned to the hexagon with the nearest center.
+    let neighbors = [(1, 0), (-1, 0), (0, 1), (0, -1), (1, -1), (-1, 1)];
+    for i in -40..=40 {
+        for j in -40..=40 {
+            let (x, y) = (i as f32 * 0.37, j as f32 * 0.41);
+            let (q, r) = binner.bin(x, y);
+            let d2 = |(cx, cy): (f32, f32)| (cx - x).powi(2) + (cy - y).powi(2);
+            let d = d2(binner.center(q, r));
+            for (dq, dr) in neighbors {
+                assert!(d <= d2(binner.center(q + dq, r + dr)) + 1e-4);
+            }
+        }
+    }
+}
+
+#[test]
+fn hex_bin_ids_are_distinct() {
+    let mut ids = std::collections::HashSet::new();
+    for q in -3..=3 {
+        for r in -3..=3 {
+            assert!(ids.insert(HexBinner::bin_id(q, r)));
+        }
+    }
+    assert_ne!(HexBinner::bin_id(-1, 0), HexBinner::bin_id(0, -1));
+    assert_ne!(HexBinner::bin_id(-1, -1), HexBinner::bin_id(1, 1));
+}
@@ -0,0 +1,195 @@
+// Matching cells between two segmentations, e.g. from different timepoints.
+
+use flate2::read::GzDecoder;
+use kiddo::float::kdtree::KdTree;
+use kiddo::SquaredEuclidean;
+use ndarray::Array2;
+use std::fs::File;
+
+use super::super::output::{infer_format_from_filename, OutputFormat};
+
+// Above this many cells, optimal matching is too slow and we fall back to
+// mutual nearest neighbors.
+const HUNGARIAN_MAX_CELLS: usize = 1000;
+
+// Minimum cost assignment of rows to columns. Returns the assigned column for
+// each row, which is None only when there are more rows than columns.
+pub fn hungarian(cost: &Array2<f64>) -> Vec<Option<usize>> {
+    let (nrows, ncols) = cost.dim();
+    if nrows > ncols {
+        let transposed = hungarian(&cost.t().to_owned());
+        let mut assignment = vec![None; nrows];
+        for (j, i) in transposed.iter().enumerate() {
+            if let Some(i) = i {
+                assignment[*i] = Some(j);
+            }
+        }
+        return assignment;
+    }
+
+    // Shortest augmenting path with row and column potentials, using 1-based
+    // indices with 0 as a sentinel column.
+    let (n, m) = (nrows, ncols);
+    let mut u = vec![0.0; n + 1];
+    let mut v = vec![0.0; m + 1];
+    let mut p = vec![0; m + 1];
+    let mut way = vec![0; m + 1];
+
+    for i in 1..=n {
+        p[0] = i;
+        let mut j0 = 0;
+        let mut minv = vec![f64::INFINITY; m + 1];
+        let mut used = vec![false; m + 1];
+        loop {
+            used[j0] = true;
+            let i0 = p[j0];
+            let mut delta = f64::INFINITY;
+            let mut j1 = 0;
+            for j in 1..=m {
+                if !used[j] {
+                    let cur = cost[[i0 - 1, j - 1]] - u[i0] - v[j];
+                    if cur < minv[j] {
+                        minv[j] = cur;
+                        way[j] = j0;
+                    }
+                    if minv[j] < delta {
+                        delta = minv[j];
+                        j1 = j;
+                    }
+                }
+            }
+            for j in 0..=m {
+                if used[j] {
+                    u[p[j]] += delta;
+                    v[j] -= delta;
+                } else {
+                    minv[j] -= delta;
+                }
+            }
+            j0 = j1;
+            if p[j0] == 0 {
+                break;
+            }
+        }
+
+        loop {
+            let j1 = way[j0];
+            p[j0] = p[j1];
+            j0 = j1;
+            if j0 == 0 {
+                break;
+            }
+        }
+    }
+
+    let mut assignment = vec![None; n];
+    for j in 1..=m {
+        if p[j] != 0 {
+            assignment[p[j] - 1] = Some(j - 1);
+        }
+    }
+    assignment
+}
+
+fn distance(a: (f32, f32, f32), b: (f32, f32, f32)) -> f32 {
+    ((a.0 - b.0).powi(2) + (a.1 - b.1).powi(2) + (a.2 - b.2).powi(2)).sqrt()
+}
+
+fn build_kdtree(centroids: &[(f32, f32, f32)]) -> KdTree<f32, u32, 3, 32, u32> {
+    let mut kdtree: KdTree<f32, u32, 3, 32, u32> = KdTree::with_capacity(centroids.len());
+    for (i, (x, y, z)) in centroids.iter().enumerate() {
+        if x.is_finite() && y.is_finite() && z.is_finite() {
+            kdtree.add(&[*x, *y, *z], i as u32);
+        }
+    }
+    kdtree
+}
+
+// Match cells between two sets of centroids. Pairs further apart than
+// `max_distance` are left unmatched, so cells may appear or disappear.
+pub fn match_cells(
+    centroids_a: &[(f32, f32, f32)],
+    centroids_b: &[(f32, f32, f32)],
+    max_distance: f32,
+) -> Vec<(usize, usize)> {
+    if centroids_a.is_empty() || centroids_b.is_empty() {
+        return Vec::new();
+    }
+
+    let mut matches = Vec::new();
+    if centroids_a.len().max(centroids_b.len()) <= HUNGARIAN_MAX_CELLS {
+        // Distances are capped so that distant pairs cost the same as being
+        // unmatched and don't distort the rest of the assignment.
+        let cost = Array2::from_shape_fn((centroids_a.len(), centroids_b.len()), |(i, j)| {
+            distance(centroids_a[i], centroids_b[j]).min(max_distance) as f64
+        });
+        for (i, j) in hungarian(&cost).iter().enumerate() {
+            if let Some(j) = j {
+                if distance(centroids_a[i], centroids_b[*j]) < max_distance {
+                    matches.push((i, *j));
+                }
+            }
+        }
+    } else {
+        let kdtree_a = build_kdtree(centroids_a);
+        let kdtree_b = build_kdtree(centroids_b);
+        for (i, &(x, y, z)) in centroids_a.iter().enumerate() {
+            if !(x.is_finite() && y.is_finite() && z.is_finite()) {
+                continue;
+            }
+            let j = kdtree_b.nearest_one::<SquaredEuclidean>(&[x, y, z]).item as usize;
+            let (xb, yb, zb) = centroids_b[j];
+            let i_back = kdtree_a.nearest_one::<SquaredEuclidean>(&[xb, yb, zb]).item as usize;
+            if i_back == i && distance(centroids_a[i], centroids_b[j]) < max_distance {
+                matches.push((i, j));
+            }
+        }
+    }
+
+    matches
+}
+
+// Read cell centroids from a cell metadata table written by proseg.
+pub fn read_cell_centroids_csv(path: &str) -> Vec<(f32, f32, f32)> {
+    let file = File::open(path).unwrap_or_else(|err| panic!("Unable to open {}: {}", path, err));
+    let mut rdr = match infer_format_from_filename(path) {
+        OutputFormat::CsvGz => {
+            csv::Reader::from_reader(Box::new(GzDecoder::new(file)) as Box<dyn std::io::Read>)
+        }
+        OutputFormat::Csv => csv::Reader::from_reader(Box::new(file) as Box<dyn std::io::Read>),
+        _ => panic!("Cell metadata must be in csv or csv.gz format: {}", path),
+    };
+
+    let headers = rdr.headers().unwrap().clone();
+    let find_column = |column: &str| {
+        headers
+            .iter()
+            .position(|x| x == column)
+            .unwrap_or_else(|| panic!("Column '{}' not found in {}", column, path))
+    };
+    let x_col = find_column("centroid_x");
+    let y_col = find_column("centroid_y");
+    let z_col = headers.iter().position(|x| x == "centroid_z");
+
+    rdr.records()
+        .map(|record| {
+            let record = record.unwrap();
+            (
+                record[x_col].parse::<f32>().unwrap(),
+                record[y_col].parse::<f32>().unwrap(),
+                z_col.map_or(0.0, |z_col| record[z_col].parse::<f32>().unwrap()),
+            )
+        })
+        .collect()
+}
+
+#[test]
+fn hungarian_finds_optimal_assignment() {
+    let cost =
+        Array2::from_shape_vec((3, 3), vec![4.0, 1.0, 3.0, 2.0, 0.0, 5.0, 3.0, 2.0, 2.0]).unwrap();
+    assert_eq!(hungarian(&cost), vec![Some(1), Some(0), Some(2)]);
+
+    // more rows than columns leaves the most expensive row unassigned
+    let cost = Array2::from_shape_vec((3, 2), vec![1.0, 9.0, 9.0, 1.0, 5.0, 5.0]).unwrap();
+    assert_eq!(hungarian(&cost), vec![Some(0), Some(1), None]);
+}
@@ -0,0 +1,80 @@
+// Outlier detection on cell positions.
+
+use kiddo::float::kdtree::KdTree;
+use kiddo::SquaredEuclidean;
+use rayon::prelude::*;
+
+// Lower bound on mean reachability distance, so that cells sharing a position
+// with all of their neighbors don't have an infinite local density.
+const MIN_REACHABILITY_DISTANCE: f32 = 1e-6;
+
+// Local Outlier Factor of each point using its k nearest neighbors. Scores
+// near 1 indicate density similar to neighbors, while larger scores indicate
+// points in sparser regions than their neighbors.
+pub fn local_outlier_factor(points: &[(f32, f32)], k: usize) -> Vec<f32> {
+    let n = points.len();
+    let k = k.min(n.saturating_sub(1));
+    if k == 0 {
+        return vec![1.0; n];
+    }
+
+    let mut kdtree: KdTree<f32, u32, 2, 32, u32> = KdTree::with_capacity(n);
+    for (i, (x, y)) in points.iter().enumerate() {
+        kdtree.add(&[*x, *y], i as u32);
+    }
+
+    // Nearest neighbors of each point, excluding itself, with distances.
+    let neighbors: Vec<Vec<(usize, f32)>> = points
+        .par_iter()
+        .enumerate()
+        .map(|(i, (x, y))| {
+            kdtree
+                .nearest_n::<SquaredEuclidean>(&[*x, *y], k + 1)
+                .iter()
+                .filter(|neighbor| neighbor.item as usize != i)
+                .take(k)
+                .map(|neighbor| (neighbor.item as usize, neighbor.distance.sqrt()))
+                .collect()
+        })
+        .collect();
+
+    let k_distance: Vec<f32> = neighbors
+        .iter()
+        .map(|neighbors_i| neighbors_i.last().map(|(_, d)| *d).unwrap_or(0.0))
+        .collect();
+
+    let local_reachability_density: Vec<f32> = neighbors
+        .par_iter()
+        .map(|neighbors_i| {
+            let mean_reachability = neighbors_i
+                .iter()
+                .map(|&(j, d)| d.max(k_distance[j]))
+                .sum::<f32>()
+                / neighbors_i.len() as f32;
+            1.0 / mean_reachability.max(MIN_REACHABILITY_DISTANCE)
+        })
+        .collect();
+
+    neighbors
+        .par_iter()
+        .enumerate()
+        .map(|(i, neighbors_i)| {
+            let mean_neighbor_density = neighbors_i
+                .iter()
+                .map(|&(j, _)| local_reachability_density[j])
+                .sum::<f32>()
+                / neighbors_i.len() as f32;
+            mean_neighbor_density / local_reachability_density[i]
+        })
+        .collect()
+}
+
+#[test]
+fn local_outlier_factor_flags_isolated_point() {
+    let mut points: Vec<(f32, f32)> = (0..25).map(|i| ((i % 5) as f32, (i / 5) as f32)).collect();
+    points.push((50.0, 50.0));
+
+    let lof = local_outlier_factor(&points, 5);
+    assert!(lof[25] > 2.0);
+    assert!(lof[12] < 1.5);
+}
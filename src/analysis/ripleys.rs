@@ -0,0 +1,71 @@
+// Ripley's K and L functions, measuring clustering of points relative to a
+// homogeneous Poisson process.
+
+use kiddo::float::kdtree::KdTree;
+use kiddo::SquaredEuclidean;
+use rayon::prelude::*;
+
+// Ripley's L function minus r, L(r) - r where L(r) = sqrt(K(r) / π), for
+// points in a region of the given area. Positive values indicate clustering
+// at that radius, negative values dispersion. No edge correction is applied.
+pub fn ripleys_l_minus_r(points: &[(f32, f32)], area: f32, radii: &[f32]) -> Vec<f32> {
+    let n = points.len();
+    if n < 2 || radii.is_empty() {
+        return vec![f32::NAN; radii.len()];
+    }
+
+    let mut kdtree: KdTree<f32, u32, 2, 32, u32> = KdTree::with_capacity(n);
+    for (i, (x, y)) in points.iter().enumerate() {
+        kdtree.add(&[*x, *y], i as u32);
+    }
+
+    // Count ordered pairs within each radius, querying only out to the largest.
+    let max_radius = radii.iter().cloned().fold(0.0, f32::max);
+    let pair_counts = points
+        .par_iter()
+        .enumerate()
+        .map(|(i, (x, y))| {
+            let mut counts = vec![0u64; radii.len()];
+            for neighbor in
+                kdtree.within_unsorted::<SquaredEuclidean>(&[*x, *y], max_radius * max_radius)
+            {
+                if neighbor.item == i as u32 {
+                    continue;
+                }
+                let d = neighbor.distance.sqrt();
+                for (count, &r) in counts.iter_mut().zip(radii) {
+                    if d <= r {
+                        *count += 1;
+                    }
+                }
+            }
+            counts
+        })
+        .reduce(
+            || vec![0u64; radii.len()],
+            |a, b| a.iter().zip(&b).map(|(a, b)| a + b).collect(),
+        );
+
+    let λ_inv = area as f64 / (n as f64 * (n - 1) as f64);
+    pair_counts
+        .iter()
+        .zip(radii)
+        .map(|(&count, &r)| {
+            let k = λ_inv * count as f64;
+            ((k / std::f64::consts::PI).sqrt() - r as f64) as f32
+        })
+        .collect()
+}
+
+#[test]
+fn ripleys_l_on_unit_square() {
+    let points = [(0.0, 0.0), (1.0, 0.0), (0.0, 1.0), (1.0, 1.0)];
+    let l = ripleys_l_minus_r(&points, 1.0, &[0.5, 1.0, 1.5]);
+
+    // no pairs within 0.5, each point has 2 neighbors within 1, and all 3
+    // within 1.5
+    let expected = |pairs: f64, r: f64| ((pairs / 12.0 / std::f64::consts::PI).sqrt() - r) as f32;
+    assert_eq!(l[0], expected(0.0, 0.5));
+    assert!((l[1] - expected(8.0, 1.0)).abs() < 1e-6);
+    assert!((l[2] - expected(12.0, 1.5)).abs() < 1e-6);
+}
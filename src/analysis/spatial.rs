@@ -0,0 +1,539 @@
+use geo::{ConvexHull, MultiPoint, MultiPolygon, Point};
+use kiddo::float::kdtree::KdTree;
+use kiddo::SquaredEuclidean;
+use ndarray::Array2;
+use rand::seq::SliceRandom;
+use rand::thread_rng;
+use rayon::prelude::*;
+use std::collections::HashMap;
+
+// Number of terms in a full degree 2 polynomial in x, y, z.
+const NUM_QUADRATIC_TERMS: usize = 10;
+
+// Small ridge penalty to keep the normal equations well conditioned when
+// cells are nearly coplanar (e.g. single layer data with constant z).
+const QUADRATIC_TREND_RIDGE: f64 = 1e-6;
+
+// Number of nearest neighbors each cell is given equal spatial weight with
+// when computing Lee's L.
+const LEE_L_NEIGHBORS: usize = 8;
+
+// Number of random permutations of cell positions used to compute Lee's L
+// p-values.
+const LEE_L_PERMUTATIONS: usize = 99;
+
+pub struct SpatialVariability {
+    pub total_cv2: f32,
+    pub spatial_cv2: f32,
+    pub residual_cv2: f32,
+    pub spatial_fraction: f32,
+}
+
+pub struct SpatialCrossCorrelation {
+    pub lee_l: f32,
+    pub p_value: f32,
+}
+
+// Quadratic design matrix row with centered and scaled coordinates.
+fn quadratic_terms(x: f64, y: f64, z: f64) -> [f64; NUM_QUADRATIC_TERMS] {
+    [1.0, x, y, z, x * x, y * y, z * z, x * y, x * z, y * z]
+}
+
+// Center and scale each coordinate axis so the polynomial terms are of
+// comparable magnitude.
+fn standardize_centroids(cell_centroids: &[(f32, f32, f32)]) -> Vec<(f64, f64, f64)> {
+    let n = cell_centroids.len().max(1) as f64;
+    let mut μ = (0.0, 0.0, 0.0);
+    for &(x, y, z) in cell_centroids {
+        μ.0 += x as f64;
+        μ.1 += y as f64;
+        μ.2 += z as f64;
+    }
+    μ = (μ.0 / n, μ.1 / n, μ.2 / n);
+
+    let mut σ2 = (0.0, 0.0, 0.0);
+    for &(x, y, z) in cell_centroids {
+        σ2.0 += (x as f64 - μ.0).powi(2);
+        σ2.1 += (y as f64 - μ.1).powi(2);
+        σ2.2 += (z as f64 - μ.2).powi(2);
+    }
+    let scale = |v: f64| {
+        let σ = (v / n).sqrt();
+        if σ > 0.0 {
+            σ
+        } else {
+            1.0
+        }
+    };
+    let σ = (scale(σ2.0), scale(σ2.1), scale(σ2.2));
+
+    cell_centroids
+        .iter()
+        .map(|&(x, y, z)| {
+            (
+                (x as f64 - μ.0) / σ.0,
+                (y as f64 - μ.1) / σ.1,
+                (z as f64 - μ.2) / σ.2,
+            )
+        })
+        .collect()
+}
+
+// Solve A x = b in place by Gaussian elimination with partial pivoting.
+#[allow(clippy::needless_range_loop)]
+fn solve_linear_system<const N: usize>(mut a: [[f64; N]; N], mut b: [f64; N]) -> [f64; N] {
+    for k in 0..N {
+        let p = (k..N)
+            .max_by(|&i, &j| a[i][k].abs().partial_cmp(&a[j][k].abs()).unwrap())
+            .unwrap();
+        a.swap(k, p);
+        b.swap(k, p);
+
+        if a[k][k] == 0.0 {
+            continue;
+        }
+
+        for i in (k + 1)..N {
+            let f = a[i][k] / a[k][k];
+            for j in k..N {
+                a[i][j] -= f * a[k][j];
+            }
+            b[i] -= f * b[k];
+        }
+    }
+
+    let mut x = [0.0; N];
+    for k in (0..N).rev() {
+        if a[k][k] == 0.0 {
+            continue;
+        }
+        let mut s = b[k];
+        for j in (k + 1)..N {
+            s -= a[k][j] * x[j];
+        }
+        x[k] = s / a[k][k];
+    }
+    x
+}
+
+// For each gene, fit a degree 2 polynomial in cell centroid position to the
+// expected counts, and decompose the squared coefficient of variation into
+// the part explained by the spatial trend and the residual.
+pub fn spatial_variability_decomposition(
+    expected_counts: &Array2<f32>, // [ngenes, ncells]
+    cell_centroids: &[(f32, f32, f32)],
+) -> Vec<SpatialVariability> {
+    let ncells = expected_counts.shape()[1];
+    assert!(ncells == cell_centroids.len());
+
+    let design: Vec<[f64; NUM_QUADRATIC_TERMS]> = standardize_centroids(cell_centroids)
+        .iter()
+        .map(|&(x, y, z)| quadratic_terms(x, y, z))
+        .collect();
+
+    let mut xtx = [[0.0; NUM_QUADRATIC_TERMS]; NUM_QUADRATIC_TERMS];
+    for row in &design {
+        for i in 0..NUM_QUADRATIC_TERMS {
+            for j in 0..NUM_QUADRATIC_TERMS {
+                xtx[i][j] += row[i] * row[j];
+            }
+        }
+    }
+    for (i, xtx_i) in xtx.iter_mut().enumerate().skip(1) {
+        xtx_i[i] += QUADRATIC_TREND_RIDGE * ncells as f64;
+    }
+
+    expected_counts
+        .outer_iter()
+        .into_par_iter()
+        .map(|counts| {
+            let mut xty = [0.0; NUM_QUADRATIC_TERMS];
+            let mut mean = 0.0;
+            for (row, &y) in design.iter().zip(counts.iter()) {
+                for (a, b) in xty.iter_mut().zip(row.iter()) {
+                    *a += b * y as f64;
+                }
+                mean += y as f64;
+            }
+            mean /= ncells.max(1) as f64;
+
+            let β = solve_linear_system(xtx, xty);
+
+            let mut total_ss = 0.0;
+            let mut spatial_ss = 0.0;
+            let mut residual_ss = 0.0;
+            for (row, &y) in design.iter().zip(counts.iter()) {
+                let ŷ: f64 = row.iter().zip(β.iter()).map(|(a, b)| a * b).sum();
+                total_ss += (y as f64 - mean).powi(2);
+                spatial_ss += (ŷ - mean).powi(2);
+                residual_ss += (y as f64 - ŷ).powi(2);
+            }
+
+            let denom = ncells.max(1) as f64 * mean * mean;
+            if denom > 0.0 {
+                let total_cv2 = total_ss / denom;
+                let spatial_cv2 = spatial_ss / denom;
+                let residual_cv2 = residual_ss / denom;
+                SpatialVariability {
+                    total_cv2: total_cv2 as f32,
+                    spatial_cv2: spatial_cv2 as f32,
+                    residual_cv2: residual_cv2 as f32,
+                    spatial_fraction: if total_cv2 > 0.0 {
+                        (spatial_cv2 / total_cv2) as f32
+                    } else {
+                        0.0
+                    },
+                }
+            } else {
+                SpatialVariability {
+                    total_cv2: 0.0,
+                    spatial_cv2: 0.0,
+                    residual_cv2: 0.0,
+                    spatial_fraction: 0.0,
+                }
+            }
+        })
+        .collect()
+}
+
+// Indexes of the k nearest neighbors of each cell in the xy plane, not
+// including itself. Neighbors are found in 2D since in single layer data
+// every centroid has the same z, which overflows the k-d tree's buckets.
+fn nearest_neighbors(cell_centroids: &[(f32, f32, f32)], k: usize) -> Vec<Vec<u32>> {
+    let mut kdtree: KdTree<f32, u32, 2, 32, u32> = KdTree::with_capacity(cell_centroids.len());
+    for (i, (x, y, _)) in cell_centroids.iter().enumerate() {
+        kdtree.add(&[*x, *y], i as u32);
+    }
+
+    cell_centroids
+        .par_iter()
+        .enumerate()
+        .map(|(i, (x, y, _))| {
+            kdtree
+                .nearest_n::<SquaredEuclidean>(&[*x, *y], k + 1)
+                .iter()
+                .map(|neighbor| neighbor.item)
+                .filter(|&j| j != i as u32)
+                .take(k)
+                .collect()
+        })
+        .collect()
+}
+
+// Mean cosine similarity between each cell's expected counts and those of
+// its k nearest spatial neighbors. Count vectors are L2-normalized first, and
+// cells with no counts have zero similarity to everything.
+pub fn neighbor_expression_similarity(
+    expected_counts: &Array2<f32>, // [ngenes, ncells]
+    cell_centroids: &[(f32, f32, f32)],
+    k: usize,
+) -> Vec<f32> {
+    let mut normalized = expected_counts.t().to_owned();
+    normalized.outer_iter_mut().for_each(|mut counts| {
+        let norm = counts.dot(&counts).sqrt();
+        if norm > 0.0 {
+            counts /= norm;
+        }
+    });
+
+    let neighbors = nearest_neighbors(cell_centroids, k);
+    neighbors
+        .par_iter()
+        .enumerate()
+        .map(|(i, neighbors_i)| {
+            if neighbors_i.is_empty() {
+                return 0.0;
+            }
+            let counts_i = normalized.row(i);
+            neighbors_i
+                .iter()
+                .map(|&j| counts_i.dot(&normalized.row(j as usize)))
+                .sum::<f32>()
+                / neighbors_i.len() as f32
+        })
+        .collect()
+}
+
+// Center and scale values so their sum of squares equals their length.
+fn standardize(values: &[f32]) -> Vec<f64> {
+    let n = values.len().max(1) as f64;
+    let μ = values.iter().map(|&v| v as f64).sum::<f64>() / n;
+    let σ = (values.iter().map(|&v| (v as f64 - μ).powi(2)).sum::<f64>() / n).sqrt();
+    values
+        .iter()
+        .map(|&v| if σ > 0.0 { (v as f64 - μ) / σ } else { 0.0 })
+        .collect()
+}
+
+// Lee's L for standardized values, with row-standardized neighbor weights.
+// Cell i takes the values at position `order[i]`.
+fn lee_l(neighbors: &[Vec<u32>], order: &[usize], x: &[f64], y: &[f64]) -> f64 {
+    let mut l = 0.0;
+    for neighbors_i in neighbors {
+        if neighbors_i.is_empty() {
+            continue;
+        }
+        let mut lag_x = 0.0;
+        let mut lag_y = 0.0;
+        for &j in neighbors_i {
+            lag_x += x[order[j as usize]];
+            lag_y += y[order[j as usize]];
+        }
+        let w = neighbors_i.len() as f64;
+        l += (lag_x / w) * (lag_y / w);
+    }
+    l / neighbors.len().max(1) as f64
+}
+
+// Compute Lee's L bivariate spatial association statistic between pairs of
+// genes' expected counts, using the nearest neighbors of cell centroids as
+// spatial weights. P-values are two-sided and computed by permuting cell
+// positions.
+pub fn spatial_cross_correlation(
+    expected_counts: &Array2<f32>, // [ngenes, ncells]
+    cell_centroids: &[(f32, f32, f32)],
+    gene_pairs: &[(usize, usize)],
+) -> Vec<SpatialCrossCorrelation> {
+    let ncells = expected_counts.shape()[1];
+    assert!(ncells == cell_centroids.len());
+
+    let neighbors = nearest_neighbors(cell_centroids, LEE_L_NEIGHBORS);
+
+    let mut used_genes: Vec<usize> = gene_pairs.iter().flat_map(|&(a, b)| [a, b]).collect();
+    used_genes.sort();
+    used_genes.dedup();
+    let standardized: HashMap<usize, Vec<f64>> = used_genes
+        .par_iter()
+        .map(|&gene| (gene, standardize(&expected_counts.row(gene).to_vec())))
+        .collect();
+
+    let identity: Vec<usize> = (0..ncells).collect();
+    gene_pairs
+        .par_iter()
+        .map(|&(a, b)| {
+            let x = &standardized[&a];
+            let y = &standardized[&b];
+            let l = lee_l(&neighbors, &identity, x, y);
+
+            let mut rng = thread_rng();
+            let mut order = identity.clone();
+            let permuted_l: Vec<f64> = (0..LEE_L_PERMUTATIONS)
+                .map(|_| {
+                    order.shuffle(&mut rng);
+                    lee_l(&neighbors, &order, x, y)
+                })
+                .collect();
+
+            // Permuting cells preserves the non-spatial correlation between
+            // the genes, so deviations are measured from the permutation mean.
+            let permuted_mean = permuted_l.iter().sum::<f64>() / LEE_L_PERMUTATIONS as f64;
+            let extreme_count = permuted_l
+                .iter()
+                .filter(|&&l_perm| (l_perm - permuted_mean).abs() >= (l - permuted_mean).abs())
+                .count();
+
+            SpatialCrossCorrelation {
+                lee_l: l as f32,
+                p_value: (extreme_count + 1) as f32 / (LEE_L_PERMUTATIONS + 1) as f32,
+            }
+        })
+        .collect()
+}
+
+// Approximate the tissue boundary as the convex hull of transcript positions.
+pub fn tissue_boundary(transcript_positions: &[(f32, f32, f32)]) -> MultiPolygon<f32> {
+    let points: MultiPoint<f32> = transcript_positions
+        .iter()
+        .map(|&(x, y, _)| Point::new(x, y))
+        .collect();
+    MultiPolygon::new(vec![points.convex_hull()])
+}
+
+// Shannon entropy of the cluster composition of cells within `bandwidth` of
+// each point on a regular grid covering the cell centroids. Returns the
+// entropy raster, indexed [row, column] with rows increasing in y, along with
+// the xy position of the center of the first pixel. Pixels with no cells
+// nearby are NaN.
+pub fn spatial_entropy_grid(
+    cell_centroids: &[(f32, f32, f32)],
+    cluster_assignments: &[u32],
+    ncomponents: usize,
+    bandwidth: f32,
+    pixel_size: f32,
+) -> (Array2<f32>, (f32, f32)) {
+    let (xmin, xmax, ymin, ymax) = cell_centroids.iter().fold(
+        (
+            f32::INFINITY,
+            f32::NEG_INFINITY,
+            f32::INFINITY,
+            f32::NEG_INFINITY,
+        ),
+        |(xmin, xmax, ymin, ymax), &(x, y, _)| (xmin.min(x), xmax.max(x), ymin.min(y), ymax.max(y)),
+    );
+    if cell_centroids.is_empty() {
+        return (Array2::zeros((0, 0)), (0.0, 0.0));
+    }
+
+    let ncols = ((xmax - xmin) / pixel_size).floor() as usize + 1;
+    let nrows = ((ymax - ymin) / pixel_size).floor() as usize + 1;
+    let x0 = xmin + 0.5 * pixel_size;
+    let y0 = ymin + 0.5 * pixel_size;
+
+    let mut kdtree: KdTree<f32, u32, 2, 32, u32> = KdTree::with_capacity(cell_centroids.len());
+    for (i, (x, y, _)) in cell_centroids.iter().enumerate() {
+        kdtree.add(&[*x, *y], i as u32);
+    }
+
+    let mut entropy = Array2::<f32>::zeros((nrows, ncols));
+    entropy
+        .outer_iter_mut()
+        .into_par_iter()
+        .enumerate()
+        .for_each(|(i, mut row)| {
+            let mut counts = vec![0u32; ncomponents];
+            for (j, h) in row.iter_mut().enumerate() {
+                let x = x0 + j as f32 * pixel_size;
+                let y = y0 + i as f32 * pixel_size;
+
+                counts.fill(0);
+                let neighbors =
+                    kdtree.within_unsorted::<SquaredEuclidean>(&[x, y], bandwidth * bandwidth);
+                for neighbor in &neighbors {
+                    counts[cluster_assignments[neighbor.item as usize] as usize] += 1;
+                }
+
+                *h = if neighbors.is_empty() {
+                    f32::NAN
+                } else {
+                    let n = neighbors.len() as f32;
+                    -counts
+                        .iter()
+                        .filter(|&&count| count > 0)
+                        .map(|&count| {
+                            let p = count as f32 / n;
+                            p * p.ln()
+                        })
+                        .sum::<f32>()
+                };
+            }
+        });
+
+    (entropy, (x0, y0))
+}
+
+// Direction of greatest variance among a set of points (their first
+// principal component), found by power iteration on the covariance matrix.
+pub fn principal_axis(points: &[(f32, f32, f32)]) -> (f32, f32, f32) {
+    let n = points.len().max(1) as f32;
+    let mean = points.iter().fold([0.0; 3], |acc, &(x, y, z)| {
+        [acc[0] + x / n, acc[1] + y / n, acc[2] + z / n]
+    });
+
+    let mut cov = [[0.0f32; 3]; 3];
+    for &(x, y, z) in points {
+        let d = [x - mean[0], y - mean[1], z - mean[2]];
+        for i in 0..3 {
+            for j in 0..3 {
+                cov[i][j] += d[i] * d[j] / n;
+            }
+        }
+    }
+
+    // Power iteration fails when the starting vector is orthogonal to the
+    // axis, so start from several vectors and keep the one with the greatest
+    // variance.
+    let seeds = [
+        [1.0f32, 1.0, 1.0],
+        [1.0, 0.0, 0.0],
+        [0.0, 1.0, 0.0],
+        [0.0, 0.0, 1.0],
+    ];
+    let mut axis = [1.0f32, 0.0, 0.0];
+    let mut max_variance = 0.0;
+    for seed in seeds {
+        if let Some((v, variance)) = power_iteration(&cov, seed) {
+            if variance > max_variance {
+                axis = v;
+                max_variance = variance;
+            }
+        }
+    }
+
+    (axis[0], axis[1], axis[2])
+}
+
+// Run power iteration from `v`, returning the unit vector it converges to and
+// its variance, or None if `v` is in the null space of `cov`.
+fn power_iteration(cov: &[[f32; 3]; 3], mut v: [f32; 3]) -> Option<([f32; 3], f32)> {
+    let mul = |v: &[f32; 3]| {
+        let mut w = [0.0f32; 3];
+        for i in 0..3 {
+            for j in 0..3 {
+                w[i] += cov[i][j] * v[j];
+            }
+        }
+        w
+    };
+
+    for _ in 0..100 {
+        let w = mul(&v);
+        let norm = (w[0] * w[0] + w[1] * w[1] + w[2] * w[2]).sqrt();
+        if norm == 0.0 {
+            return None;
+        }
+        v = [w[0] / norm, w[1] / norm, w[2] / norm];
+    }
+
+    let w = mul(&v);
+    Some((v, v[0] * w[0] + v[1] * w[1] + v[2] * w[2]))
+}
+
+#[test]
+fn spatial_cross_correlation_of_gradients() {
+    // A 12x12 grid of cells, with gene 0 increasing along x, gene 1 the same
+    // as gene 0, gene 2 its reverse, and gene 3 alternating between
+    // neighboring cells.
+    let mut cell_centroids = Vec::new();
+    let mut expected_counts = Array2::<f32>::zeros((4, 144));
+    for i in 0..12 {
+        for j in 0..12 {
+            let cell = cell_centroids.len();
+            cell_centroids.push((i as f32, j as f32, 0.0));
+            expected_counts[[0, cell]] = i as f32;
+            expected_counts[[1, cell]] = i as f32;
+            expected_counts[[2, cell]] = 11.0 - i as f32;
+            expected_counts[[3, cell]] = ((i + j) % 2) as f32;
+        }
+    }
+
+    let results =
+        spatial_cross_correlation(&expected_counts, &cell_centroids, &[(0, 1), (0, 2), (0, 3)]);
+
+    assert!(results[0].lee_l > 0.5);
+    assert!((results[1].lee_l + results[0].lee_l).abs() < 1e-5);
+    assert!(results[2].lee_l.abs() < 0.1);
+
+    let min_p_value = 1.0 / (LEE_L_PERMUTATIONS + 1) as f32;
+    assert_eq!(results[0].p_value, min_p_value);
+    assert_eq!(results[1].p_value, min_p_value);
+}
+
+#[test]
+fn principal_axis_of_diagonal_points() {
+    // Points along x = -y, where the axis is orthogonal to (1, 1, 1).
+    let points = (-5..=5)
+        .map(|i| (i as f32, -i as f32, 0.0))
+        .collect::<Vec<_>>();
+    let (x, y, z) = principal_axis(&points);
+    let s = 0.5_f32.sqrt();
+    assert!((x.abs() - s).abs() < 1e-4);
+    assert!((y.abs() - s).abs() < 1e-4);
+    assert!(x * y < 0.0);
+    assert!(z.abs() < 1e-4);
+
+    // Points along x.
+    let points = (-5..=5).map(|i| (i as f32, 2.0, 1.0)).collect::<Vec<_>>();
+    let (x, y, z) = principal_axis(&points);
+    assert!((x.abs() - 1.0).abs() < 1e-4);
+    assert!(y.abs() < 1e-4 && z.abs() < 1e-4);
+}
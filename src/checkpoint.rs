@@ -0,0 +1,154 @@
+// Versioned binary checkpoints of model state.
+//
+// A checkpoint file begins with a 16 byte header: the magic bytes
+// `PROSEG\0\0`, the format version as a little-endian u32, and 4 reserved
+// bytes. The rest of the file is the payload, `Checkpoint` encoded with
+// bincode. Payloads from older versions are migrated on load where a
+// migration exists.
+
+use serde::{Deserialize, Serialize};
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::Path;
+
+use super::sampler::voxelsampler::{VoxelSampler, VoxelState};
+use super::sampler::{ModelParams, ModelState};
+
+const CHECKPOINT_MAGIC: &[u8; 8] = b"PROSEG\0\0";
+pub const CHECKPOINT_VERSION: u32 = 1;
+
+#[derive(Debug)]
+pub enum CheckpointError {
+    Io(std::io::Error),
+    BadMagic,
+    CheckpointVersionMismatch { found: u32, expected: u32 },
+    Malformed(String),
+}
+
+impl std::fmt::Display for CheckpointError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CheckpointError::Io(err) => write!(f, "{}", err),
+            CheckpointError::BadMagic => write!(f, "Not a proseg checkpoint"),
+            CheckpointError::CheckpointVersionMismatch { found, expected } => write!(
+                f,
+                "Checkpoint format version {} can't be read by this version of proseg (expected {})",
+                found, expected
+            ),
+            CheckpointError::Malformed(msg) => write!(f, "Malformed checkpoint: {}", msg),
+        }
+    }
+}
+
+impl From<std::io::Error> for CheckpointError {
+    fn from(err: std::io::Error) -> Self {
+        CheckpointError::Io(err)
+    }
+}
+
+impl From<bincode::Error> for CheckpointError {
+    fn from(err: bincode::Error) -> Self {
+        match *err {
+            bincode::ErrorKind::Io(err) => CheckpointError::Io(err),
+            err => CheckpointError::Malformed(err.to_string()),
+        }
+    }
+}
+
+// Model and sampler state saved in a checkpoint.
+#[derive(Serialize, Deserialize)]
+pub struct Checkpoint {
+    pub params: ModelState,
+    pub voxels: VoxelState,
+}
+
+impl Checkpoint {
+    pub fn new(params: &ModelParams, sampler: &VoxelSampler) -> Self {
+        Checkpoint {
+            params: params.state(),
+            voxels: sampler.state(),
+        }
+    }
+}
+
+// Convert a payload written by an older format version to the current one.
+// There have been no format changes yet, so any other version is an error.
+fn migrate_checkpoint(version: u32, payload: Vec<u8>) -> Result<Vec<u8>, CheckpointError> {
+    match version {
+        CHECKPOINT_VERSION => Ok(payload),
+        _ => Err(CheckpointError::CheckpointVersionMismatch {
+            found: version,
+            expected: CHECKPOINT_VERSION,
+        }),
+    }
+}
+
+fn write_checkpoint<W: Write>(
+    mut output: W,
+    checkpoint: &Checkpoint,
+) -> Result<(), CheckpointError> {
+    output.write_all(CHECKPOINT_MAGIC)?;
+    output.write_all(&CHECKPOINT_VERSION.to_le_bytes())?;
+    output.write_all(&[0; 4])?;
+    bincode::serialize_into(&mut output, checkpoint)?;
+    output.flush()?;
+    Ok(())
+}
+
+fn read_checkpoint<R: Read>(mut input: R) -> Result<Checkpoint, CheckpointError> {
+    let mut header = [0_u8; 16];
+    input.read_exact(&mut header)?;
+    if &header[..8] != CHECKPOINT_MAGIC {
+        return Err(CheckpointError::BadMagic);
+    }
+    let version = u32::from_le_bytes(header[8..12].try_into().unwrap());
+
+    let mut payload = Vec::new();
+    input.read_to_end(&mut payload)?;
+    let payload = migrate_checkpoint(version, payload)?;
+    Ok(bincode::deserialize(&payload)?)
+}
+
+pub fn save_checkpoint_binary(path: &Path, checkpoint: &Checkpoint) -> Result<(), CheckpointError> {
+    write_checkpoint(BufWriter::new(File::create(path)?), checkpoint)
+}
+
+pub fn load_checkpoint_binary(path: &Path) -> Result<Checkpoint, CheckpointError> {
+    read_checkpoint(BufReader::new(File::open(path)?))
+}
+
+#[test]
+fn checkpoint_round_trip() {
+    use super::sampler::voxelsampler::two_square_cells;
+
+    // save state at a finer resolution than a new sampler starts with
+    let (transcripts, _priors, mut params, sampler) = two_square_cells();
+    let sampler = sampler.double_resolution(&params, false);
+    params.λ[[1, 0]] = 2.5;
+    params.r[[0, 1]] = 7.0;
+    let checkpoint = Checkpoint::new(&params, &sampler);
+
+    let mut bytes = Vec::new();
+    write_checkpoint(&mut bytes, &checkpoint).unwrap();
+    assert_eq!(&bytes[..8], CHECKPOINT_MAGIC);
+
+    let loaded = read_checkpoint(bytes.as_slice()).unwrap();
+    assert_eq!(loaded.params, checkpoint.params);
+    assert_eq!(loaded.voxels, checkpoint.voxels);
+
+    let (_, _, mut restored_params, restored_sampler) = two_square_cells();
+    restored_params.restore_state(loaded.params, &transcripts);
+    let restored_sampler = restored_sampler.restore_state(&restored_params, &loaded.voxels);
+    assert_eq!(restored_params.state(), checkpoint.params);
+    assert_eq!(restored_sampler.state(), checkpoint.voxels);
+    assert_eq!(
+        restored_sampler.cell_voxel_counts(),
+        sampler.cell_voxel_counts()
+    );
+
+    bytes[8..12].copy_from_slice(&(CHECKPOINT_VERSION + 1).to_le_bytes());
+    assert!(matches!(
+        read_checkpoint(bytes.as_slice()),
+        Err(CheckpointError::CheckpointVersionMismatch { .. })
+    ));
+}
@@ -0,0 +1,458 @@
+// Page-level CRC32 checksums for parquet output.
+//
+// The parquet writer always leaves the `crc` field of page headers empty. To
+// fill it in, each row group is written to a parquet file in memory, and its
+// pages are copied to the output with the CRC32 of the page data added to
+// their headers. Offsets and sizes in the column metadata, page indexes, and
+// footer are adjusted for the larger headers.
+
+use arrow2::datatypes::Schema;
+use arrow2::io::parquet::write::{FileWriter, RowGroupIter, WriteOptions};
+use parquet_format_safe::thrift::protocol::{TCompactInputProtocol, TCompactOutputProtocol};
+use parquet_format_safe::{
+    ColumnMetaData, FileMetaData, OffsetIndex, PageHeader, PageType, RowGroup,
+};
+use std::io::{Cursor, Write};
+use std::path::Path;
+
+use super::output::OutputError;
+
+const PARQUET_MAGIC: &[u8; 4] = b"PAR1";
+
+struct PageChecksum {
+    computed: u32,
+    header: Option<i32>,
+}
+
+// Limit on allocations while parsing `len` bytes of thrift, the same allowance
+// parquet2 makes when reading metadata.
+fn max_thrift_size(len: usize) -> usize {
+    len * 2 + 1024
+}
+
+// Parse the footer metadata, returning it with the offset at which it begins.
+fn read_footer(bytes: &[u8]) -> Result<(FileMetaData, usize), OutputError> {
+    let n = bytes.len();
+    if n < 12 || &bytes[n - 4..] != PARQUET_MAGIC || &bytes[..4] != PARQUET_MAGIC {
+        return Err(OutputError::Malformed("not a parquet file".to_string()));
+    }
+
+    let footer_len = u32::from_le_bytes(bytes[n - 8..n - 4].try_into().unwrap()) as usize;
+    if footer_len + 12 > n {
+        return Err(OutputError::Malformed(
+            "footer length out of range".to_string(),
+        ));
+    }
+    let footer_start = n - 8 - footer_len;
+
+    let mut protocol =
+        TCompactInputProtocol::new(&bytes[footer_start..n - 8], max_thrift_size(footer_len));
+    let metadata = FileMetaData::read_from_in_protocol(&mut protocol)?;
+    Ok((metadata, footer_start))
+}
+
+// Bytes in `bytes[offset..offset + length]`, as given by file metadata.
+fn byte_range(bytes: &[u8], offset: i64, length: i64) -> Result<&[u8], OutputError> {
+    let start = usize::try_from(offset)
+        .map_err(|_| OutputError::Malformed("negative offset".to_string()))?;
+    let end = usize::try_from(length)
+        .ok()
+        .and_then(|length| start.checked_add(length))
+        .filter(|&end| end <= bytes.len())
+        .ok_or_else(|| OutputError::Malformed("byte range out of range".to_string()))?;
+    Ok(&bytes[start..end])
+}
+
+// Bytes of a column chunk's pages.
+fn column_chunk_bytes<'a>(
+    bytes: &'a [u8],
+    column_metadata: &ColumnMetaData,
+) -> Result<&'a [u8], OutputError> {
+    let start = column_metadata
+        .dictionary_page_offset
+        .unwrap_or(column_metadata.data_page_offset);
+    byte_range(bytes, start, column_metadata.total_compressed_size)
+}
+
+// Split a column chunk into the header and data of each page.
+fn column_chunk_pages(chunk: &[u8]) -> Result<Vec<(PageHeader, &[u8])>, OutputError> {
+    let mut pages = Vec::new();
+    let mut cursor = Cursor::new(chunk);
+    while (cursor.position() as usize) < chunk.len() {
+        let mut protocol = TCompactInputProtocol::new(&mut cursor, max_thrift_size(chunk.len()));
+        let header = PageHeader::read_from_in_protocol(&mut protocol)?;
+
+        let data_start = cursor.position() as usize;
+        let data_end = data_start + header.compressed_page_size as usize;
+        if data_end > chunk.len() {
+            return Err(OutputError::Malformed("page out of range".to_string()));
+        }
+
+        pages.push((header, &chunk[data_start..data_end]));
+        cursor.set_position(data_end as u64);
+    }
+    Ok(pages)
+}
+
+fn missing_column_metadata() -> OutputError {
+    OutputError::Malformed("missing column metadata".to_string())
+}
+
+// Writes parquet files with a CRC32 checksum in every page header. Used like
+// arrow2's `FileWriter`.
+pub struct ChecksummedFileWriter<W: Write> {
+    writer: W,
+    schema: Schema,
+    options: WriteOptions,
+    offset: u64,
+    // Footer of an empty file with the same schema, to which row groups are
+    // added as they're written.
+    metadata: FileMetaData,
+    // Serialized column index of each column chunk, if statistics are written
+    column_indexes: Vec<Vec<Option<Vec<u8>>>>,
+    // Offset index of each column chunk, with offsets in the output
+    offset_indexes: Vec<Vec<OffsetIndex>>,
+}
+
+impl<W: Write> ChecksummedFileWriter<W> {
+    pub fn try_new(
+        mut writer: W,
+        schema: Schema,
+        options: WriteOptions,
+    ) -> Result<Self, OutputError> {
+        let mut empty = FileWriter::try_new(Vec::new(), schema.clone(), options)?;
+        empty.end(None)?;
+        let (metadata, _) = read_footer(&empty.into_inner())?;
+
+        writer.write_all(PARQUET_MAGIC)?;
+        Ok(ChecksummedFileWriter {
+            writer,
+            schema,
+            options,
+            offset: PARQUET_MAGIC.len() as u64,
+            metadata,
+            column_indexes: Vec::new(),
+            offset_indexes: Vec::new(),
+        })
+    }
+
+    fn write_bytes(&mut self, bytes: &[u8]) -> Result<(), OutputError> {
+        self.writer.write_all(bytes)?;
+        self.offset += bytes.len() as u64;
+        Ok(())
+    }
+
+    pub fn write(
+        &mut self,
+        row_group: RowGroupIter<'_, arrow2::error::Error>,
+    ) -> Result<(), OutputError> {
+        let mut group_writer = FileWriter::try_new(Vec::new(), self.schema.clone(), self.options)?;
+        group_writer.write(row_group)?;
+        group_writer.end(None)?;
+        let bytes = group_writer.into_inner();
+        let (metadata, _) = read_footer(&bytes)?;
+        let mut row_group = metadata
+            .row_groups
+            .into_iter()
+            .next()
+            .ok_or_else(|| OutputError::Malformed("missing row group".to_string()))?;
+
+        let mut column_indexes = Vec::new();
+        let mut offset_indexes = Vec::new();
+        for column in row_group.columns.iter_mut() {
+            let column_metadata = column
+                .meta_data
+                .as_mut()
+                .ok_or_else(missing_column_metadata)?;
+
+            // copy pages, recording the location of each data page
+            let start = self.offset as i64;
+            let mut data_page_locations = Vec::new();
+            for (mut header, data) in
+                column_chunk_pages(column_chunk_bytes(&bytes, column_metadata)?)?
+            {
+                header.crc = Some(crc32fast::hash(data) as i32);
+
+                let page_offset = self.offset as i64;
+                let mut page = Vec::new();
+                header.write_to_out_protocol(&mut TCompactOutputProtocol::new(&mut page))?;
+                page.extend_from_slice(data);
+                self.write_bytes(&page)?;
+
+                if header.type_ != PageType::DICTIONARY_PAGE {
+                    data_page_locations.push((page_offset, page.len() as i32));
+                }
+            }
+
+            let total_compressed_size = self.offset as i64 - start;
+            column_metadata.total_uncompressed_size +=
+                total_compressed_size - column_metadata.total_compressed_size;
+            column_metadata.total_compressed_size = total_compressed_size;
+            if column_metadata.dictionary_page_offset.is_some() {
+                column_metadata.dictionary_page_offset = Some(start);
+            }
+            column_metadata.data_page_offset = data_page_locations
+                .first()
+                .map(|(offset, _)| *offset)
+                .unwrap_or(start);
+
+            // column metadata is repeated after the pages, as arrow2 does
+            let mut encoded_metadata = Vec::new();
+            column_metadata
+                .write_to_out_protocol(&mut TCompactOutputProtocol::new(&mut encoded_metadata))?;
+            column.file_offset = self.offset as i64;
+            self.write_bytes(&encoded_metadata)?;
+
+            // column indexes hold no offsets, so they're copied as is
+            column_indexes.push(
+                match (column.column_index_offset, column.column_index_length) {
+                    (Some(offset), Some(length)) => {
+                        Some(byte_range(&bytes, offset, length as i64)?.to_vec())
+                    }
+                    _ => None,
+                },
+            );
+
+            let mut offset_index = match (column.offset_index_offset, column.offset_index_length) {
+                (Some(offset), Some(length)) => {
+                    let index_bytes = byte_range(&bytes, offset, length as i64)?;
+                    let mut protocol =
+                        TCompactInputProtocol::new(index_bytes, max_thrift_size(index_bytes.len()));
+                    OffsetIndex::read_from_in_protocol(&mut protocol)?
+                }
+                _ => OffsetIndex::new(Vec::new()),
+            };
+            if offset_index.page_locations.len() != data_page_locations.len() {
+                return Err(OutputError::Malformed(
+                    "offset index doesn't match pages".to_string(),
+                ));
+            }
+            for (location, (offset, size)) in offset_index
+                .page_locations
+                .iter_mut()
+                .zip(data_page_locations)
+            {
+                location.offset = offset;
+                location.compressed_page_size = size;
+            }
+            offset_indexes.push(offset_index);
+
+            column.column_index_offset = None;
+            column.column_index_length = None;
+            column.offset_index_offset = None;
+            column.offset_index_length = None;
+        }
+
+        row_group.file_offset = row_group.columns.first().and_then(|column| {
+            column
+                .meta_data
+                .as_ref()
+                .map(|m| m.dictionary_page_offset.unwrap_or(m.data_page_offset))
+        });
+        row_group.total_byte_size = row_group
+            .columns
+            .iter()
+            .map(|column| column.meta_data.as_ref().unwrap().total_uncompressed_size)
+            .sum();
+        row_group.total_compressed_size = Some(
+            row_group
+                .columns
+                .iter()
+                .map(|column| column.meta_data.as_ref().unwrap().total_compressed_size)
+                .sum(),
+        );
+        row_group.ordinal = Some(self.metadata.row_groups.len() as i16);
+
+        self.metadata.num_rows += row_group.num_rows;
+        self.metadata.row_groups.push(row_group);
+        self.column_indexes.push(column_indexes);
+        self.offset_indexes.push(offset_indexes);
+        Ok(())
+    }
+
+    // Write page indexes and the footer.
+    pub fn end(&mut self) -> Result<(), OutputError> {
+        let mut row_groups: Vec<RowGroup> = std::mem::take(&mut self.metadata.row_groups);
+
+        let column_indexes = std::mem::take(&mut self.column_indexes);
+        for (row_group, indexes) in row_groups.iter_mut().zip(column_indexes) {
+            for (column, index) in row_group.columns.iter_mut().zip(indexes) {
+                if let Some(index) = index {
+                    column.column_index_offset = Some(self.offset as i64);
+                    column.column_index_length = Some(index.len() as i32);
+                    self.write_bytes(&index)?;
+                }
+            }
+        }
+
+        let offset_indexes = std::mem::take(&mut self.offset_indexes);
+        for (row_group, indexes) in row_groups.iter_mut().zip(offset_indexes) {
+            for (column, index) in row_group.columns.iter_mut().zip(indexes) {
+                let mut encoded_index = Vec::new();
+                index
+                    .write_to_out_protocol(&mut TCompactOutputProtocol::new(&mut encoded_index))?;
+                column.offset_index_offset = Some(self.offset as i64);
+                column.offset_index_length = Some(encoded_index.len() as i32);
+                self.write_bytes(&encoded_index)?;
+            }
+        }
+
+        self.metadata.row_groups = row_groups;
+        let mut footer = Vec::new();
+        let footer_len = self
+            .metadata
+            .write_to_out_protocol(&mut TCompactOutputProtocol::new(&mut footer))?;
+        footer.extend_from_slice(&(footer_len as u32).to_le_bytes());
+        footer.extend_from_slice(PARQUET_MAGIC);
+        self.write_bytes(&footer)?;
+        self.writer.flush()?;
+        Ok(())
+    }
+
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+}
+
+// Check the checksum in every page header of a parquet file. Returns false if
+// any fail, and an error if no page has a checksum.
+pub fn verify_parquet_checksums(path: &Path) -> Result<bool, OutputError> {
+    let bytes = std::fs::read(path)?;
+    let (metadata, _) = read_footer(&bytes)?;
+
+    let mut checksums = Vec::new();
+    for row_group in &metadata.row_groups {
+        for column in &row_group.columns {
+            let column_metadata = column
+                .meta_data
+                .as_ref()
+                .ok_or_else(missing_column_metadata)?;
+            let chunk = column_chunk_bytes(&bytes, column_metadata)?;
+            for (header, data) in column_chunk_pages(chunk)? {
+                checksums.push(PageChecksum {
+                    computed: crc32fast::hash(data),
+                    header: header.crc,
+                });
+            }
+        }
+    }
+
+    if checksums.iter().all(|checksum| checksum.header.is_none()) {
+        return Err(OutputError::MissingChecksums(path.display().to_string()));
+    }
+
+    Ok(checksums.iter().all(|checksum| {
+        checksum
+            .header
+            .is_some_and(|crc| crc as u32 == checksum.computed)
+    }))
+}
+
+#[test]
+fn page_checksums_detect_corruption() {
+    use arrow2::array::{Array, Int32Array, Utf8Array};
+    use arrow2::chunk::Chunk;
+    use arrow2::datatypes::{DataType, Field};
+    use arrow2::io::parquet::read::indexes::read_columns_indexes;
+    use arrow2::io::parquet::read::{read_metadata, read_pages_locations, FileReader};
+    use arrow2::io::parquet::write::{CompressionOptions, Encoding, RowGroupIterator, Version};
+    use std::sync::Arc;
+
+    let schema = Schema::from(vec![
+        Field::new("x", DataType::Int32, false),
+        Field::new("name", DataType::Utf8, false),
+    ]);
+    let options = WriteOptions {
+        write_statistics: true,
+        version: Version::V2,
+        compression: CompressionOptions::Uncompressed,
+        data_pagesize_limit: Some(1024),
+    };
+    let chunks = [0..1000, 1000..1500].map(|values| {
+        let columns: Vec<Arc<dyn Array>> = vec![
+            Arc::new(Int32Array::from_values(values.clone())),
+            Arc::new(Utf8Array::<i32>::from_iter_values(
+                values.map(|i| format!("cell{}", i)),
+            )),
+        ];
+        Chunk::new(columns)
+    });
+    let row_groups = RowGroupIterator::try_new(
+        chunks.iter().cloned().map(Ok),
+        &schema,
+        options,
+        vec![vec![Encoding::Plain], vec![Encoding::Plain]],
+    )
+    .unwrap();
+
+    let mut plain = FileWriter::try_new(Vec::new(), schema.clone(), options).unwrap();
+    let mut checksummed = ChecksummedFileWriter::try_new(Vec::new(), schema, options).unwrap();
+    for group in row_groups {
+        let group = group.unwrap();
+        checksummed.write(group).unwrap();
+    }
+    for group in RowGroupIterator::try_new(
+        chunks.iter().cloned().map(Ok),
+        plain.schema(),
+        options,
+        vec![vec![Encoding::Plain], vec![Encoding::Plain]],
+    )
+    .unwrap()
+    {
+        plain.write(group.unwrap()).unwrap();
+    }
+    plain.end(None).unwrap();
+    checksummed.end().unwrap();
+    let mut bytes = checksummed.into_inner();
+
+    let path = std::env::temp_dir().join(format!("proseg-checksum-{}.parquet", std::process::id()));
+    std::fs::write(&path, plain.into_inner()).unwrap();
+    assert!(matches!(
+        verify_parquet_checksums(&path),
+        Err(OutputError::MissingChecksums(_))
+    ));
+
+    std::fs::write(&path, &bytes).unwrap();
+    assert!(verify_parquet_checksums(&path).unwrap());
+
+    // the file reads back the same, including through page indexes
+    let mut file = std::fs::File::open(&path).unwrap();
+    let metadata = read_metadata(&mut file).unwrap();
+    assert_eq!(metadata.num_rows, 1500);
+    assert_eq!(metadata.row_groups.len(), 2);
+    let fields = [
+        Field::new("x", DataType::Int32, false),
+        Field::new("name", DataType::Utf8, false),
+    ];
+    for row_group in &metadata.row_groups {
+        let indexes = read_columns_indexes(&mut file, row_group.columns(), &fields).unwrap();
+        assert_eq!(indexes.len(), 2);
+        let locations = read_pages_locations(&mut file, row_group.columns()).unwrap();
+        for (column, pages) in row_group.columns().iter().zip(locations) {
+            let (start, _) = column.byte_range();
+            assert_eq!(pages[0].offset as u64, start);
+        }
+    }
+    let schema = arrow2::io::parquet::read::infer_schema(&metadata).unwrap();
+    let read_chunks = FileReader::new(file, metadata.row_groups, schema, None, None, None)
+        .collect::<arrow2::error::Result<Vec<_>>>()
+        .unwrap();
+    assert_eq!(read_chunks.len(), chunks.len());
+    for (read, original) in read_chunks.iter().zip(&chunks) {
+        for (a, b) in read.arrays().iter().zip(original.arrays()) {
+            assert_eq!(a.as_ref(), b.as_ref());
+        }
+    }
+
+    // flip a bit in a value in the middle of a page, away from the min and
+    // max recorded in statistics
+    let i = bytes
+        .windows(4)
+        .position(|w| w == 100_i32.to_le_bytes())
+        .unwrap();
+    bytes[i] ^= 1;
+    std::fs::write(&path, &bytes).unwrap();
+    assert!(!verify_parquet_checksums(&path).unwrap());
+
+    std::fs::remove_file(&path).unwrap();
+}
@@ -0,0 +1,441 @@
+// Writing output directly to object storage. Output paths starting with
+// `s3://` or `gs://` are routed to a cloud backend instead of the local
+// filesystem. The backends are optional and enabled with the `aws-s3` and
+// `gcs` features.
+
+use std::fs::File;
+use std::io::Write;
+use std::sync::Arc;
+
+pub trait CloudBackend: Send + Sync {
+    fn open_write(&self, url: &str) -> Box<dyn OutputWriter>;
+}
+
+// An output file or object being written. `finish` must be called once
+// everything is written. For object storage this is when the upload completes,
+// and an upload that's dropped without being finished is abandoned.
+pub trait OutputWriter: Write + Send {
+    fn finish(self: Box<Self>) -> std::io::Result<()>;
+}
+
+impl OutputWriter for File {
+    fn finish(self: Box<Self>) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+impl OutputWriter for Box<dyn OutputWriter> {
+    fn finish(self: Box<Self>) -> std::io::Result<()> {
+        (*self).finish()
+    }
+}
+
+impl<W: OutputWriter> OutputWriter for flate2::write::GzEncoder<W> {
+    fn finish(self: Box<Self>) -> std::io::Result<()> {
+        Box::new(flate2::write::GzEncoder::finish(*self)?).finish()
+    }
+}
+
+impl<W: OutputWriter> OutputWriter for std::io::BufWriter<W> {
+    fn finish(self: Box<Self>) -> std::io::Result<()> {
+        Box::new(self.into_inner().map_err(|err| err.into_error())?).finish()
+    }
+}
+
+impl OutputWriter for &mut Vec<u8> {
+    fn finish(self: Box<Self>) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+pub fn is_remote_url(filename: &str) -> bool {
+    filename.starts_with("s3://") || filename.starts_with("gs://")
+}
+
+// Choose a backend for writing to the given object storage URLs. Returns None
+// if there are none. All remote outputs must use the same scheme.
+pub fn cloud_backend_for_urls<'a, I>(urls: I) -> Option<Arc<dyn CloudBackend>>
+where
+    I: IntoIterator<Item = &'a str>,
+{
+    let mut scheme: Option<&str> = None;
+    for url in urls.into_iter().filter(|url| is_remote_url(url)) {
+        let url_scheme = url.split_once("://").unwrap().0;
+        match scheme {
+            Some(scheme) if scheme != url_scheme => panic!(
+                "Output can't be written to both {}:// and {}:// in one run",
+                scheme, url_scheme
+            ),
+            _ => scheme = Some(url_scheme),
+        }
+    }
+
+    match scheme {
+        None => None,
+        Some("s3") => {
+            #[cfg(feature = "aws-s3")]
+            return Some(Arc::new(s3::S3Backend::new()));
+            #[cfg(not(feature = "aws-s3"))]
+            panic!("Writing to s3:// requires proseg to be built with the `aws-s3` feature");
+        }
+        Some(_) => {
+            #[cfg(feature = "gcs")]
+            return Some(Arc::new(gcs::GcsBackend::new()));
+            #[cfg(not(feature = "gcs"))]
+            panic!("Writing to gs:// requires proseg to be built with the `gcs` feature");
+        }
+    }
+}
+
+// Split `scheme://bucket/key` into bucket and key.
+#[cfg(any(feature = "aws-s3", feature = "gcs"))]
+fn parse_bucket_key(url: &str) -> (String, String) {
+    let (_scheme, path) = url
+        .split_once("://")
+        .unwrap_or_else(|| panic!("Malformed object storage URL: {}", url));
+    let (bucket, key) = path
+        .split_once('/')
+        .unwrap_or_else(|| panic!("Object storage URL has no object key: {}", url));
+    if bucket.is_empty() || key.is_empty() {
+        panic!("Malformed object storage URL: {}", url);
+    }
+    (bucket.to_string(), key.to_string())
+}
+
+#[cfg(feature = "aws-s3")]
+mod s3 {
+    use super::{parse_bucket_key, CloudBackend, OutputWriter};
+    use aws_sdk_s3::primitives::ByteStream;
+    use aws_sdk_s3::types::{CompletedMultipartUpload, CompletedPart};
+    use aws_sdk_s3::Client;
+    use std::io::Write;
+    use std::sync::Arc;
+    use tokio::runtime::Runtime;
+
+    // Objects larger than this are written with a multipart upload, in parts
+    // of this size. S3 requires every part but the last to be at least 5MB.
+    const S3_PART_SIZE: usize = 5 * 1024 * 1024;
+
+    pub struct S3Backend {
+        runtime: Arc<Runtime>,
+        client: Client,
+    }
+
+    impl S3Backend {
+        pub fn new() -> Self {
+            let runtime = Arc::new(
+                tokio::runtime::Builder::new_current_thread()
+                    .enable_all()
+                    .build()
+                    .unwrap(),
+            );
+            let config = runtime.block_on(aws_config::load_defaults(
+                aws_config::BehaviorVersion::latest(),
+            ));
+            let client = Client::new(&config);
+            S3Backend { runtime, client }
+        }
+    }
+
+    impl CloudBackend for S3Backend {
+        fn open_write(&self, url: &str) -> Box<dyn OutputWriter> {
+            let (bucket, key) = parse_bucket_key(url);
+            Box::new(S3Writer {
+                runtime: self.runtime.clone(),
+                client: self.client.clone(),
+                bucket,
+                key,
+                buffer: Vec::new(),
+                upload_id: None,
+                parts: Vec::new(),
+            })
+        }
+    }
+
+    // Buffers output, switching to a multipart upload once more than one
+    // part's worth of data has been written. The object is created when the
+    // writer is finished.
+    struct S3Writer {
+        runtime: Arc<Runtime>,
+        client: Client,
+        bucket: String,
+        key: String,
+        buffer: Vec<u8>,
+        upload_id: Option<String>,
+        parts: Vec<CompletedPart>,
+    }
+
+    impl S3Writer {
+        fn upload_part(&mut self) -> std::io::Result<()> {
+            let upload_id = match &self.upload_id {
+                Some(upload_id) => upload_id.clone(),
+                None => {
+                    let upload = self
+                        .runtime
+                        .block_on(
+                            self.client
+                                .create_multipart_upload()
+                                .bucket(&self.bucket)
+                                .key(&self.key)
+                                .send(),
+                        )
+                        .map_err(|err| {
+                            std::io::Error::other(format!(
+                                "Unable to start upload to s3://{}/{}: {}",
+                                self.bucket, self.key, err
+                            ))
+                        })?;
+                    let upload_id = upload.upload_id().unwrap().to_string();
+                    self.upload_id = Some(upload_id.clone());
+                    upload_id
+                }
+            };
+
+            let part_number = self.parts.len() as i32 + 1;
+            let body = std::mem::take(&mut self.buffer);
+            let part = self
+                .runtime
+                .block_on(
+                    self.client
+                        .upload_part()
+                        .bucket(&self.bucket)
+                        .key(&self.key)
+                        .upload_id(upload_id)
+                        .part_number(part_number)
+                        .body(ByteStream::from(body))
+                        .send(),
+                )
+                .map_err(|err| {
+                    std::io::Error::other(format!(
+                        "Unable to upload part to s3://{}/{}: {}",
+                        self.bucket, self.key, err
+                    ))
+                })?;
+
+            self.parts.push(
+                CompletedPart::builder()
+                    .set_e_tag(part.e_tag().map(|e_tag| e_tag.to_string()))
+                    .part_number(part_number)
+                    .build(),
+            );
+            Ok(())
+        }
+    }
+
+    impl Write for S3Writer {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.buffer.extend_from_slice(buf);
+            if self.buffer.len() > S3_PART_SIZE {
+                self.upload_part()?;
+            }
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl OutputWriter for S3Writer {
+        fn finish(mut self: Box<Self>) -> std::io::Result<()> {
+            if self.upload_id.is_none() {
+                let body = std::mem::take(&mut self.buffer);
+                return self
+                    .runtime
+                    .block_on(
+                        self.client
+                            .put_object()
+                            .bucket(&self.bucket)
+                            .key(&self.key)
+                            .body(ByteStream::from(body))
+                            .send(),
+                    )
+                    .map(|_| ())
+                    .map_err(|err| {
+                        std::io::Error::other(format!(
+                            "Unable to upload s3://{}/{}: {}",
+                            self.bucket, self.key, err
+                        ))
+                    });
+            }
+
+            if !self.buffer.is_empty() {
+                self.upload_part()?;
+            }
+            let upload_id = self.upload_id.take().unwrap();
+            let parts = std::mem::take(&mut self.parts);
+            self.runtime
+                .block_on(
+                    self.client
+                        .complete_multipart_upload()
+                        .bucket(&self.bucket)
+                        .key(&self.key)
+                        .upload_id(upload_id)
+                        .multipart_upload(
+                            CompletedMultipartUpload::builder()
+                                .set_parts(Some(parts))
+                                .build(),
+                        )
+                        .send(),
+                )
+                .map(|_| ())
+                .map_err(|err| {
+                    std::io::Error::other(format!(
+                        "Unable to complete upload to s3://{}/{}: {}",
+                        self.bucket, self.key, err
+                    ))
+                })
+        }
+    }
+
+    // Abort any multipart upload that wasn't completed, so its parts aren't
+    // left in the bucket.
+    impl Drop for S3Writer {
+        fn drop(&mut self) {
+            if let Some(upload_id) = self.upload_id.take() {
+                let _ = self.runtime.block_on(
+                    self.client
+                        .abort_multipart_upload()
+                        .bucket(&self.bucket)
+                        .key(&self.key)
+                        .upload_id(upload_id)
+                        .send(),
+                );
+            }
+        }
+    }
+}
+
+#[cfg(feature = "gcs")]
+mod gcs {
+    use super::{parse_bucket_key, CloudBackend, OutputWriter};
+    use google_cloud_storage::client::Storage;
+    use google_cloud_storage::streaming_source::StreamingSource;
+    use std::io::Write;
+    use std::sync::Arc;
+    use tokio::runtime::Runtime;
+    use tokio::sync::mpsc;
+    use tokio::task::JoinHandle;
+
+    // Data is handed to the upload task in chunks of roughly this size.
+    const GCS_CHUNK_SIZE: usize = 8 * 1024 * 1024;
+
+    pub struct GcsBackend {
+        runtime: Arc<Runtime>,
+        client: Storage,
+    }
+
+    impl GcsBackend {
+        pub fn new() -> Self {
+            let runtime = Arc::new(
+                tokio::runtime::Builder::new_multi_thread()
+                    .worker_threads(1)
+                    .enable_all()
+                    .build()
+                    .unwrap(),
+            );
+            let client = runtime
+                .block_on(Storage::builder().build())
+                .unwrap_or_else(|err| panic!("Unable to create GCS client: {}", err));
+            GcsBackend { runtime, client }
+        }
+    }
+
+    // Feeds chunks written on the main thread to the upload task.
+    struct ChannelSource {
+        receiver: mpsc::Receiver<bytes::Bytes>,
+    }
+
+    impl StreamingSource for ChannelSource {
+        type Error = std::io::Error;
+
+        async fn next(&mut self) -> Option<Result<bytes::Bytes, Self::Error>> {
+            self.receiver.recv().await.map(Ok)
+        }
+    }
+
+    impl CloudBackend for GcsBackend {
+        fn open_write(&self, url: &str) -> Box<dyn OutputWriter> {
+            let (bucket, key) = parse_bucket_key(url);
+            let (sender, receiver) = mpsc::channel(2);
+            let upload = self.runtime.spawn(
+                self.client
+                    .write_object(
+                        format!("projects/_/buckets/{}", bucket),
+                        key,
+                        ChannelSource { receiver },
+                    )
+                    .send_buffered(),
+            );
+            Box::new(GcsWriter {
+                runtime: self.runtime.clone(),
+                url: url.to_string(),
+                buffer: Vec::new(),
+                sender: Some(sender),
+                upload: Some(upload),
+            })
+        }
+    }
+
+    struct GcsWriter {
+        runtime: Arc<Runtime>,
+        url: String,
+        buffer: Vec<u8>,
+        sender: Option<mpsc::Sender<bytes::Bytes>>,
+        upload:
+            Option<JoinHandle<google_cloud_storage::Result<google_cloud_storage::model::Object>>>,
+    }
+
+    impl GcsWriter {
+        fn send_buffer(&mut self) -> std::io::Result<()> {
+            let chunk = bytes::Bytes::from(std::mem::take(&mut self.buffer));
+            self.sender
+                .as_ref()
+                .unwrap()
+                .blocking_send(chunk)
+                .map_err(|_| std::io::Error::other(format!("Upload to {} failed", self.url)))
+        }
+    }
+
+    impl Write for GcsWriter {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.buffer.extend_from_slice(buf);
+            if self.buffer.len() >= GCS_CHUNK_SIZE {
+                self.send_buffer()?;
+            }
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl OutputWriter for GcsWriter {
+        fn finish(mut self: Box<Self>) -> std::io::Result<()> {
+            if !self.buffer.is_empty() {
+                self.send_buffer()?;
+            }
+            // closing the channel ends the object's data
+            self.sender = None;
+            let upload = self.upload.take().unwrap();
+            self.runtime
+                .block_on(upload)
+                .map_err(std::io::Error::other)?
+                .map(|_| ())
+                .map_err(|err| {
+                    std::io::Error::other(format!("Unable to upload {}: {}", self.url, err))
+                })
+        }
+    }
+
+    // Cancel an upload that wasn't finished, so a partial object isn't
+    // created when the channel closes.
+    impl Drop for GcsWriter {
+        fn drop(&mut self) {
+            if let Some(upload) = self.upload.take() {
+                upload.abort();
+            }
+        }
+    }
+}
@@ -0,0 +1,3 @@
+// Encoding cell geometry for interchange with other tools.
+
+pub mod wkt;
@@ -0,0 +1,64 @@
+// Well-Known Text (WKT) encoding of cell polygons.
+
+use geo::{LineString, MultiPolygon};
+use std::fmt::Write;
+
+fn write_ring(wkt: &mut String, ring: &LineString<f32>, decimals: usize) {
+    wkt.push('(');
+    for (i, coord) in ring.coords().enumerate() {
+        if i > 0 {
+            wkt.push_str(", ");
+        }
+        write!(wkt, "{:.*} {:.*}", decimals, coord.x, decimals, coord.y).unwrap();
+    }
+    wkt.push(')');
+}
+
+// Encode a multipolygon as ISO WKT, with coordinates rounded to the given
+// number of decimal places.
+pub fn encode_multipolygon_wkt(poly: &MultiPolygon<f32>, decimals: usize) -> String {
+    if poly.0.is_empty() {
+        return "MULTIPOLYGON EMPTY".to_string();
+    }
+
+    let mut wkt = String::from("MULTIPOLYGON (");
+    for (i, polygon) in poly.iter().enumerate() {
+        if i > 0 {
+            wkt.push_str(", ");
+        }
+        wkt.push('(');
+        write_ring(&mut wkt, polygon.exterior(), decimals);
+        for interior in polygon.interiors() {
+            wkt.push_str(", ");
+            write_ring(&mut wkt, interior, decimals);
+        }
+        wkt.push(')');
+    }
+    wkt.push(')');
+    wkt
+}
+
+#[test]
+fn multipolygon_wkt_encoding() {
+    use geo::Polygon;
+
+    assert_eq!(
+        encode_multipolygon_wkt(&MultiPolygon::new(vec![]), 3),
+        "MULTIPOLYGON EMPTY"
+    );
+
+    let square = Polygon::new(
+        LineString::from(vec![(0.0, 0.0), (4.0, 0.0), (4.0, 4.0), (0.0, 4.0)]),
+        vec![LineString::from(vec![(1.0, 1.0), (2.0, 1.0), (2.0, 2.0)])],
+    );
+    let triangle = Polygon::new(
+        LineString::from(vec![(10.0, 0.0), (11.24, 0.0), (10.0, 1.0)]),
+        vec![],
+    );
+    assert_eq!(
+        encode_multipolygon_wkt(&MultiPolygon::new(vec![square, triangle]), 1),
+        "MULTIPOLYGON (((0.0 0.0, 4.0 0.0, 4.0 4.0, 0.0 4.0, 0.0 0.0), \
+         (1.0 1.0, 2.0 1.0, 2.0 2.0, 1.0 1.0)), \
+         ((10.0 0.0, 11.2 0.0, 10.0 1.0, 10.0 0.0)))"
+    );
+}
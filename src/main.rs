@@ -1,22 +1,39 @@
 #![allow(confusable_idents)]
 
-use clap::Parser;
+use clap::{CommandFactory, FromArgMatches, Parser};
 
+mod analysis;
+mod checkpoint;
+mod checksum;
+mod cloud;
+mod geometry;
 mod output;
 mod sampler;
 
+use analysis::deduplication::find_duplicate_transcripts;
+use analysis::hexbin::HexBinner;
+use analysis::matching::read_cell_centroids_csv;
+use analysis::spatial::{neighbor_expression_similarity, principal_axis, tissue_boundary};
+use checkpoint::{load_checkpoint_binary, save_checkpoint_binary, Checkpoint};
+use cloud::cloud_backend_for_urls;
 use indicatif::{ProgressBar, ProgressStyle};
 use itertools::Itertools;
 use rayon::current_num_threads;
-use sampler::voxelsampler::{filter_sparse_cells, VoxelSampler};
 use sampler::hull::compute_cell_areas;
 use sampler::transcripts::{
-    coordinate_span, estimate_full_area, filter_cellfree_transcripts, read_transcripts_csv,
-    Transcript,
+    coordinate_span, estimate_full_area, filter_cellfree_transcripts, read_fov_bounds_csv,
+    read_transcripts_csv, retain_transcripts, Transcript,
+};
+use sampler::voxelsampler::{
+    filter_sparse_cells, transcript_voxels, CentroidTracker, VoxelSampler,
+};
+use sampler::{
+    CellCountTrace, ModelParams, ModelPriors, ProposalStats, Sampler, TranscriptAssignmentTracker,
+    UncertaintyTracker,
 };
-use sampler::{ModelParams, ModelPriors, ProposalStats, Sampler, UncertaintyTracker};
 use std::cell::RefCell;
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
 
 use output::*;
 
@@ -31,7 +48,8 @@ struct Args {
     /// CSV with transcript information. How this is interpreted is determined
     /// either by using a preset (`--xenium`, `--cosmx`, `--cosmx-micron`, `--merfish`)
     /// or by manually setting column names using (`--x-column`, `--transcript-column`, etc).
-    transcript_csv: String,
+    #[arg(required_unless_present_any = ["migrate_transcript_metadata", "checkpoint_info"])]
+    transcript_csv: Option<String>,
 
     /// Preset for 10X Xenium data
     #[arg(long, default_value_t = false)]
@@ -237,6 +255,422 @@ struct Args {
     #[arg(long, value_enum, default_value_t = OutputFormat::Infer)]
     output_component_params_fmt: OutputFormat,
 
+    /// Output posterior variance of the Poisson expression rates per cell
+    #[arg(long, default_value = None)]
+    output_rate_variance: Option<String>,
+
+    #[arg(long, value_enum, default_value_t = OutputFormat::Infer)]
+    output_rate_variance_fmt: OutputFormat,
+
+    /// Output posterior variance of the per-component dispersion parameters
+    #[arg(long, default_value = None)]
+    output_dispersion_variance: Option<String>,
+
+    #[arg(long, value_enum, default_value_t = OutputFormat::Infer)]
+    output_dispersion_variance_fmt: OutputFormat,
+
+    /// Output per-gene decomposition of expression variability into spatial trend and residual
+    #[arg(long, default_value = None)]
+    output_spatial_variability: Option<String>,
+
+    #[arg(long, value_enum, default_value_t = OutputFormat::Infer)]
+    output_spatial_variability_fmt: OutputFormat,
+
+    /// Output directory for counts and cell locations in CARD input format
+    #[arg(long, default_value = None)]
+    output_card_dir: Option<String>,
+
+    /// Include per-component expression profiles as reference.csv in CARD output
+    #[arg(long, default_value_t = false)]
+    output_card_reference: bool,
+
+    /// Output the number of active cells after each iteration
+    #[arg(long, default_value = None)]
+    output_cell_count_trace: Option<String>,
+
+    #[arg(long, value_enum, default_value_t = OutputFormat::Infer)]
+    output_cell_count_trace_fmt: OutputFormat,
+
+    /// Output a matrix of per-cell rates scaled by cell volume
+    #[arg(long, default_value = None)]
+    output_per_cell_rates: Option<String>,
+
+    #[arg(long, value_enum, default_value_t = OutputFormat::Infer)]
+    output_per_cell_rates_fmt: OutputFormat,
+
+    /// Output cell metadata with cells projected onto the xy plane
+    #[arg(long, default_value = None)]
+    output_z_projection_metadata: Option<String>,
+
+    #[arg(long, value_enum, default_value_t = OutputFormat::Infer)]
+    output_z_projection_metadata_fmt: OutputFormat,
+
+    /// How cell positions are chosen in z-projected metadata
+    #[arg(long, value_enum, default_value_t = ZProjection::MeanPosition)]
+    z_projection: ZProjection,
+
+    /// Output a single 2D polygon per cell, the union of its polygons across layers
+    #[arg(long, default_value = None)]
+    output_z_projected_polygons: Option<String>,
+
+    /// Output background transcript rates per hex bin and layer
+    #[arg(long, default_value = None)]
+    output_background_spatial_stats: Option<String>,
+
+    #[arg(long, value_enum, default_value_t = OutputFormat::Infer)]
+    output_background_spatial_stats_fmt: OutputFormat,
+
+    /// Size (center to corner distance) of hexagonal bins used in spatially binned output
+    #[arg(long, default_value_t = 50.0)]
+    hex_bin_size: f32,
+
+    /// Output displacement of each cell relative to a cell metadata table from an earlier timepoint
+    #[arg(long, default_value = None)]
+    output_cell_displacements: Option<String>,
+
+    #[arg(long, value_enum, default_value_t = OutputFormat::Infer)]
+    output_cell_displacements_fmt: OutputFormat,
+
+    /// Cell metadata (csv or csv.gz) from the earlier timepoint to match cells against
+    #[arg(long, default_value = None)]
+    displacement_reference: Option<String>,
+
+    /// Cells further than this apart are not matched between timepoints
+    #[arg(long, default_value_t = 20.0)]
+    displacement_max_distance: f32,
+
+    /// Also write rates and component parameters as initialized, before sampling,
+    /// with an `_init` suffix on the output filenames
+    #[arg(long, default_value_t = false)]
+    save_init_params: bool,
+
+    /// Output per-gene summary of how much rates and dispersions changed from initialization
+    #[arg(long, default_value = None)]
+    output_parameter_change_summary: Option<String>,
+
+    #[arg(long, value_enum, default_value_t = OutputFormat::Infer)]
+    output_parameter_change_summary_fmt: OutputFormat,
+
+    /// Output per-cell statistics on transcript assignment changes over the final iterations.
+    /// Also adds an `assignment_stable` column to cell metadata.
+    #[arg(long, default_value = None)]
+    output_assignment_instability: Option<String>,
+
+    #[arg(long, value_enum, default_value_t = OutputFormat::Infer)]
+    output_assignment_instability_fmt: OutputFormat,
+
+    /// Number of final iterations over which to track assignment changes
+    #[arg(long, default_value_t = 50)]
+    assignment_tracking_window: usize,
+
+    /// Record assignments every this many iterations when tracking assignment changes
+    #[arg(long, default_value_t = 5)]
+    assignment_tracking_interval: usize,
+
+    /// Cells with an instability score above this are marked as not stable
+    #[arg(long, default_value_t = 0.01)]
+    assignment_stability_threshold: f32,
+
+    /// Output Lee's L spatial cross-correlation between pairs of genes
+    #[arg(long, default_value = None)]
+    output_spatial_cross_correlation: Option<String>,
+
+    #[arg(long, value_enum, default_value_t = OutputFormat::Infer)]
+    output_spatial_cross_correlation_fmt: OutputFormat,
+
+    /// Only compute spatial cross-correlation between genes with at least this total expected count
+    #[arg(long, default_value_t = 100.0)]
+    spatial_cross_correlation_min_count: f32,
+
+    /// Only compute spatial cross-correlation between this many genes, those
+    /// with the highest total expected count. The number of gene pairs grows
+    /// quadratically with this.
+    #[arg(long, default_value_t = 50)]
+    spatial_cross_correlation_max_genes: usize,
+
+    /// Output the number of rows written to each output table, and the number expected
+    #[arg(long, default_value = None)]
+    output_row_counts: Option<String>,
+
+    #[arg(long, value_enum, default_value_t = OutputFormat::Infer)]
+    output_row_counts_fmt: OutputFormat,
+
+    /// Output the fraction of cells of each cluster in bins along a spatial axis
+    #[arg(long, default_value = None)]
+    output_spatial_composition: Option<String>,
+
+    #[arg(long, value_enum, default_value_t = OutputFormat::Infer)]
+    output_spatial_composition_fmt: OutputFormat,
+
+    /// Axis along which cells are binned in the spatial composition profile
+    #[arg(long, value_enum, default_value_t = SpatialAxis::Y)]
+    spatial_composition_axis: SpatialAxis,
+
+    /// Number of bins in the spatial composition profile
+    #[arg(long, default_value_t = 50)]
+    spatial_composition_bins: usize,
+
+    /// Smooth spatial composition fractions with a Gaussian kernel of this bandwidth, in bins
+    #[arg(long, default_value = None)]
+    spatial_composition_smoothing: Option<f32>,
+
+    /// Output a zone for each cell based on its distance from the tissue boundary
+    #[arg(long, default_value = None)]
+    output_cell_zones: Option<String>,
+
+    #[arg(long, value_enum, default_value_t = OutputFormat::Infer)]
+    output_cell_zones_fmt: OutputFormat,
+
+    /// Distances from the tissue boundary at which each new cell zone begins
+    #[arg(long, num_args=1.., value_delimiter=',', default_values_t=[50.0, 150.0])]
+    zone_radii: Vec<f32>,
+
+    /// Output a histogram of transcript assignment probabilities for each gene
+    #[arg(long, default_value = None)]
+    output_gene_assignment_distribution: Option<String>,
+
+    #[arg(long, value_enum, default_value_t = OutputFormat::Infer)]
+    output_gene_assignment_distribution_fmt: OutputFormat,
+
+    /// Output the sparse voxel by cell assignment matrix
+    #[arg(long, default_value = None)]
+    output_voxel_cell_incidence: Option<String>,
+
+    #[arg(long, value_enum, default_value_t = OutputFormat::Infer)]
+    output_voxel_cell_incidence_fmt: OutputFormat,
+
+    /// Output sparse transcript counts per voxel and gene
+    #[arg(long, default_value = None)]
+    output_voxel_features: Option<String>,
+
+    #[arg(long, value_enum, default_value_t = OutputFormat::Infer)]
+    output_voxel_features_fmt: OutputFormat,
+
+    /// Statistics and checksums to include in parquet output
+    #[arg(long, value_enum, default_value_t = WriteStatisticsLevel::ColumnLevel)]
+    parquet_write_statistics: WriteStatisticsLevel,
+
+    /// Verify checksums of parquet output after it's written
+    #[arg(long, default_value_t = false)]
+    verify_outputs: bool,
+
+    /// Output the local outlier factor of each cell's position among its neighbors
+    #[arg(long, default_value = None)]
+    output_spatial_outliers: Option<String>,
+
+    #[arg(long, value_enum, default_value_t = OutputFormat::Infer)]
+    output_spatial_outliers_fmt: OutputFormat,
+
+    /// Add a spatial outlier score column to cell metadata
+    #[arg(long, default_value_t = false)]
+    spatial_outlier_detection: bool,
+
+    /// Number of nearest neighbors used to compute spatial outlier scores
+    #[arg(long, default_value_t = 10)]
+    spatial_outlier_neighbors: usize,
+
+    /// Output a JSON file with the most enriched genes of each component
+    #[arg(long, default_value = None)]
+    output_component_fingerprints: Option<String>,
+
+    /// Number of genes to include for each component in component fingerprints
+    #[arg(long, default_value_t = 20)]
+    component_fingerprint_genes: usize,
+
+    /// Output directory for a raster of local cluster diversity (Shannon entropy)
+    #[arg(long, default_value = None)]
+    output_spatial_entropy_dir: Option<String>,
+
+    /// Radius of the neighborhood used to compute local cluster diversity
+    #[arg(long, default_value_t = 50.0)]
+    spatial_entropy_bandwidth: f32,
+
+    /// Pixel size of the local cluster diversity raster
+    #[arg(long, default_value_t = 10.0)]
+    spatial_entropy_pixel_size: f32,
+
+    /// CSV with FOV bounding boxes in columns x_min, y_min, x_max, y_max, used
+    /// to flag cells on FOV boundaries in the cell metadata
+    #[arg(long, default_value = None)]
+    fov_bounds_csv: Option<String>,
+
+    /// Output cells ordered by their position along a spatial axis
+    #[arg(long, default_value = None)]
+    output_spatial_pseudotime: Option<String>,
+
+    #[arg(long, value_enum, default_value_t = OutputFormat::Infer)]
+    output_spatial_pseudotime_fmt: OutputFormat,
+
+    /// Output the fraction of cells of each cluster in bins along the spatial pseudotime axis
+    #[arg(long, default_value = None)]
+    output_pseudotime_bin_composition: Option<String>,
+
+    #[arg(long, value_enum, default_value_t = OutputFormat::Infer)]
+    output_pseudotime_bin_composition_fmt: OutputFormat,
+
+    /// Direction (x,y,z) of the spatial pseudotime axis. If not given, the
+    /// first principal component of the cell centroids is used.
+    #[arg(long, num_args=1.., value_delimiter=',', allow_hyphen_values=true)]
+    pseudotime_axis: Option<Vec<f32>>,
+
+    /// Output a table of cell polygons encoded as WKT in a geometry column
+    #[arg(long, default_value = None)]
+    output_cell_polygons_wkt: Option<String>,
+
+    #[arg(long, value_enum, default_value_t = OutputFormat::Infer)]
+    output_cell_polygons_wkt_fmt: OutputFormat,
+
+    /// Number of decimal places of coordinates in WKT cell polygons
+    #[arg(long, default_value_t = 3)]
+    wkt_coordinate_decimals: usize,
+
+    /// Output a per-gene comparison of prior and posterior means of λ and r
+    #[arg(long, default_value = None)]
+    output_prior_posterior_comparison: Option<String>,
+
+    #[arg(long, value_enum, default_value_t = OutputFormat::Infer)]
+    output_prior_posterior_comparison_fmt: OutputFormat,
+
+    /// Output the top marker genes of each component, ranked by log2 fold change
+    #[arg(long, default_value = None)]
+    output_marker_genes: Option<String>,
+
+    #[arg(long, value_enum, default_value_t = OutputFormat::Infer)]
+    output_marker_genes_fmt: OutputFormat,
+
+    /// Number of marker genes to report for each component
+    #[arg(long, default_value_t = 20)]
+    marker_genes_per_component: usize,
+
+    /// Exclude transcripts flagged as likely duplicate detections of the same
+    /// molecule (same gene, in the same initial voxel or within half the
+    /// initial voxel size) from the model
+    #[arg(long, default_value_t = false)]
+    exclude_duplicates: bool,
+
+    /// Number of spatial neighbors used to compute each cell's mean expression
+    /// similarity to its neighbors in the cell metadata
+    #[arg(long, default_value_t = 10)]
+    neighbor_similarity_neighbors: usize,
+
+    /// Only output transcripts with at least this true positive probability in
+    /// the transcript metadata
+    #[arg(long, default_value = None)]
+    tp_threshold: Option<f32>,
+
+    /// Output Ripley's L function of each component's cell positions
+    #[arg(long, default_value = None)]
+    output_ripleys_l: Option<String>,
+
+    #[arg(long, value_enum, default_value_t = OutputFormat::Infer)]
+    output_ripleys_l_fmt: OutputFormat,
+
+    /// Radii at which Ripley's L function is evaluated
+    #[arg(long, num_args=1.., value_delimiter=',', default_values_t=[0.0, 50.0, 100.0, 200.0])]
+    ripleys_radii: Vec<f32>,
+
+    /// Output a markdown document describing changes to output table schemas
+    #[arg(long, default_value = None)]
+    output_schema_changelog: Option<String>,
+
+    /// Upgrade transcript metadata written by an older version of proseg (in
+    /// parquet format) to the current schema, given input and output paths,
+    /// and exit without running segmentation
+    #[arg(long, num_args = 2, value_names = ["OLD", "NEW"])]
+    migrate_transcript_metadata: Option<Vec<String>>,
+
+    /// Output cell, transcript, and background counts for each z-layer
+    #[arg(long, default_value = None)]
+    output_layer_summary: Option<String>,
+
+    #[arg(long, value_enum, default_value_t = OutputFormat::Infer)]
+    output_layer_summary_fmt: OutputFormat,
+
+    /// Output the Jensen-Shannon divergence between each pair of component expression profiles
+    #[arg(long, default_value = None)]
+    output_component_jsd: Option<String>,
+
+    #[arg(long, value_enum, default_value_t = OutputFormat::Infer)]
+    output_component_jsd_fmt: OutputFormat,
+
+    /// Output the fraction of transcripts on each cell-cell boundary assigned to the cell containing them
+    #[arg(long, default_value = None)]
+    output_cell_boundary_purity: Option<String>,
+
+    #[arg(long, value_enum, default_value_t = OutputFormat::Infer)]
+    output_cell_boundary_purity_fmt: OutputFormat,
+
+    /// Genes with a coefficient of variation of rates across components below
+    /// this are flagged as housekeeping genes in the gene metadata
+    #[arg(long, default_value_t = 0.2)]
+    housekeeping_cv_threshold: f32,
+
+    /// Genes with a coefficient of variation of rates across components above
+    /// this are flagged as markers in the gene metadata
+    #[arg(long, default_value_t = 1.0)]
+    marker_cv_threshold: f32,
+
+    /// Output pairs of adjacent cells weighted by the area of their shared boundary
+    #[arg(long, default_value = None)]
+    output_cell_adjacency: Option<String>,
+
+    #[arg(long, value_enum, default_value_t = OutputFormat::Infer)]
+    output_cell_adjacency_fmt: OutputFormat,
+
+    /// Number of final iterations over which cell centroids are averaged
+    /// (by default, the last 10% of iterations)
+    #[arg(long, default_value = None)]
+    centroid_averaging_window: Option<usize>,
+
+    /// Output covariance across cells of expected counts for the most variable genes
+    #[arg(long, default_value = None)]
+    output_gene_covariance: Option<String>,
+
+    #[arg(long, value_enum, default_value_t = OutputFormat::Infer)]
+    output_gene_covariance_fmt: OutputFormat,
+
+    /// Number of genes, by variance across cells, to include in the gene covariance matrix
+    #[arg(long, default_value_t = 500)]
+    gene_covariance_top_genes: usize,
+
+    /// Save a binary checkpoint of the final model state (e.g. `checkpoint.proseg`)
+    #[arg(long, default_value = None)]
+    output_checkpoint: Option<String>,
+
+    /// Resume sampling from a binary checkpoint saved with `--output-checkpoint`
+    /// from the same transcripts and model settings. Only the last stage of the
+    /// schedule is run, at the checkpoint's voxel resolution.
+    #[arg(long, default_value = None)]
+    resume_checkpoint: Option<String>,
+
+    /// Print a summary of a binary checkpoint and exit without running segmentation
+    #[arg(long, default_value = None)]
+    checkpoint_info: Option<String>,
+
+    /// Output observed and expected numbers of contacts between cells of each pair of components
+    #[arg(long, default_value = None)]
+    output_cell_type_contact_enrichment: Option<String>,
+
+    #[arg(long, value_enum, default_value_t = OutputFormat::Infer)]
+    output_cell_type_contact_enrichment_fmt: OutputFormat,
+
+    /// Output pairwise cosine similarities between cells' expected counts
+    #[arg(long, default_value = None)]
+    output_cell_expression_similarity: Option<String>,
+
+    #[arg(long, value_enum, default_value_t = OutputFormat::Infer)]
+    output_cell_expression_similarity_fmt: OutputFormat,
+
+    /// Maximum number of cells in the expression similarity output. Larger
+    /// datasets are subsampled evenly across components.
+    #[arg(long, default_value_t = 5000)]
+    cell_similarity_max_cells: usize,
+
+    /// Number of most similar cells written for each cell when the expression
+    /// similarity output includes more than 5000 cells
+    #[arg(long, default_value_t = 15)]
+    cell_similarity_neighbors: usize,
+
     #[arg(long, value_enum, default_value_t = OutputFormat::Infer)]
     output_expected_counts_fmt: OutputFormat,
 
@@ -255,8 +689,8 @@ struct Args {
     #[arg(long, default_value = "transcript-metadata.csv.gz")]
     output_transcript_metadata: Option<String>,
 
-    #[arg(long, value_enum, default_value_t = OutputFormat::Infer)]
-    output_transcript_metadata_fmt: OutputFormat,
+    #[arg(long, value_enum, default_value_t = SinkFormat::Infer)]
+    output_transcript_metadata_fmt: SinkFormat,
 
     /// Output gene metadata
     #[arg(long, default_value=None)]
@@ -269,8 +703,8 @@ struct Args {
     #[arg(long, default_value=None)]
     output_cell_voxels: Option<String>,
 
-    #[arg(long, value_enum, default_value_t = OutputFormat::Infer)]
-    output_cell_voxels_fmt: OutputFormat,
+    #[arg(long, value_enum, default_value_t = SinkFormat::Infer)]
+    output_cell_voxels_fmt: SinkFormat,
 
     /// Output cell polygons flattened to 2D
     #[arg(long, default_value = "cell-polygons.geojson.gz")]
@@ -280,6 +714,19 @@ struct Args {
     #[arg(long, default_value = "cell-polygons-layers.geojson.gz")]
     output_cell_polygon_layers: Option<String>,
 
+    /// Output cell polygons in GML 3.2 format
+    #[arg(long, default_value = None)]
+    output_cell_polygons_gml: Option<String>,
+
+    /// Omit per-feature bounding boxes from cell polygon GeoJSON output
+    #[arg(long, default_value_t = false)]
+    no_cell_polygon_bbox: bool,
+
+    /// Number of decimal places to round coordinates to in cell polygon
+    /// GeoJSON output. By default, coordinates are written exactly.
+    #[arg(long, default_value = None)]
+    coordinate_precision: Option<usize>,
+
     /// Output cell polygons repeatedly during sampling
     #[arg(long, default_value = None)]
     monitor_cell_polygons: Option<String>,
@@ -390,7 +837,8 @@ fn main() {
     //     panic!();
     // }
 
-    let mut args = Args::parse();
+    let matches = Args::command().get_matches();
+    let mut args = Args::from_arg_matches(&matches).unwrap_or_else(|err| err.exit());
 
     if let Some(nthreads) = args.nthreads {
         rayon::ThreadPoolBuilder::new()
@@ -401,10 +849,57 @@ fn main() {
     let nthreads = current_num_threads();
     println!("Using {} threads", nthreads);
 
-    if (args.xenium as u8) + (args.cosmx as u8) + (args.cosmx_micron as u8) + (args.merfish as u8) + (args.merscope as u8)
+    // Object storage URLs among the output paths determine which cloud
+    // backend, if any, output is written with.
+    let remote_outputs = matches
+        .ids()
+        .filter(|id| id.as_str().starts_with("output_"))
+        .filter_map(|id| matches.get_raw(id.as_str()))
+        .flatten()
+        .filter_map(|value| value.to_str());
+    let outputs = OutputBundle::new(
+        ParquetWriteOptions {
+            write_statistics: args.parquet_write_statistics,
+        },
+        cloud_backend_for_urls(remote_outputs),
+    );
+
+    if let Some(paths) = &args.migrate_transcript_metadata {
+        let (old_path, new_path) = (Path::new(&paths[0]), Path::new(&paths[1]));
+        migrate_transcript_metadata_v1_to_v2(&outputs, old_path, new_path)
+            .unwrap_or_else(|err| panic!("Unable to migrate {}: {}", old_path.display(), err));
+        return;
+    }
+
+    if let Some(checkpoint_info) = &args.checkpoint_info {
+        let path = Path::new(checkpoint_info);
+        let checkpoint = load_checkpoint_binary(path)
+            .unwrap_or_else(|err| panic!("Unable to load {}: {}", path.display(), err));
+        println!(
+            "{}: {} transcripts, {} cells, {} genes, {} components",
+            path.display(),
+            checkpoint.params.cell_assignments.len(),
+            checkpoint.params.z.len(),
+            checkpoint.params.λ.nrows(),
+            checkpoint.params.r.nrows(),
+        );
+        return;
+    }
+
+    if let Some(output_schema_changelog) = &args.output_schema_changelog {
+        write_schema_changelog(&outputs, output_schema_changelog);
+    }
+
+    if (args.xenium as u8)
+        + (args.cosmx as u8)
+        + (args.cosmx_micron as u8)
+        + (args.merfish as u8)
+        + (args.merscope as u8)
         > 1
     {
-        panic!("At most one of --xenium, --cosmx, --cosmx-micron, --merfish, --merscope can be set");
+        panic!(
+            "At most one of --xenium, --cosmx, --cosmx-micron, --merfish, --merscope can be set"
+        );
     }
 
     if args.xenium {
@@ -445,7 +940,7 @@ fn main() {
     mut nucleus_population) = */
 
     let mut dataset = read_transcripts_csv(
-        &args.transcript_csv,
+        args.transcript_csv.as_ref().unwrap(),
         &expect_arg(args.gene_column, "transcript-column"),
         args.transcript_id_column,
         args.compartment_column,
@@ -462,6 +957,12 @@ fn main() {
         args.coordinate_scale.unwrap_or(1.0),
     );
 
+    let fov_bounds = args
+        .fov_bounds_csv
+        .as_ref()
+        .map(|path| read_fov_bounds_csv(path, args.coordinate_scale.unwrap_or(1.0)))
+        .unwrap_or_default();
+
     // Warn if any nucleus has extremely high population, which is likely
     // an error interpreting the file.
     dataset.nucleus_population.iter().for_each(|&p| {
@@ -494,11 +995,25 @@ fn main() {
     }
 
     let mut ncells = dataset.nucleus_population.len();
-    filter_cellfree_transcripts(
-        &mut dataset,
-        ncells,
-        args.max_transcript_nucleus_distance,
-    );
+    filter_cellfree_transcripts(&mut dataset, ncells, args.max_transcript_nucleus_distance);
+
+    // Duplicates are only needed to exclude them or to report them.
+    let mut duplicate_flag = if args.exclude_duplicates || args.output_transcript_metadata.is_some()
+    {
+        let voxels = transcript_voxels(
+            &dataset.transcripts,
+            args.initial_voxel_size,
+            args.voxel_layers,
+        );
+        find_duplicate_transcripts(&dataset.transcripts, &voxels, args.initial_voxel_size / 2.0)
+    } else {
+        ndarray::Array1::from_elem(dataset.transcripts.len(), false)
+    };
+    if args.exclude_duplicates {
+        let mask = duplicate_flag.iter().map(|&d| !d).collect::<Vec<_>>();
+        retain_transcripts(&mut dataset, &mask);
+        duplicate_flag = ndarray::Array1::from_elem(dataset.transcripts.len(), false);
+    }
 
     // keep removing cells until we can initialize with every cell having at least one voxel
     loop {
@@ -656,7 +1171,12 @@ fn main() {
         ngenes,
     );
 
-    let total_iterations = args.schedule.iter().sum::<usize>();
+    // a resumed run skips to the last stage of the schedule
+    let total_iterations = if args.resume_checkpoint.is_some() {
+        *args.schedule.last().unwrap()
+    } else {
+        args.schedule.iter().sum::<usize>()
+    };
     let mut prog = ProgressBar::new(total_iterations as u64);
     prog.set_style(
         ProgressStyle::with_template("{eta_precise} {bar:60} | {msg}")
@@ -680,9 +1200,58 @@ fn main() {
     ));
     sampler.borrow_mut().initialize(&priors, &mut params);
 
+    if let Some(resume_checkpoint) = &args.resume_checkpoint {
+        let path = Path::new(resume_checkpoint);
+        let checkpoint = load_checkpoint_binary(path)
+            .unwrap_or_else(|err| panic!("Unable to load {}: {}", path.display(), err));
+        params.restore_state(checkpoint.params, &dataset.transcripts);
+        sampler.replace_with(|sampler| sampler.restore_state(&params, &checkpoint.voxels));
+    }
+
+    if args.save_init_params {
+        write_rates(
+            &outputs,
+            &args
+                .output_rates
+                .as_ref()
+                .map(|filename| with_filename_suffix(filename, "_init")),
+            args.output_rates_fmt,
+            &params,
+            &dataset.transcript_names,
+        );
+        write_component_params(
+            &outputs,
+            &args
+                .output_component_params
+                .as_ref()
+                .map(|filename| with_filename_suffix(filename, "_init")),
+            args.output_component_params_fmt,
+            &params,
+            &dataset.transcript_names,
+        );
+    }
+    let params_init = args
+        .output_parameter_change_summary
+        .is_some()
+        .then(|| params.snapshot());
+
     let mut total_steps = 0;
+    let mut cell_count_trace = CellCountTrace::new();
+    let mut assignment_tracker = args.output_assignment_instability.is_some().then(|| {
+        TranscriptAssignmentTracker::new(
+            total_iterations,
+            args.assignment_tracking_window,
+            args.assignment_tracking_interval,
+        )
+    });
 
-    if args.schedule.len() > 1 {
+    let mut centroid_tracker = CentroidTracker::new(
+        total_iterations,
+        args.centroid_averaging_window
+            .unwrap_or(total_iterations / 10),
+    );
+
+    if args.schedule.len() > 1 && args.resume_checkpoint.is_none() {
         run_hexbin_sampler(
             &mut prog,
             sampler.get_mut(),
@@ -693,6 +1262,10 @@ fn main() {
             args.morphology_steps_per_iter,
             None,
             &mut total_steps,
+            &mut cell_count_trace,
+            &mut assignment_tracker,
+            &mut centroid_tracker,
+            &outputs,
             &args.monitor_cell_polygons,
             args.monitor_cell_polygons_freq,
             true,
@@ -717,6 +1290,10 @@ fn main() {
                 args.morphology_steps_per_iter,
                 None,
                 &mut total_steps,
+                &mut cell_count_trace,
+                &mut assignment_tracker,
+                &mut centroid_tracker,
+                &outputs,
                 &args.monitor_cell_polygons,
                 args.monitor_cell_polygons_freq,
                 true,
@@ -740,6 +1317,10 @@ fn main() {
         args.morphology_steps_per_iter,
         None,
         &mut total_steps,
+        &mut cell_count_trace,
+        &mut assignment_tracker,
+        &mut centroid_tracker,
+        &outputs,
         &args.monitor_cell_polygons,
         args.monitor_cell_polygons_freq,
         true,
@@ -757,6 +1338,10 @@ fn main() {
         args.morphology_steps_per_iter,
         Some(&mut uncertainty),
         &mut total_steps,
+        &mut cell_count_trace,
+        &mut assignment_tracker,
+        &mut centroid_tracker,
+        &outputs,
         &args.monitor_cell_polygons,
         args.monitor_cell_polygons_freq,
         true,
@@ -768,8 +1353,18 @@ fn main() {
         sampler.borrow_mut().check_consistency(&priors, &mut params);
     }
     prog.finish();
+    println!(
+        "Cell births: {}, deaths: {}",
+        cell_count_trace.n_births, cell_count_trace.n_deaths
+    );
 
     uncertainty.finish(&params);
+    params.finish_parameter_variance();
+    if let Some(output_checkpoint) = &args.output_checkpoint {
+        let path = Path::new(output_checkpoint);
+        save_checkpoint_binary(path, &Checkpoint::new(&params, &sampler.borrow()))
+            .unwrap_or_else(|err| panic!("Unable to save {}: {}", path.display(), err));
+    }
     let (counts, cell_assignments) = uncertainty.max_posterior_transcript_counts_assignments(
         &params,
         &dataset.transcripts,
@@ -778,42 +1373,323 @@ fn main() {
     );
 
     let ecounts = uncertainty.expected_counts(&params, &dataset.transcripts);
-    let cell_centroids = sampler.borrow().cell_centroids();
+    let cell_centroids = centroid_tracker.posterior_mean_centroids(params.ncells());
+    let cell_isolation_scores = sampler.borrow().cell_isolation_scores();
+    let hex_binner = HexBinner::new(args.hex_bin_size);
+    let cell_polygons = (args.output_cell_metadata.is_some()
+        || args.output_z_projection_metadata.is_some()
+        || args.output_cell_polygon_layers.is_some()
+        || args.output_cell_polygons.is_some()
+        || args.output_z_projected_polygons.is_some()
+        || args.output_cell_polygons_gml.is_some()
+        || args.output_cell_polygons_wkt.is_some())
+    .then(|| sampler.borrow().cell_polygons());
+    let cell_shape_indices = cell_polygons
+        .as_ref()
+        .map(|(_, cell_flattened_polygons)| cell_shape_indices(cell_flattened_polygons))
+        .unwrap_or_default();
+    let cell_neighbor_similarity =
+        if args.output_cell_metadata.is_some() || args.output_z_projection_metadata.is_some() {
+            neighbor_expression_similarity(
+                &ecounts,
+                &cell_centroids,
+                args.neighbor_similarity_neighbors,
+            )
+        } else {
+            Vec::new()
+        };
+    let cell_spatial_outlier_scores = args
+        .spatial_outlier_detection
+        .then(|| spatial_outlier_scores(&cell_centroids, args.spatial_outlier_neighbors));
+    let cell_assignment_stable = assignment_tracker.as_ref().map(|tracker| {
+        let (_, _, instability_score) = tracker.instability(params.ncells());
+        instability_score
+            .iter()
+            .map(|&score| score <= args.assignment_stability_threshold)
+            .collect::<Vec<_>>()
+    });
 
     write_expected_counts(
+        &outputs,
         &args.output_expected_counts,
         args.output_expected_counts_fmt,
         &dataset.transcript_names,
         &ecounts,
     );
     write_counts(
+        &outputs,
         &args.output_maxpost_counts,
         args.output_maxpost_counts_fmt,
         &dataset.transcript_names,
         &counts,
     );
+    write_card_input(
+        &outputs,
+        &args.output_card_dir,
+        &counts,
+        &cell_centroids,
+        &dataset.transcript_names,
+        args.output_card_reference
+            .then(|| params.component_mean_rates())
+            .as_ref(),
+    );
     write_rates(
+        &outputs,
         &args.output_rates,
         args.output_rates_fmt,
         &params,
         &dataset.transcript_names,
     );
+    write_per_cell_rates(
+        &outputs,
+        &args.output_per_cell_rates,
+        args.output_per_cell_rates_fmt,
+        &params,
+        &dataset.transcript_names,
+    );
     write_component_params(
+        &outputs,
         &args.output_component_params,
         args.output_component_params_fmt,
         &params,
         &dataset.transcript_names,
     );
+    write_rate_variance(
+        &outputs,
+        &args.output_rate_variance,
+        args.output_rate_variance_fmt,
+        &params,
+        &dataset.transcript_names,
+    );
+    write_dispersion_variance(
+        &outputs,
+        &args.output_dispersion_variance,
+        args.output_dispersion_variance_fmt,
+        &params,
+        &dataset.transcript_names,
+    );
+    write_spatial_variability_decomposition(
+        &outputs,
+        &args.output_spatial_variability,
+        args.output_spatial_variability_fmt,
+        &ecounts,
+        &cell_centroids,
+        &dataset.transcript_names,
+    );
+    if args.output_spatial_cross_correlation.is_some() {
+        let gene_totals = ecounts.sum_axis(ndarray::Axis(1));
+        let mut genes: Vec<usize> = (0..ngenes)
+            .filter(|&gene| gene_totals[gene] >= args.spatial_cross_correlation_min_count)
+            .collect();
+        genes.sort_by(|&a, &b| gene_totals[b].total_cmp(&gene_totals[a]));
+        genes.truncate(args.spatial_cross_correlation_max_genes);
+        genes.sort();
+        let gene_pairs: Vec<(usize, usize)> = genes
+            .iter()
+            .tuple_combinations()
+            .map(|(&a, &b)| (a, b))
+            .collect();
+        write_spatial_cross_correlation(
+            &outputs,
+            &args.output_spatial_cross_correlation,
+            args.output_spatial_cross_correlation_fmt,
+            &cell_centroids,
+            &ecounts,
+            &dataset.transcript_names,
+            &gene_pairs,
+        );
+    }
+    if let Some(output_component_fingerprints) = &args.output_component_fingerprints {
+        write_component_fingerprints_json(
+            &outputs,
+            output_component_fingerprints,
+            &params,
+            &dataset.transcript_names,
+            args.component_fingerprint_genes,
+        );
+    }
+    write_cell_expression_similarity(
+        &outputs,
+        &args.output_cell_expression_similarity,
+        args.output_cell_expression_similarity_fmt,
+        &ecounts,
+        params.z.as_slice().unwrap(),
+        params.ncomponents(),
+        args.cell_similarity_max_cells,
+        args.cell_similarity_neighbors,
+    );
+    write_gene_covariance(
+        &outputs,
+        &args.output_gene_covariance,
+        args.output_gene_covariance_fmt,
+        &ecounts,
+        &dataset.transcript_names,
+        args.gene_covariance_top_genes,
+    );
+    write_component_jsd_matrix(
+        &outputs,
+        &args.output_component_jsd,
+        args.output_component_jsd_fmt,
+        &params,
+    );
+    write_marker_genes(
+        &outputs,
+        &args.output_marker_genes,
+        args.output_marker_genes_fmt,
+        &params,
+        &dataset.transcript_names,
+        args.marker_genes_per_component,
+    );
+    write_spatial_entropy_map(
+        &outputs,
+        &args.output_spatial_entropy_dir,
+        &cell_centroids,
+        params.z.as_slice().unwrap(),
+        params.ncomponents(),
+        args.spatial_entropy_bandwidth,
+        args.spatial_entropy_pixel_size,
+    );
+    write_spatial_composition_profile(
+        &outputs,
+        &args.output_spatial_composition,
+        args.output_spatial_composition_fmt,
+        &cell_centroids,
+        &params.z,
+        params.ncomponents(),
+        args.spatial_composition_axis,
+        args.spatial_composition_bins,
+        args.spatial_composition_smoothing,
+    );
+    if args.output_spatial_pseudotime.is_some() || args.output_pseudotime_bin_composition.is_some()
+    {
+        let axis_direction = match &args.pseudotime_axis {
+            Some(axis) => {
+                if axis.len() != 3 {
+                    panic!("--pseudotime-axis must have exactly three components");
+                }
+                (axis[0], axis[1], axis[2])
+            }
+            None => principal_axis(&cell_centroids),
+        };
+        write_spatial_pseudotime(
+            &outputs,
+            &args.output_spatial_pseudotime,
+            args.output_spatial_pseudotime_fmt,
+            &args.output_pseudotime_bin_composition,
+            args.output_pseudotime_bin_composition_fmt,
+            &cell_centroids,
+            axis_direction,
+            &params.z,
+            params.ncomponents(),
+            args.spatial_composition_bins,
+            args.spatial_composition_smoothing,
+        );
+    }
+    if args.output_cell_zones.is_some() {
+        write_cell_zone_annotations(
+            &outputs,
+            &args.output_cell_zones,
+            args.output_cell_zones_fmt,
+            &cell_centroids,
+            &tissue_boundary(&params.transcript_positions),
+            &args.zone_radii,
+        );
+    }
+    write_prior_posterior_comparison(
+        &outputs,
+        &args.output_prior_posterior_comparison,
+        args.output_prior_posterior_comparison_fmt,
+        &priors,
+        &params,
+        &dataset.transcript_names,
+    );
+    if let Some(params_init) = &params_init {
+        write_parameter_change_summary(
+            &outputs,
+            &args.output_parameter_change_summary,
+            args.output_parameter_change_summary_fmt,
+            params_init,
+            &params,
+            &dataset.transcript_names,
+        );
+    }
+    if let Some(assignment_tracker) = &assignment_tracker {
+        write_assignment_instability(
+            &outputs,
+            &args.output_assignment_instability,
+            args.output_assignment_instability_fmt,
+            assignment_tracker,
+            params.ncells(),
+        );
+    }
+    write_cell_count_trace(
+        &outputs,
+        &args.output_cell_count_trace,
+        args.output_cell_count_trace_fmt,
+        &cell_count_trace.trace,
+    );
+    write_layer_summary(
+        &outputs,
+        &args.output_layer_summary,
+        args.output_layer_summary_fmt,
+        &params,
+        &cell_centroids,
+        &cell_assignments,
+    );
+    write_ripleys_l(
+        &outputs,
+        &args.output_ripleys_l,
+        args.output_ripleys_l_fmt,
+        &cell_centroids,
+        &params.z,
+        params.ncomponents(),
+        &args.ripleys_radii,
+    );
+    write_spatial_outlier_scores(
+        &outputs,
+        &args.output_spatial_outliers,
+        args.output_spatial_outliers_fmt,
+        &cell_centroids,
+        args.spatial_outlier_neighbors,
+    );
     write_cell_metadata(
+        &outputs,
         &args.output_cell_metadata,
         args.output_cell_metadata_fmt,
         &params,
+        &sampler.borrow(),
+        &cell_centroids,
+        &cell_isolation_scores,
+        &cell_shape_indices,
+        &cell_neighbor_similarity,
+        cell_assignment_stable.as_deref(),
+        cell_spatial_outlier_scores.as_deref(),
+        &fov_bounds,
+        &counts,
+        &cell_assignments,
+        &dataset.fovs,
+        &dataset.fov_names,
+    );
+    write_z_projection_metadata(
+        &outputs,
+        &args.output_z_projection_metadata,
+        args.output_z_projection_metadata_fmt,
+        args.z_projection,
+        &params,
+        &sampler.borrow(),
         &cell_centroids,
+        &cell_isolation_scores,
+        &cell_shape_indices,
+        &cell_neighbor_similarity,
+        cell_assignment_stable.as_deref(),
+        cell_spatial_outlier_scores.as_deref(),
+        &counts,
         &cell_assignments,
         &dataset.fovs,
         &dataset.fov_names,
     );
     write_transcript_metadata(
+        &outputs,
         &args.output_transcript_metadata,
         args.output_transcript_metadata_fmt,
         &dataset.transcripts,
@@ -821,30 +1697,184 @@ fn main() {
         &dataset.transcript_names,
         &cell_assignments,
         &params.transcript_state,
+        &duplicate_flag,
+        &params.true_positive_probabilities(&dataset.transcripts, &cell_assignments),
+        args.tp_threshold,
         &dataset.fovs,
         &dataset.fov_names,
+        &hex_binner,
     );
     write_gene_metadata(
+        &outputs,
         &args.output_gene_metadata,
         args.output_gene_metadata_fmt,
         &params,
         &dataset.transcript_names,
         &ecounts,
+        args.housekeeping_cv_threshold,
+        args.marker_cv_threshold,
+    );
+    write_gene_assignment_distribution(
+        &outputs,
+        &args.output_gene_assignment_distribution,
+        args.output_gene_assignment_distribution_fmt,
+        &dataset.transcripts,
+        &cell_assignments,
+        &dataset.transcript_names,
+    );
+    write_background_spatial_stats(
+        &outputs,
+        &args.output_background_spatial_stats,
+        args.output_background_spatial_stats_fmt,
+        &hex_binner,
+        &dataset.transcripts,
+        &params.transcript_state,
+        &params.transcript_positions,
+        &dataset.transcript_names,
+        &params,
     );
+    if args.output_cell_displacements.is_some() {
+        let reference_centroids = read_cell_centroids_csv(
+            args.displacement_reference
+                .as_ref()
+                .expect("--output-cell-displacements requires --displacement-reference"),
+        );
+        write_cell_displacement_vectors(
+            &outputs,
+            &args.output_cell_displacements,
+            args.output_cell_displacements_fmt,
+            &reference_centroids,
+            &cell_centroids,
+            args.displacement_max_distance,
+        );
+    }
     write_voxels(
+        &outputs,
         &args.output_cell_voxels,
         args.output_cell_voxels_fmt,
         &sampler.borrow(),
+        &params,
+    );
+    write_voxel_cell_incidence(
+        &outputs,
+        &args.output_voxel_cell_incidence,
+        args.output_voxel_cell_incidence_fmt,
+        &sampler.borrow(),
+    );
+    write_voxel_features(
+        &outputs,
+        &args.output_voxel_features,
+        args.output_voxel_features_fmt,
+        &sampler.borrow(),
+        &dataset.transcript_names,
+    );
+    write_cell_adjacency(
+        &outputs,
+        &args.output_cell_adjacency,
+        args.output_cell_adjacency_fmt,
+        &sampler.borrow(),
+    );
+    let cell_adjacency = args.output_cell_type_contact_enrichment.is_some().then(|| {
+        sampler
+            .borrow()
+            .cell_shared_boundaries()
+            .0
+            .into_keys()
+            .collect::<Vec<_>>()
+    });
+    write_cell_type_contact_enrichment(
+        &outputs,
+        &args.output_cell_type_contact_enrichment,
+        args.output_cell_type_contact_enrichment_fmt,
+        cell_adjacency.as_deref().unwrap_or(&[]),
+        params.z.as_slice().unwrap(),
+        params.ncomponents(),
+    );
+    write_cell_boundary_purity(
+        &outputs,
+        &args.output_cell_boundary_purity,
+        args.output_cell_boundary_purity_fmt,
+        &sampler.borrow(),
+        &cell_assignments,
     );
 
-    if args.output_cell_polygon_layers.is_some() || args.output_cell_polygons.is_some() {
-        let (cell_polygons, cell_flattened_polygons) = sampler.borrow().cell_polygons();
-        write_cell_multipolygons(&args.output_cell_polygons, cell_flattened_polygons);
-        write_cell_layered_multipolygons(&args.output_cell_polygon_layers, cell_polygons);
+    if let Some((cell_polygons, cell_flattened_polygons)) = cell_polygons {
+        write_cell_polygons_gml(
+            &outputs,
+            &args.output_cell_polygons_gml,
+            &cell_flattened_polygons,
+            &params,
+            &cell_centroids,
+            args.coordinate_precision,
+        );
+        write_z_projected_polygons(
+            &outputs,
+            &args.output_z_projected_polygons,
+            &cell_polygons,
+            !args.no_cell_polygon_bbox,
+            args.coordinate_precision,
+        );
+        write_cell_polygons_wkt_table(
+            &outputs,
+            &args.output_cell_polygons_wkt,
+            args.output_cell_polygons_wkt_fmt,
+            &cell_flattened_polygons,
+            &params,
+            &cell_assignments,
+            &dataset.fovs,
+            &dataset.fov_names,
+            args.wkt_coordinate_decimals,
+        );
+        write_cell_multipolygons(
+            &outputs,
+            &args.output_cell_polygons,
+            cell_flattened_polygons,
+            !args.no_cell_polygon_bbox,
+            args.coordinate_precision,
+        );
+        write_cell_layered_multipolygons(
+            &outputs,
+            &args.output_cell_polygon_layers,
+            cell_polygons,
+            !args.no_cell_polygon_bbox,
+            args.coordinate_precision,
+        );
+    }
+
+    if let Some(output_cell_hulls) = &args.output_cell_hulls {
+        params.write_cell_hulls(&outputs, &dataset.transcripts, &counts, output_cell_hulls);
     }
 
-    if let Some(output_cell_hulls) = args.output_cell_hulls {
-        params.write_cell_hulls(&dataset.transcripts, &counts, &output_cell_hulls);
+    let ncells = params.ncells();
+    let ntranscripts = dataset.transcripts.len();
+    let expected_row_counts: HashMap<String, usize> = [
+        (&args.output_maxpost_counts, ncells),
+        (&args.output_expected_counts, ncells),
+        (&args.output_rates, ncells),
+        (&args.output_per_cell_rates, ncells),
+        (&args.output_cell_metadata, ncells),
+        (&args.output_z_projection_metadata, ncells),
+        (&args.output_assignment_instability, ncells),
+        (&args.output_spatial_outliers, ncells),
+        (&args.output_transcript_metadata, ntranscripts),
+        (&args.output_gene_metadata, ngenes),
+        (&args.output_spatial_variability, ngenes),
+        (&args.output_gene_assignment_distribution, ngenes),
+    ]
+    .iter()
+    .filter_map(|(output, n)| output.as_ref().map(|output| (output.clone(), *n)))
+    .collect();
+    let written_outputs = outputs.written_outputs();
+    write_row_count_summary(
+        &outputs,
+        &args.output_row_counts,
+        args.output_row_counts_fmt,
+        &written_outputs,
+        &expected_row_counts,
+    );
+
+    if args.verify_outputs {
+        verify_outputs(&written_outputs);
     }
 }
 
@@ -859,6 +1889,10 @@ fn run_hexbin_sampler(
     local_steps_per_iter: usize,
     mut uncertainty: Option<&mut UncertaintyTracker>,
     total_steps: &mut usize,
+    cell_count_trace: &mut CellCountTrace,
+    assignment_tracker: &mut Option<TranscriptAssignmentTracker>,
+    centroid_tracker: &mut CentroidTracker,
+    outputs: &OutputBundle,
     monitor_cell_polygons: &Option<String>,
     monitor_cell_polygons_freq: usize,
     sample_cell_regions: bool,
@@ -888,6 +1922,11 @@ fn run_hexbin_sampler(
         // let t0 = std::time::Instant::now();
         sampler.sample_global_params(priors, params, transcripts, &mut uncertainty, burnin);
         // println!("Sample parameters: {:?}", t0.elapsed());
+        cell_count_trace.record(*total_steps, &sampler.cell_voxel_counts());
+        if let Some(assignment_tracker) = assignment_tracker.as_mut() {
+            assignment_tracker.record(*total_steps, params);
+        }
+        centroid_tracker.record(*total_steps, sampler);
 
         let nassigned = params.nassigned();
         let nforeground = params.nforeground();
@@ -918,7 +1957,13 @@ fn run_hexbin_sampler(
             if let Some(basename) = monitor_cell_polygons {
                 let filename = format!("{}-{:04}.geojson.gz", basename, *total_steps);
                 let (cell_polygons, _cell_flattened_polygons) = sampler.cell_polygons();
-                write_cell_layered_multipolygons(&Some(filename), cell_polygons);
+                write_cell_layered_multipolygons(
+                    outputs,
+                    &Some(filename),
+                    cell_polygons,
+                    true,
+                    None,
+                );
             }
         }
 
@@ -1,19 +1,40 @@
+pub mod sink;
+
 use arrow2::array;
 use arrow2::chunk::Chunk;
 use arrow2::datatypes::{DataType, Field, Schema};
 use clap::ValueEnum;
 use flate2::write::GzEncoder;
 use flate2::Compression;
-use geo::MultiPolygon;
+use geo::{
+    Area, BooleanOps, BoundingRect, ClosestPoint, Contains, ConvexHull, EuclideanDistance,
+    EuclideanLength, MultiPoint, MultiPolygon, Point,
+};
 use ndarray::{Array1, Array2, Axis, Zip};
-use std::fs::File;
+use rayon::prelude::*;
+use std::collections::{BTreeMap, HashMap};
 use std::io::Write;
-use std::sync::Arc;
-
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use super::analysis::hexbin::HexBinner;
+use super::analysis::matching::match_cells;
+use super::analysis::outliers::local_outlier_factor;
+use super::analysis::ripleys::ripleys_l_minus_r;
+use super::analysis::spatial::{
+    spatial_cross_correlation, spatial_entropy_grid, spatial_variability_decomposition,
+};
+use super::checksum::{verify_parquet_checksums, ChecksummedFileWriter};
+use super::cloud::{is_remote_url, CloudBackend, OutputWriter};
+use super::geometry::wkt::encode_multipolygon_wkt;
 use super::sampler::transcripts::Transcript;
 use super::sampler::transcripts::BACKGROUND_CELL;
 use super::sampler::voxelsampler::VoxelSampler;
-use super::sampler::{ModelParams, TranscriptState};
+use super::sampler::{
+    ModelParams, ModelPriors, ParamsSnapshot, TranscriptAssignmentTracker, TranscriptState,
+};
+pub use sink::SinkFormat;
+use sink::{TranscriptSink, VoxelSink};
 
 #[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, ValueEnum)]
 pub enum OutputFormat {
@@ -24,7 +45,337 @@ pub enum OutputFormat {
     Parquet,
 }
 
+// Coordinate axis along which cells are ordered in spatial profiles.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, ValueEnum)]
+pub enum SpatialAxis {
+    X,
+    Y,
+    Z,
+}
+
+// How cells are collapsed to a single 2D position in z-projected output.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, ValueEnum)]
+pub enum ZProjection {
+    /// Centroid of the layer where the cell occupies the most volume
+    MaxIntensity,
+    /// The 3D centroid projected onto the xy plane
+    MeanPosition,
+    /// Centroid of the layer at the median z of the cell's transcripts
+    MedianZ,
+}
+
+// Insert a suffix into a filename before its extension, e.g. "rates.csv.gz"
+// becomes "rates_init.csv.gz".
+pub fn with_filename_suffix(filename: &str, suffix: &str) -> String {
+    for ext in [
+        ".csv.gz",
+        ".csv",
+        ".parquet",
+        ".geojson.gz",
+        ".geojson",
+        ".gml",
+    ] {
+        if let Some(stem) = filename.strip_suffix(ext) {
+            return format!("{}{}{}", stem, suffix, ext);
+        }
+    }
+    format!("{}{}", filename, suffix)
+}
+
+// How much statistics and integrity information is written to parquet files.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, ValueEnum)]
+pub enum WriteStatisticsLevel {
+    /// No statistics
+    None,
+    /// Column chunk and page statistics
+    ColumnLevel,
+    /// Statistics along with a CRC32 checksum of every page
+    PageLevel,
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct ParquetWriteOptions {
+    pub write_statistics: WriteStatisticsLevel,
+}
+
+impl ParquetWriteOptions {
+    fn arrow2_options(&self) -> arrow2::io::parquet::write::WriteOptions {
+        arrow2::io::parquet::write::WriteOptions {
+            write_statistics: self.write_statistics != WriteStatisticsLevel::None,
+            version: arrow2::io::parquet::write::Version::V2,
+            compression: arrow2::io::parquet::write::CompressionOptions::Zstd(Some(
+                arrow2::io::parquet::write::ZstdLevel::default(),
+            )),
+            data_pagesize_limit: None,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum OutputError {
+    Io(std::io::Error),
+    Thrift(parquet_format_safe::thrift::Error),
+    Arrow(arrow2::error::Error),
+    Malformed(String),
+    MissingChecksums(String),
+    UnsupportedSchemaVersion(String),
+}
+
+impl std::fmt::Display for OutputError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            OutputError::Io(err) => write!(f, "{}", err),
+            OutputError::Thrift(err) => write!(f, "{}", err),
+            OutputError::Arrow(err) => write!(f, "{}", err),
+            OutputError::Malformed(msg) => write!(f, "Malformed parquet file: {}", msg),
+            OutputError::MissingChecksums(path) => write!(f, "No page checksums in {}", path),
+            OutputError::UnsupportedSchemaVersion(msg) => {
+                write!(f, "Unsupported schema version: {}", msg)
+            }
+        }
+    }
+}
+
+impl From<std::io::Error> for OutputError {
+    fn from(err: std::io::Error) -> Self {
+        OutputError::Io(err)
+    }
+}
+
+impl From<parquet_format_safe::thrift::Error> for OutputError {
+    fn from(err: parquet_format_safe::thrift::Error) -> Self {
+        OutputError::Thrift(err)
+    }
+}
+
+impl From<arrow2::error::Error> for OutputError {
+    fn from(err: arrow2::error::Error) -> Self {
+        OutputError::Arrow(err)
+    }
+}
+
+// Tables record the version of their schema in their metadata (which is only
+// kept in parquet output). Tables without their own version use
+// SCHEMA_VERSION. Bump a table's version when its columns are added, removed,
+// or change type, and describe the change in SCHEMA_CHANGELOG.
+const SCHEMA_VERSION_KEY: &str = "proseg_schema_version";
+const SCHEMA_VERSION: &str = "1";
+const CELL_METADATA_SCHEMA_VERSION: &str = "3";
+const GENE_METADATA_SCHEMA_VERSION: &str = "2";
+const TRANSCRIPT_METADATA_SCHEMA_VERSION: &str = "2";
+
+// (table, version, description of changes from the previous version)
+const SCHEMA_CHANGELOG: &[(&str, &str, &str)] = &[
+    (
+        "all tables",
+        "1",
+        "Schema version recorded in parquet metadata as `proseg_schema_version`.",
+    ),
+    (
+        "cell metadata",
+        "2",
+        "Added `shape_index`, `shape_index_z_score`, `n_voxels`, and \
+         `mean_neighbor_expression_similarity`, and the optional columns \
+         `assignment_stable`, `spatial_outlier_score`, `fov_edge_distance_um`, and \
+         `is_fov_boundary_cell`.",
+    ),
+    (
+        "cell metadata",
+        "3",
+        "Added `n_genes_detected_1`, `n_genes_detected_5`, and `n_genes_detected_10`.",
+    ),
+    (
+        "gene metadata",
+        "2",
+        "Added `inter_component_cv`, `inter_component_range`, `is_housekeeping`, and \
+         `is_marker`.",
+    ),
+    (
+        "transcript metadata",
+        "2",
+        "Added `is_duplicate` (UInt8) and `true_positive_probability` (Float32).",
+    ),
+];
+
+fn with_schema_version(schema: Schema, version: &str) -> Schema {
+    let mut metadata = schema.metadata.clone();
+    metadata.insert(SCHEMA_VERSION_KEY.to_string(), version.to_string());
+    schema.with_metadata(metadata)
+}
+
+// Write a markdown document listing changes to output schemas by version.
+pub fn write_schema_changelog(outputs: &OutputBundle, output_path: &str) {
+    let mut output = outputs.open(output_path);
+    let write = || -> std::io::Result<()> {
+        writeln!(output, "# proseg output schema changelog")?;
+        for (table, version, changes) in SCHEMA_CHANGELOG {
+            writeln!(output, "\n## {} v{}\n\n{}", table, version, changes)?;
+        }
+        output.finish()
+    };
+    write().unwrap_or_else(|err| panic!("Error writing {}: {}", output_path, err));
+}
+
+// Upgrade version 1 transcript metadata in parquet format to version 2 by
+// adding the columns introduced in version 2, filled with NaN, or nulls for
+// integer columns.
+pub fn migrate_transcript_metadata_v1_to_v2(
+    outputs: &OutputBundle,
+    old_path: &Path,
+    new_path: &Path,
+) -> Result<(), OutputError> {
+    let mut input = std::fs::File::open(old_path)?;
+    let metadata = arrow2::io::parquet::read::read_metadata(&mut input)?;
+    let schema = arrow2::io::parquet::read::infer_schema(&metadata)?;
+    if let Some(version) = schema.metadata.get(SCHEMA_VERSION_KEY) {
+        if version != "1" {
+            return Err(OutputError::UnsupportedSchemaVersion(format!(
+                "{} has schema version {}, expected 1",
+                old_path.display(),
+                version
+            )));
+        }
+    }
+
+    let chunks = arrow2::io::parquet::read::FileReader::new(
+        input,
+        metadata.row_groups,
+        schema.clone(),
+        None,
+        None,
+        None,
+    )
+    .collect::<arrow2::error::Result<Vec<_>>>()?;
+    let nrows = chunks.iter().map(|chunk| chunk.len()).sum::<usize>();
+
+    let mut fields = schema.fields.clone();
+    let mut columns: Vec<Arc<dyn arrow2::array::Array>> = (0..fields.len())
+        .map(|i| {
+            let arrays = chunks
+                .iter()
+                .map(|chunk| chunk.arrays()[i].as_ref())
+                .collect::<Vec<_>>();
+            arrow2::compute::concatenate::concatenate(&arrays).map(Arc::from)
+        })
+        .collect::<arrow2::error::Result<_>>()?;
+
+    let has_field = |name: &str| schema.fields.iter().any(|field| field.name == name);
+    if !has_field("is_duplicate") {
+        fields.push(Field::new("is_duplicate", DataType::UInt8, true));
+        columns.push(Arc::new(array::UInt8Array::new_null(
+            DataType::UInt8,
+            nrows,
+        )));
+    }
+    if !has_field("true_positive_probability") {
+        fields.push(Field::new(
+            "true_positive_probability",
+            DataType::Float32,
+            false,
+        ));
+        columns.push(Arc::new(array::Float32Array::from_vec(vec![
+            f32::NAN;
+            nrows
+        ])));
+    }
+
+    let schema = with_schema_version(Schema::from(fields), TRANSCRIPT_METADATA_SCHEMA_VERSION);
+    let mut output = std::fs::File::create(new_path)?;
+    write_table_parquet(
+        &mut output,
+        schema,
+        Chunk::new(columns),
+        outputs.parquet_options,
+    )?;
+    Ok(())
+}
+
+// Where and how output is written. Passed to every writer.
+pub struct OutputBundle {
+    parquet_options: ParquetWriteOptions,
+    cloud_backend: Option<Arc<dyn CloudBackend>>,
+
+    // Every output opened, in the order they were opened.
+    written: Mutex<Vec<WrittenOutput>>,
+}
+
+impl OutputBundle {
+    pub fn new(
+        parquet_options: ParquetWriteOptions,
+        cloud_backend: Option<Arc<dyn CloudBackend>>,
+    ) -> Self {
+        OutputBundle {
+            parquet_options,
+            cloud_backend,
+            written: Mutex::new(Vec::new()),
+        }
+    }
+
+    // Open an output file, or an object if `filename` is an object storage
+    // URL. The writer must be finished once everything is written.
+    pub fn open(&self, filename: &str) -> Box<dyn OutputWriter> {
+        {
+            // An output written more than once is only recorded once.
+            let mut written = self.written.lock().unwrap();
+            written.retain(|output| output.filename != filename);
+            written.push(WrittenOutput {
+                filename: filename.to_string(),
+                parquet: false,
+                nrows: None,
+            });
+        }
+
+        if !is_remote_url(filename) {
+            return Box::new(
+                std::fs::File::create(filename)
+                    .unwrap_or_else(|err| panic!("Unable to create {}: {}", filename, err)),
+            );
+        }
+        match &self.cloud_backend {
+            Some(backend) => backend.open_write(filename),
+            None => panic!("No object storage backend configured for {}", filename),
+        }
+    }
+
+    // Create an output directory. Object storage has no directories, so
+    // nothing is created for remote paths.
+    pub fn create_dir(&self, path: &str) {
+        if !is_remote_url(path) {
+            std::fs::create_dir_all(path)
+                .unwrap_or_else(|err| panic!("Unable to create {}: {}", path, err));
+        }
+    }
+
+    // Record that the opened output `filename` is a table with `nrows` rows.
+    fn record_table(&self, filename: &str, parquet: bool, nrows: usize) {
+        let mut written = self.written.lock().unwrap();
+        if let Some(output) = written
+            .iter_mut()
+            .rev()
+            .find(|output| output.filename == filename)
+        {
+            output.parquet = parquet;
+            output.nrows = Some(nrows);
+        }
+    }
+
+    // Every output written so far, in the order they were opened.
+    pub fn written_outputs(&self) -> Vec<WrittenOutput> {
+        self.written.lock().unwrap().clone()
+    }
+}
+
+// An output that was written. Tables also record whether they're parquet and
+// their number of rows.
+#[derive(Clone)]
+pub struct WrittenOutput {
+    filename: String,
+    parquet: bool,
+    nrows: Option<usize>,
+}
+
 pub fn write_table(
+    outputs: &OutputBundle,
     filename: &str,
     fmt: OutputFormat,
     schema: Schema,
@@ -35,22 +386,34 @@ pub fn write_table(
         _ => fmt,
     };
 
-    let mut file = File::create(filename).unwrap();
+    let nrows = chunk.len();
+    let schema = if schema.metadata.contains_key(SCHEMA_VERSION_KEY) {
+        schema
+    } else {
+        with_schema_version(schema, SCHEMA_VERSION)
+    };
+
+    let mut file = outputs.open(filename);
+    outputs.record_table(filename, fmt == OutputFormat::Parquet, nrows);
 
     match fmt {
         OutputFormat::Csv => {
-            if write_table_csv(&mut file, schema, chunk).is_err() {
+            if write_table_csv(&mut file, schema, chunk).is_err() || file.finish().is_err() {
                 panic!("Error writing csv file: {}", filename);
             }
         }
         OutputFormat::CsvGz => {
             let mut encoder = GzEncoder::new(file, Compression::default());
-            if write_table_csv(&mut encoder, schema, chunk).is_err() {
+            if write_table_csv(&mut encoder, schema, chunk).is_err()
+                || Box::new(encoder).finish().is_err()
+            {
                 panic!("Error writing csv.gz file: {}", filename);
             }
         }
         OutputFormat::Parquet => {
-            if write_table_parquet(&mut file, schema, chunk).is_err() {
+            if write_table_parquet(&mut file, schema, chunk, outputs.parquet_options).is_err()
+                || file.finish().is_err()
+            {
                 panic!("Error writing parquet file: {}", filename);
             }
         }
@@ -60,6 +423,80 @@ pub fn write_table(
     }
 }
 
+// Write the number of rows in every table in `written_outputs`, alongside
+// the number expected, where known, to help catch truncated or malformed
+// output.
+pub fn write_row_count_summary(
+    outputs: &OutputBundle,
+    output_row_counts: &Option<String>,
+    output_row_counts_fmt: OutputFormat,
+    written_outputs: &[WrittenOutput],
+    expected_row_counts: &HashMap<String, usize>,
+) {
+    if let Some(output_row_counts) = output_row_counts {
+        let row_counts: Vec<(String, usize)> = written_outputs
+            .iter()
+            .filter_map(|output| output.nrows.map(|nrows| (output.filename.clone(), nrows)))
+            .collect();
+
+        let schema = Schema::from(vec![
+            Field::new("output_file", DataType::Utf8, false),
+            Field::new("n_rows", DataType::UInt64, false),
+            Field::new("expected_n_rows", DataType::UInt64, true),
+        ]);
+
+        let columns: Vec<Arc<dyn arrow2::array::Array>> = vec![
+            Arc::new(array::Utf8Array::<i32>::from_iter_values(
+                row_counts.iter().map(|(filename, _)| filename.clone()),
+            )),
+            Arc::new(array::UInt64Array::from_values(
+                row_counts.iter().map(|(_, n_rows)| *n_rows as u64),
+            )),
+            Arc::new(array::UInt64Array::from_iter(row_counts.iter().map(
+                |(filename, _)| expected_row_counts.get(filename).map(|&n| n as u64),
+            ))),
+        ];
+
+        let chunk = arrow2::chunk::Chunk::new(columns);
+        write_table(
+            outputs,
+            output_row_counts,
+            output_row_counts_fmt,
+            schema,
+            chunk,
+        );
+    }
+}
+
+// Verify that every output in `written_outputs` written to the local
+// filesystem exists, and check page checksums of parquet tables.
+pub fn verify_outputs(written_outputs: &[WrittenOutput]) {
+    for WrittenOutput {
+        filename, parquet, ..
+    } in written_outputs
+    {
+        if is_remote_url(filename) {
+            println!("Skipping verification of remote output: {}", filename);
+            continue;
+        }
+        if !Path::new(filename).exists() {
+            panic!("Missing output: {}", filename);
+        }
+        if !parquet {
+            continue;
+        }
+        match verify_parquet_checksums(Path::new(filename)) {
+            Ok(true) => println!("Verified checksums: {}", filename),
+            Ok(false) => panic!("Checksum mismatch: {}", filename),
+            Err(OutputError::MissingChecksums(_)) => println!(
+                "No checksums to verify in {}. Use `--parquet-write-statistics page-level` to add them.",
+                filename
+            ),
+            Err(err) => panic!("Unable to verify {}: {}", filename, err),
+        }
+    }
+}
+
 fn write_table_csv<W>(
     output: &mut W,
     schema: Schema,
@@ -79,24 +516,8 @@ where
     Ok(())
 }
 
-fn write_table_parquet<W>(
-    output: &mut W,
-    schema: Schema,
-    chunk: Chunk<Arc<dyn arrow2::array::Array>>,
-) -> arrow2::error::Result<()>
-where
-    W: std::io::Write,
-{
-    let options = arrow2::io::parquet::write::WriteOptions {
-        write_statistics: true,
-        version: arrow2::io::parquet::write::Version::V2,
-        compression: arrow2::io::parquet::write::CompressionOptions::Zstd(Some(
-            arrow2::io::parquet::write::ZstdLevel::default(),
-        )),
-        data_pagesize_limit: None,
-    };
-
-    let encodings = schema
+fn parquet_encodings(schema: &Schema) -> Vec<Vec<arrow2::io::parquet::write::Encoding>> {
+    schema
         .fields
         .iter()
         // .map(|f| arrow2::io::parquet::write::Encoding::Plain)
@@ -105,7 +526,20 @@ where
                 arrow2::io::parquet::write::Encoding::Plain
             })
         })
-        .collect();
+        .collect()
+}
+
+fn write_table_parquet<W>(
+    output: &mut W,
+    schema: Schema,
+    chunk: Chunk<Arc<dyn arrow2::array::Array>>,
+    parquet_options: ParquetWriteOptions,
+) -> Result<(), OutputError>
+where
+    W: std::io::Write,
+{
+    let options = parquet_options.arrow2_options();
+    let encodings = parquet_encodings(&schema);
 
     let chunk_iter = vec![Ok(chunk)];
     let row_groups = arrow2::io::parquet::write::RowGroupIterator::try_new(
@@ -115,14 +549,20 @@ where
         encodings,
     )?;
 
-    let mut writer = arrow2::io::parquet::write::FileWriter::try_new(output, schema, options)?;
-
-    for group in row_groups {
-        writer.write(group?)?;
+    if parquet_options.write_statistics == WriteStatisticsLevel::PageLevel {
+        let mut writer = ChecksummedFileWriter::try_new(output, schema, options)?;
+        for group in row_groups {
+            writer.write(group?)?;
+        }
+        writer.end()?;
+    } else {
+        let mut writer = arrow2::io::parquet::write::FileWriter::try_new(output, schema, options)?;
+        for group in row_groups {
+            writer.write(group?)?;
+        }
+        writer.end(None)?;
     }
 
-    writer.end(None)?;
-
     Ok(())
 }
 
@@ -139,6 +579,7 @@ pub fn infer_format_from_filename(filename: &str) -> OutputFormat {
 }
 
 pub fn write_counts(
+    outputs: &OutputBundle,
     output_counts: &Option<String>,
     output_counts_fmt: OutputFormat,
     transcript_names: &[String],
@@ -162,11 +603,111 @@ pub fn write_counts(
         }
         let chunk = arrow2::chunk::Chunk::new(columns);
 
-        write_table(output_counts, output_counts_fmt, schema, chunk);
+        write_table(outputs, output_counts, output_counts_fmt, schema, chunk);
+    }
+}
+
+// Write counts, cell locations, and optionally per-component expression
+// profiles in the layout expected by CARD, with cells labeled by `cell_{i}`
+// barcodes. Cells with no transcripts are left out, since CARD can't use them
+// and cells without voxels have no meaningful location.
+pub fn write_card_input(
+    outputs: &OutputBundle,
+    output_card_dir: &Option<String>,
+    counts: &Array2<u32>,
+    cell_centroids: &[(f32, f32, f32)],
+    transcript_names: &[String],
+    reference: Option<&Array2<f32>>,
+) {
+    if let Some(output_card_dir) = output_card_dir {
+        outputs.create_dir(output_card_dir);
+        let path = |filename: &str| {
+            std::path::Path::new(output_card_dir)
+                .join(filename)
+                .to_str()
+                .unwrap()
+                .to_string()
+        };
+
+        let cells = (0..counts.ncols())
+            .filter(|&i| counts.column(i).sum() > 0)
+            .collect::<Vec<_>>();
+        let barcodes = cells
+            .iter()
+            .map(|i| format!("cell_{}", i))
+            .collect::<Vec<_>>();
+
+        // counts.csv: cells × genes
+        let mut schema_fields = vec![Field::new("", DataType::Utf8, false)];
+        let mut columns: Vec<Arc<dyn arrow2::array::Array>> = vec![Arc::new(
+            array::Utf8Array::<i32>::from_iter_values(barcodes.iter()),
+        )];
+        for (name, row) in transcript_names.iter().zip(counts.rows()) {
+            schema_fields.push(Field::new(name, DataType::UInt32, false));
+            columns.push(Arc::new(array::UInt32Array::from_values(
+                cells.iter().map(|&i| row[i]),
+            )));
+        }
+        write_table(
+            outputs,
+            &path("counts.csv"),
+            OutputFormat::Csv,
+            Schema::from(schema_fields),
+            arrow2::chunk::Chunk::new(columns),
+        );
+
+        // location.csv: x, y per cell
+        let schema = Schema::from(vec![
+            Field::new("", DataType::Utf8, false),
+            Field::new("x", DataType::Float32, false),
+            Field::new("y", DataType::Float32, false),
+        ]);
+        let columns: Vec<Arc<dyn arrow2::array::Array>> = vec![
+            Arc::new(array::Utf8Array::<i32>::from_iter_values(barcodes.iter())),
+            Arc::new(array::Float32Array::from_values(
+                cells.iter().map(|&i| cell_centroids[i].0),
+            )),
+            Arc::new(array::Float32Array::from_values(
+                cells.iter().map(|&i| cell_centroids[i].1),
+            )),
+        ];
+        write_table(
+            outputs,
+            &path("location.csv"),
+            OutputFormat::Csv,
+            schema,
+            arrow2::chunk::Chunk::new(columns),
+        );
+
+        // reference.csv: genes × components
+        if let Some(reference) = reference {
+            let mut schema_fields = vec![Field::new("", DataType::Utf8, false)];
+            let mut columns: Vec<Arc<dyn arrow2::array::Array>> = vec![Arc::new(
+                array::Utf8Array::<i32>::from_iter_values(transcript_names.iter()),
+            )];
+            for (i, row) in reference.rows().into_iter().enumerate() {
+                schema_fields.push(Field::new(
+                    format!("component_{}", i),
+                    DataType::Float32,
+                    false,
+                ));
+                columns.push(Arc::new(array::Float32Array::from_values(
+                    row.iter().cloned(),
+                )));
+            }
+            write_table(
+                outputs,
+                &path("reference.csv"),
+                OutputFormat::Csv,
+                Schema::from(schema_fields),
+                arrow2::chunk::Chunk::new(columns),
+            );
+        }
     }
 }
 
 pub fn write_expected_counts(
+    outputs: &OutputBundle,
     output_expected_counts: &Option<String>,
     output_expected_counts_fmt: OutputFormat,
     transcript_names: &[String],
@@ -191,6 +732,7 @@ pub fn write_expected_counts(
         let chunk = arrow2::chunk::Chunk::new(columns);
 
         write_table(
+            outputs,
             output_expected_counts,
             output_expected_counts_fmt,
             schema,
@@ -200,6 +742,7 @@ pub fn write_expected_counts(
 }
 
 pub fn write_rates(
+    outputs: &OutputBundle,
     output_rates: &Option<String>,
     output_rates_fmt: OutputFormat,
     params: &ModelParams,
@@ -223,395 +766,2759 @@ pub fn write_rates(
         }
         let chunk = arrow2::chunk::Chunk::new(columns);
 
-        write_table(output_rates, output_rates_fmt, schema, chunk);
+        write_table(outputs, output_rates, output_rates_fmt, schema, chunk);
     }
 }
 
-pub fn write_component_params(
-    output_component_params: &Option<String>,
-    output_component_params_fmt: OutputFormat,
+// Per-cell rates integrated over cell volume, giving the expected number of
+// foreground transcripts of each gene in each cell. [ngenes, ncells]
+fn volume_scaled_rates(λ: &Array2<f32>, cell_volume: &Array1<f32>) -> Array2<f32> {
+    let mut rates = λ.clone();
+    Zip::from(rates.columns_mut())
+        .and(cell_volume)
+        .for_each(|mut λ_c, &v| λ_c *= v);
+    rates
+}
+
+pub fn write_per_cell_rates(
+    outputs: &OutputBundle,
+    output_per_cell_rates: &Option<String>,
+    output_per_cell_rates_fmt: OutputFormat,
     params: &ModelParams,
     transcript_names: &[String],
 ) {
-    if let Some(output_component_params) = output_component_params {
-        // What does this look like: rows for each gene, columns for α1, β1, α2, β2, etc.
-        let α = &params.r;
-        let φ = &params.φ;
-        let β = φ.map(|φ| (-φ).exp());
+    if let Some(output_per_cell_rates) = output_per_cell_rates {
+        let rates = volume_scaled_rates(&params.λ, &params.cell_volume);
 
-        let ncomponents = params.ncomponents();
-
-        let mut fields = Vec::new();
-        fields.push(Field::new("gene", DataType::Utf8, false));
-        for i in 0..ncomponents {
-            fields.push(Field::new(&format!("α_{}", i), DataType::Float32, false));
-            fields.push(Field::new(&format!("β_{}", i), DataType::Float32, false));
-        }
-        let schema = Schema::from(fields);
+        let schema = Schema::from(
+            transcript_names
+                .iter()
+                .map(|name| Field::new(name, DataType::Float32, false))
+                .collect::<Vec<_>>(),
+        );
 
         let mut columns: Vec<Arc<dyn arrow2::array::Array>> = Vec::new();
-        columns.push(Arc::new(array::Utf8Array::<i32>::from_iter_values(
-            transcript_names.iter().cloned(),
-        )));
-        Zip::from(α.rows()).and(β.rows()).for_each(|α, β| {
-            columns.push(Arc::new(array::Float32Array::from_values(
-                α.iter().cloned(),
-            )));
+        for row in rates.rows() {
             columns.push(Arc::new(array::Float32Array::from_values(
-                β.iter().cloned(),
+                row.iter().cloned(),
             )));
-        });
-
+        }
         let chunk = arrow2::chunk::Chunk::new(columns);
+
         write_table(
-            output_component_params,
-            output_component_params_fmt,
+            outputs,
+            output_per_cell_rates,
+            output_per_cell_rates_fmt,
             schema,
             chunk,
         );
     }
 }
 
-// Assign cells to fovs by finding the most common transcript fov of the
-// assigned transcripts.
-fn cell_fov_vote(
-    ncells: usize,
-    nfovs: usize,
-    cell_assignments: &[(u32, f32)],
-    fovs: &[u32],
-) -> Vec<u32> {
-    let mut fov_votes = Array2::<u32>::zeros((ncells, nfovs));
-    for (fov, (cell, _)) in fovs.iter().zip(cell_assignments) {
-        if *cell != BACKGROUND_CELL {
-            fov_votes[[*cell as usize, *fov as usize]] += 1;
+pub fn write_rate_variance(
+    outputs: &OutputBundle,
+    output_rate_variance: &Option<String>,
+    output_rate_variance_fmt: OutputFormat,
+    params: &ModelParams,
+    transcript_names: &[String],
+) {
+    if let Some(output_rate_variance) = output_rate_variance {
+        let λ_variance = params
+            .λ_variance
+            .as_ref()
+            .expect("No recorded samples from which to compute rate variance");
+
+        let schema = arrow2::datatypes::Schema::from(
+            transcript_names
+                .iter()
+                .map(|name| {
+                    arrow2::datatypes::Field::new(name, arrow2::datatypes::DataType::Float32, false)
+                })
+                .collect::<Vec<_>>(),
+        );
+
+        let mut columns: Vec<Arc<dyn arrow2::array::Array>> = Vec::new();
+        for row in λ_variance.rows() {
+            columns.push(Arc::new(arrow2::array::Float32Array::from_values(
+                row.iter().cloned(),
+            )));
         }
-    }
+        let chunk = arrow2::chunk::Chunk::new(columns);
 
-    fov_votes
-        .outer_iter()
-        .map(|votes| {
-            let mut winner = u32::MAX;
-            let mut winner_count: u32 = 0;
-            for (fov, count) in votes.iter().enumerate() {
-                if *count > winner_count {
-                    winner_count = *count;
-                    winner = fov as u32;
-                }
-            }
-            winner
-        })
-        .collect::<Vec<u32>>()
+        write_table(
+            outputs,
+            output_rate_variance,
+            output_rate_variance_fmt,
+            schema,
+            chunk,
+        );
+    }
 }
 
-pub fn write_cell_metadata(
-    output_cell_metadata: &Option<String>,
-    output_cell_metadata_fmt: OutputFormat,
-    params: &ModelParams,
+pub fn write_spatial_variability_decomposition(
+    outputs: &OutputBundle,
+    output_spatial_variability: &Option<String>,
+    output_spatial_variability_fmt: OutputFormat,
+    expected_counts: &Array2<f32>,
     cell_centroids: &[(f32, f32, f32)],
-    cell_assignments: &[(u32, f32)],
-    fovs: &[u32],
-    fov_names: &[String],
+    transcript_names: &[String],
 ) {
-    let ncells = cell_centroids.len();
-    let nfovs = fov_names.len();
-    let cell_fovs = cell_fov_vote(ncells, nfovs, cell_assignments, fovs);
+    if let Some(output_spatial_variability) = output_spatial_variability {
+        let decomposition = spatial_variability_decomposition(expected_counts, cell_centroids);
 
-    if let Some(output_cell_metadata) = output_cell_metadata {
         let schema = Schema::from(vec![
-            Field::new("cell", DataType::UInt32, false),
-            Field::new("centroid_x", DataType::Float32, false),
-            Field::new("centroid_y", DataType::Float32, false),
-            Field::new("centroid_z", DataType::Float32, false),
-            Field::new("fov", DataType::Utf8, true),
-            Field::new("cluster", DataType::UInt16, false),
-            Field::new("volume", DataType::Float32, false),
-            Field::new("population", DataType::UInt64, false),
+            Field::new("gene", DataType::Utf8, false),
+            Field::new("total_cv2", DataType::Float32, false),
+            Field::new("spatial_cv2", DataType::Float32, false),
+            Field::new("residual_cv2", DataType::Float32, false),
+            Field::new("spatial_fraction", DataType::Float32, false),
         ]);
 
         let columns: Vec<Arc<dyn arrow2::array::Array>> = vec![
-            Arc::new(array::UInt32Array::from_values(0..params.ncells() as u32)),
+            Arc::new(array::Utf8Array::<i32>::from_iter_values(
+                transcript_names.iter().cloned(),
+            )),
             Arc::new(array::Float32Array::from_values(
-                cell_centroids.iter().map(|(x, _, _)| *x),
+                decomposition.iter().map(|d| d.total_cv2),
             )),
             Arc::new(array::Float32Array::from_values(
-                cell_centroids.iter().map(|(_, y, _)| *y),
+                decomposition.iter().map(|d| d.spatial_cv2),
             )),
             Arc::new(array::Float32Array::from_values(
-                cell_centroids.iter().map(|(_, _, z)| *z),
+                decomposition.iter().map(|d| d.residual_cv2),
             )),
-            Arc::new(array::Utf8Array::<i32>::from_iter(cell_fovs.iter().map(
-                |fov| {
-                    if *fov == u32::MAX {
-                        None
-                    } else {
-                        Some(fov_names[*fov as usize].clone())
-                    }
-                },
-            ))),
-            Arc::new(array::UInt16Array::from_values(
-                params.z.iter().map(|&z| z as u16),
+            Arc::new(array::Float32Array::from_values(
+                decomposition.iter().map(|d| d.spatial_fraction),
+            )),
+        ];
+
+        let chunk = arrow2::chunk::Chunk::new(columns);
+        write_table(
+            outputs,
+            output_spatial_variability,
+            output_spatial_variability_fmt,
+            schema,
+            chunk,
+        );
+    }
+}
+
+pub fn write_spatial_cross_correlation(
+    outputs: &OutputBundle,
+    output_spatial_cross_correlation: &Option<String>,
+    output_spatial_cross_correlation_fmt: OutputFormat,
+    cell_centroids: &[(f32, f32, f32)],
+    expected_counts: &Array2<f32>,
+    transcript_names: &[String],
+    gene_pairs: &[(usize, usize)],
+) {
+    if let Some(output_spatial_cross_correlation) = output_spatial_cross_correlation {
+        let cross_correlation =
+            spatial_cross_correlation(expected_counts, cell_centroids, gene_pairs);
+
+        let schema = Schema::from(vec![
+            Field::new("gene_a", DataType::Utf8, false),
+            Field::new("gene_b", DataType::Utf8, false),
+            Field::new("lee_l", DataType::Float32, false),
+            Field::new("p_value", DataType::Float32, false),
+        ]);
+
+        let columns: Vec<Arc<dyn arrow2::array::Array>> = vec![
+            Arc::new(array::Utf8Array::<i32>::from_iter_values(
+                gene_pairs.iter().map(|&(a, _)| transcript_names[a].clone()),
+            )),
+            Arc::new(array::Utf8Array::<i32>::from_iter_values(
+                gene_pairs.iter().map(|&(_, b)| transcript_names[b].clone()),
             )),
             Arc::new(array::Float32Array::from_values(
-                params.cell_volume.iter().cloned(),
+                cross_correlation.iter().map(|c| c.lee_l),
             )),
-            Arc::new(array::UInt64Array::from_values(
-                params.cell_population.iter().map(|&p| p as u64),
+            Arc::new(array::Float32Array::from_values(
+                cross_correlation.iter().map(|c| c.p_value),
             )),
         ];
 
         let chunk = arrow2::chunk::Chunk::new(columns);
+        write_table(
+            outputs,
+            output_spatial_cross_correlation,
+            output_spatial_cross_correlation_fmt,
+            schema,
+            chunk,
+        );
+    }
+}
 
+// Bin cells along a coordinate axis and write the fraction of cells in each bin
+// assigned to each component. With a smoothing bandwidth (in bins), fractions
+// are computed from Gaussian kernel weighted counts of neighboring bins.
+#[allow(clippy::too_many_arguments)]
+pub fn write_spatial_composition_profile(
+    outputs: &OutputBundle,
+    output_spatial_composition: &Option<String>,
+    output_spatial_composition_fmt: OutputFormat,
+    cell_centroids: &[(f32, f32, f32)],
+    cluster_assignments: &Array1<u32>,
+    ncomponents: usize,
+    axis: SpatialAxis,
+    n_bins: usize,
+    smoothing_bandwidth: Option<f32>,
+) {
+    if let Some(output_spatial_composition) = output_spatial_composition {
+        let positions: Vec<f32> = cell_centroids
+            .iter()
+            .map(|&(x, y, z)| match axis {
+                SpatialAxis::X => x,
+                SpatialAxis::Y => y,
+                SpatialAxis::Z => z,
+            })
+            .collect();
+
+        let (schema, chunk) = composition_profile_table(
+            &positions,
+            cluster_assignments,
+            ncomponents,
+            n_bins,
+            smoothing_bandwidth,
+        );
         write_table(
-            output_cell_metadata,
-            output_cell_metadata_fmt,
+            outputs,
+            output_spatial_composition,
+            output_spatial_composition_fmt,
             schema,
             chunk,
         );
     }
 }
 
+// Fraction of cells of each cluster in evenly spaced bins of `positions`,
+// optionally smoothed across neighboring bins.
+fn composition_profile_table(
+    positions: &[f32],
+    cluster_assignments: &Array1<u32>,
+    ncomponents: usize,
+    n_bins: usize,
+    smoothing_bandwidth: Option<f32>,
+) -> (Schema, arrow2::chunk::Chunk<Arc<dyn arrow2::array::Array>>) {
+    let n_bins = n_bins.max(1);
+    let (min_pos, max_pos) = positions
+        .iter()
+        .fold((f32::INFINITY, f32::NEG_INFINITY), |(lo, hi), &p| {
+            (lo.min(p), hi.max(p))
+        });
+    let bin_width = if max_pos > min_pos {
+        (max_pos - min_pos) / n_bins as f32
+    } else {
+        1.0
+    };
+
+    let mut bin_counts = vec![0u32; n_bins];
+    let mut component_counts = Array2::<f32>::zeros((n_bins, ncomponents));
+    for (&pos, &z) in positions.iter().zip(cluster_assignments) {
+        let bin = (((pos - min_pos) / bin_width) as usize).min(n_bins - 1);
+        bin_counts[bin] += 1;
+        component_counts[[bin, z as usize]] += 1.0;
+    }
+
+    if let Some(bandwidth) = smoothing_bandwidth {
+        let unsmoothed = component_counts.clone();
+        for (i, mut counts_i) in component_counts.outer_iter_mut().enumerate() {
+            counts_i.fill(0.0);
+            for (j, counts_j) in unsmoothed.outer_iter().enumerate() {
+                let d = (i as f32 - j as f32) / bandwidth;
+                counts_i.scaled_add((-0.5 * d * d).exp(), &counts_j);
+            }
+        }
+    }
+
+    let mut fields = vec![
+        Field::new("bin_center", DataType::Float32, false),
+        Field::new("bin_count", DataType::UInt32, false),
+    ];
+    let mut columns: Vec<Arc<dyn arrow2::array::Array>> = vec![
+        Arc::new(array::Float32Array::from_values(
+            (0..n_bins).map(|i| min_pos + (i as f32 + 0.5) * bin_width),
+        )),
+        Arc::new(array::UInt32Array::from_vec(bin_counts)),
+    ];
+
+    let bin_totals = component_counts.sum_axis(Axis(1));
+    for k in 0..ncomponents {
+        fields.push(Field::new(
+            format!("type_{}_fraction", k),
+            DataType::Float32,
+            false,
+        ));
+        columns.push(Arc::new(array::Float32Array::from_values(
+            component_counts
+                .column(k)
+                .iter()
+                .zip(bin_totals.iter())
+                .map(|(&count, &total)| if total > 0.0 { count / total } else { 0.0 }),
+        )));
+    }
+
+    (Schema::from(fields), arrow2::chunk::Chunk::new(columns))
+}
+
+// Order cells along a spatial gradient by projecting their centroids onto
+// `axis_direction`, scaled so the pseudotime runs from 0 to 1. The cluster
+// composition along the pseudotime axis is written as a separate table.
 #[allow(clippy::too_many_arguments)]
-pub fn write_transcript_metadata(
-    output_transcript_metadata: &Option<String>,
-    output_transcript_metadata_fmt: OutputFormat,
-    transcripts: &[Transcript],
-    transcript_positions: &[(f32, f32, f32)],
-    transcript_names: &[String],
+pub fn write_spatial_pseudotime(
+    outputs: &OutputBundle,
+    output_spatial_pseudotime: &Option<String>,
+    output_spatial_pseudotime_fmt: OutputFormat,
+    output_pseudotime_bin_composition: &Option<String>,
+    output_pseudotime_bin_composition_fmt: OutputFormat,
+    cell_centroids: &[(f32, f32, f32)],
+    axis_direction: (f32, f32, f32),
+    cluster_assignments: &Array1<u32>,
+    ncomponents: usize,
+    n_bins: usize,
+    smoothing_bandwidth: Option<f32>,
+) {
+    let (ax, ay, az) = axis_direction;
+    let projections: Vec<f32> = cell_centroids
+        .iter()
+        .map(|&(x, y, z)| x * ax + y * ay + z * az)
+        .collect();
+    let (min_proj, max_proj) = projections
+        .iter()
+        .fold((f32::INFINITY, f32::NEG_INFINITY), |(lo, hi), &p| {
+            (lo.min(p), hi.max(p))
+        });
+    let pseudotime: Vec<f32> = projections
+        .iter()
+        .map(|&p| {
+            if max_proj > min_proj {
+                (p - min_proj) / (max_proj - min_proj)
+            } else {
+                0.0
+            }
+        })
+        .collect();
+
+    if let Some(output_spatial_pseudotime) = output_spatial_pseudotime {
+        let schema = Schema::from(vec![
+            Field::new("cell", DataType::UInt32, false),
+            Field::new("pseudotime", DataType::Float32, false),
+            Field::new("cluster", DataType::UInt16, false),
+        ]);
+        let columns: Vec<Arc<dyn arrow2::array::Array>> = vec![
+            Arc::new(array::UInt32Array::from_values(
+                0..cell_centroids.len() as u32,
+            )),
+            Arc::new(array::Float32Array::from_slice(&pseudotime)),
+            Arc::new(array::UInt16Array::from_values(
+                cluster_assignments.iter().map(|&z| z as u16),
+            )),
+        ];
+        let chunk = arrow2::chunk::Chunk::new(columns);
+        write_table(
+            outputs,
+            output_spatial_pseudotime,
+            output_spatial_pseudotime_fmt,
+            schema,
+            chunk,
+        );
+    }
+
+    if let Some(output_pseudotime_bin_composition) = output_pseudotime_bin_composition {
+        let (schema, chunk) = composition_profile_table(
+            &pseudotime,
+            cluster_assignments,
+            ncomponents,
+            n_bins,
+            smoothing_bandwidth,
+        );
+        write_table(
+            outputs,
+            output_pseudotime_bin_composition,
+            output_pseudotime_bin_composition_fmt,
+            schema,
+            chunk,
+        );
+    }
+}
+
+// Classify cells into zones by distance from the tissue boundary. Cells closer
+// than the first radius are zone 0 (periphery), and each subsequent radius
+// starts a new zone further into the tissue. Cells outside the boundary are
+// given a distance of zero.
+pub fn write_cell_zone_annotations(
+    outputs: &OutputBundle,
+    output_cell_zones: &Option<String>,
+    output_cell_zones_fmt: OutputFormat,
+    cell_centroids: &[(f32, f32, f32)],
+    tissue_boundary: &MultiPolygon<f32>,
+    zone_radii: &[f32],
+) {
+    if let Some(output_cell_zones) = output_cell_zones {
+        let boundary_rings: Vec<_> = tissue_boundary
+            .iter()
+            .flat_map(|polygon| std::iter::once(polygon.exterior()).chain(polygon.interiors()))
+            .collect();
+
+        let distances: Vec<f32> = cell_centroids
+            .par_iter()
+            .map(|&(x, y, _)| {
+                let point = Point::new(x, y);
+                if !tissue_boundary.contains(&point) {
+                    return 0.0;
+                }
+                boundary_rings
+                    .iter()
+                    .filter_map(|ring| match ring.closest_point(&point) {
+                        geo::Closest::Intersection(_) => Some(0.0),
+                        geo::Closest::SinglePoint(closest) => {
+                            Some(point.euclidean_distance(&closest))
+                        }
+                        geo::Closest::Indeterminate => None,
+                    })
+                    .fold(f32::INFINITY, f32::min)
+            })
+            .collect();
+
+        let zones: Vec<u8> = distances
+            .iter()
+            .map(|&d| zone_radii.iter().filter(|&&radius| d >= radius).count() as u8)
+            .collect();
+
+        let schema = Schema::from(vec![
+            Field::new("cell", DataType::UInt32, false),
+            Field::new("zone", DataType::UInt8, false),
+            Field::new("distance_to_boundary_um", DataType::Float32, false),
+        ]);
+
+        let columns: Vec<Arc<dyn arrow2::array::Array>> = vec![
+            Arc::new(array::UInt32Array::from_values(
+                0..cell_centroids.len() as u32,
+            )),
+            Arc::new(array::UInt8Array::from_vec(zones)),
+            Arc::new(array::Float32Array::from_vec(distances)),
+        ];
+
+        let chunk = arrow2::chunk::Chunk::new(columns);
+        write_table(
+            outputs,
+            output_cell_zones,
+            output_cell_zones_fmt,
+            schema,
+            chunk,
+        );
+    }
+}
+
+// Cells with a local outlier factor above this are flagged as outliers.
+const SPATIAL_OUTLIER_THRESHOLD: f32 = 2.0;
+
+// Compute the local outlier factor of each cell's xy centroid among its k
+// nearest neighbors.
+pub fn spatial_outlier_scores(cell_centroids: &[(f32, f32, f32)], k: usize) -> Vec<f32> {
+    let points: Vec<(f32, f32)> = cell_centroids.iter().map(|&(x, y, _)| (x, y)).collect();
+    local_outlier_factor(&points, k)
+}
+
+pub fn write_spatial_outlier_scores(
+    outputs: &OutputBundle,
+    output_spatial_outliers: &Option<String>,
+    output_spatial_outliers_fmt: OutputFormat,
+    cell_centroids: &[(f32, f32, f32)],
+    k: usize,
+) {
+    if let Some(output_spatial_outliers) = output_spatial_outliers {
+        let lof_scores = spatial_outlier_scores(cell_centroids, k);
+        let is_outlier: Vec<bool> = lof_scores
+            .iter()
+            .map(|&score| score > SPATIAL_OUTLIER_THRESHOLD)
+            .collect();
+
+        let schema = Schema::from(vec![
+            Field::new("cell", DataType::UInt32, false),
+            Field::new("lof_score", DataType::Float32, false),
+            Field::new("is_outlier", DataType::Boolean, false),
+        ]);
+
+        let columns: Vec<Arc<dyn arrow2::array::Array>> = vec![
+            Arc::new(array::UInt32Array::from_values(
+                0..cell_centroids.len() as u32,
+            )),
+            Arc::new(array::Float32Array::from_vec(lof_scores)),
+            Arc::new(array::BooleanArray::from_slice(is_outlier)),
+        ];
+
+        let chunk = arrow2::chunk::Chunk::new(columns);
+        write_table(
+            outputs,
+            output_spatial_outliers,
+            output_spatial_outliers_fmt,
+            schema,
+            chunk,
+        );
+    }
+}
+
+// Summarize each z-layer: cells whose centroid falls in the layer,
+// transcripts in the layer, and the fraction of those in the background. The
+// mean transcripts per cell is NaN for layers with no cell centroids.
+pub fn write_layer_summary(
+    outputs: &OutputBundle,
+    output_layer_summary: &Option<String>,
+    output_layer_summary_fmt: OutputFormat,
+    params: &ModelParams,
+    cell_centroids: &[(f32, f32, f32)],
     cell_assignments: &[(u32, f32)],
-    transcript_state: &Array1<TranscriptState>,
-    fovs: &[u32],
-    fov_names: &[String],
 ) {
-    dbg!(fovs.len());
-    dbg!(fov_names.len());
-    dbg!(transcripts.len());
+    if let Some(output_layer_summary) = output_layer_summary {
+        let nlayers = params.nlayers();
+
+        let mut cell_counts = vec![0u32; nlayers];
+        for &(_, _, z) in cell_centroids {
+            cell_counts[params.zlayer(z)] += 1;
+        }
+
+        let mut transcript_counts = vec![0u64; nlayers];
+        let mut background_counts = vec![0u64; nlayers];
+        let mut assigned_counts = vec![0u64; nlayers];
+        for ((position, &state), &(cell, _)) in params
+            .transcript_positions
+            .iter()
+            .zip(&params.transcript_state)
+            .zip(cell_assignments)
+        {
+            let layer = params.zlayer(position.2);
+            transcript_counts[layer] += 1;
+            if state == TranscriptState::Background {
+                background_counts[layer] += 1;
+            }
+            if cell != BACKGROUND_CELL {
+                assigned_counts[layer] += 1;
+            }
+        }
+
+        let schema = Schema::from(vec![
+            Field::new("layer", DataType::UInt32, false),
+            Field::new("cell_count", DataType::UInt32, false),
+            Field::new("transcript_count", DataType::UInt64, false),
+            Field::new("background_count", DataType::UInt64, false),
+            Field::new("background_fraction", DataType::Float32, false),
+            Field::new("mean_transcripts_per_cell", DataType::Float32, false),
+        ]);
+
+        let columns: Vec<Arc<dyn arrow2::array::Array>> = vec![
+            Arc::new(array::UInt32Array::from_values(0..nlayers as u32)),
+            Arc::new(array::UInt32Array::from_slice(&cell_counts)),
+            Arc::new(array::UInt64Array::from_slice(&transcript_counts)),
+            Arc::new(array::UInt64Array::from_slice(&background_counts)),
+            Arc::new(array::Float32Array::from_values(
+                background_counts
+                    .iter()
+                    .zip(&transcript_counts)
+                    .map(|(&bg, &total)| bg as f32 / total.max(1) as f32),
+            )),
+            Arc::new(array::Float32Array::from_values(
+                assigned_counts
+                    .iter()
+                    .zip(&cell_counts)
+                    .map(|(&assigned, &ncells)| {
+                        if ncells > 0 {
+                            assigned as f32 / ncells as f32
+                        } else {
+                            f32::NAN
+                        }
+                    }),
+            )),
+        ];
+
+        let chunk = arrow2::chunk::Chunk::new(columns);
+        write_table(
+            outputs,
+            output_layer_summary,
+            output_layer_summary_fmt,
+            schema,
+            chunk,
+        );
+    }
+}
+
+// Write Ripley's L function (as L(r) - r) of the centroids of each component's
+// cells, taking the study area to be the convex hull of all cell centroids.
+pub fn write_ripleys_l(
+    outputs: &OutputBundle,
+    output_ripleys_l: &Option<String>,
+    output_ripleys_l_fmt: OutputFormat,
+    cell_centroids: &[(f32, f32, f32)],
+    cluster_assignments: &Array1<u32>,
+    ncomponents: usize,
+    radii: &[f32],
+) {
+    if let Some(output_ripleys_l) = output_ripleys_l {
+        let area = cell_centroids
+            .iter()
+            .map(|&(x, y, _)| Point::new(x, y))
+            .collect::<MultiPoint<f32>>()
+            .convex_hull()
+            .unsigned_area();
+
+        let mut component_points = vec![Vec::new(); ncomponents];
+        for (&(x, y, _), &z) in cell_centroids.iter().zip(cluster_assignments) {
+            component_points[z as usize].push((x, y));
+        }
+
+        let mut components = Vec::new();
+        let mut radius_um = Vec::new();
+        let mut l_minus_r = Vec::new();
+        for (k, points) in component_points.iter().enumerate() {
+            components.extend(std::iter::repeat_n(k as u16, radii.len()));
+            radius_um.extend_from_slice(radii);
+            l_minus_r.extend(ripleys_l_minus_r(points, area, radii));
+        }
+
+        let schema = Schema::from(vec![
+            Field::new("component", DataType::UInt16, false),
+            Field::new("radius_um", DataType::Float32, false),
+            Field::new("L_minus_r", DataType::Float32, false),
+        ]);
+
+        let columns: Vec<Arc<dyn arrow2::array::Array>> = vec![
+            Arc::new(array::UInt16Array::from_vec(components)),
+            Arc::new(array::Float32Array::from_vec(radius_um)),
+            Arc::new(array::Float32Array::from_vec(l_minus_r)),
+        ];
+
+        let chunk = arrow2::chunk::Chunk::new(columns);
+        write_table(
+            outputs,
+            output_ripleys_l,
+            output_ripleys_l_fmt,
+            schema,
+            chunk,
+        );
+    }
+}
+
+// Write each pair of adjacent cells with the area of their shared boundary
+// and the fraction of each cell's surface area it makes up.
+pub fn write_cell_adjacency(
+    outputs: &OutputBundle,
+    output_cell_adjacency: &Option<String>,
+    output_cell_adjacency_fmt: OutputFormat,
+    sampler: &VoxelSampler,
+) {
+    if let Some(output_cell_adjacency) = output_cell_adjacency {
+        let (shared, surface_area) = sampler.cell_shared_boundaries();
+        let mut pairs: Vec<((u32, u32), f32)> = shared.into_iter().collect();
+        pairs.sort_by_key(|(pair, _)| *pair);
+
+        let schema = Schema::from(vec![
+            Field::new("cell_a", DataType::UInt32, false),
+            Field::new("cell_b", DataType::UInt32, false),
+            Field::new("shared_boundary_um2", DataType::Float32, false),
+            Field::new("shared_boundary_fraction_a", DataType::Float32, false),
+            Field::new("shared_boundary_fraction_b", DataType::Float32, false),
+        ]);
+
+        let columns: Vec<Arc<dyn arrow2::array::Array>> = vec![
+            Arc::new(array::UInt32Array::from_values(
+                pairs.iter().map(|((a, _), _)| *a),
+            )),
+            Arc::new(array::UInt32Array::from_values(
+                pairs.iter().map(|((_, b), _)| *b),
+            )),
+            Arc::new(array::Float32Array::from_values(
+                pairs.iter().map(|(_, area)| *area),
+            )),
+            Arc::new(array::Float32Array::from_values(
+                pairs
+                    .iter()
+                    .map(|((a, _), area)| area / surface_area[*a as usize]),
+            )),
+            Arc::new(array::Float32Array::from_values(
+                pairs
+                    .iter()
+                    .map(|((_, b), area)| area / surface_area[*b as usize]),
+            )),
+        ];
+
+        let chunk = arrow2::chunk::Chunk::new(columns);
+        write_table(
+            outputs,
+            output_cell_adjacency,
+            output_cell_adjacency_fmt,
+            schema,
+            chunk,
+        );
+    }
+}
+
+// Probability of observing at least `observed` events from a Poisson
+// distribution with mean `expected`.
+fn poisson_upper_tail(observed: u32, expected: f64) -> f64 {
+    if observed == 0 {
+        return 1.0;
+    }
+    if expected <= 0.0 {
+        return 0.0;
+    }
+    let lower_tail: f64 = (0..observed)
+        .map(|k| (k as f64 * expected.ln() - expected - libm::lgamma(k as f64 + 1.0)).exp())
+        .sum();
+    (1.0 - lower_tail).max(0.0)
+}
+
+// Contacts between cells of a pair of components.
+struct ContactEnrichment {
+    type_i: usize,
+    type_j: usize,
+    observed: u32,
+    expected: f64,
+    // observed / expected, or None if no contacts are expected
+    enrichment: Option<f64>,
+    p_value: f64,
+}
+
+// Count contacts between adjacent cells of each pair of components, and the
+// number expected if component labels were randomly permuted across cells.
+fn cell_type_contact_enrichment(
+    cell_adjacency: &[(u32, u32)],
+    cluster_assignments: &[u32],
+    ncomponents: usize,
+) -> Vec<ContactEnrichment> {
+    let mut observed = Array2::<u32>::zeros((ncomponents, ncomponents));
+    for &(a, b) in cell_adjacency {
+        let (i, j) = (
+            cluster_assignments[a as usize] as usize,
+            cluster_assignments[b as usize] as usize,
+        );
+        observed[[i.min(j), i.max(j)]] += 1;
+    }
+
+    let mut component_cells = vec![0_u64; ncomponents];
+    for &z in cluster_assignments {
+        component_cells[z as usize] += 1;
+    }
+    let ncells = cluster_assignments.len() as f64;
+    let ncontacts = cell_adjacency.len() as f64;
+
+    let mut enrichment = Vec::new();
+    for i in 0..ncomponents {
+        for j in i..ncomponents {
+            let (ni, nj) = (component_cells[i] as f64, component_cells[j] as f64);
+            // probability that a contact joins components i and j when
+            // labels are drawn without replacement
+            let pr = if i == j {
+                ni * (ni - 1.0) / (ncells * (ncells - 1.0))
+            } else {
+                2.0 * ni * nj / (ncells * (ncells - 1.0))
+            };
+            let expected = if ncells > 1.0 { ncontacts * pr } else { 0.0 };
+            let obs = observed[[i, j]];
+
+            enrichment.push(ContactEnrichment {
+                type_i: i,
+                type_j: j,
+                observed: obs,
+                expected,
+                enrichment: (expected > 0.0).then(|| obs as f64 / expected),
+                p_value: poisson_upper_tail(obs, expected),
+            });
+        }
+    }
+
+    enrichment
+}
+
+// Write, for each pair of components, the number of contacts between adjacent
+// cells of those components, the number expected if component labels were
+// randomly permuted across cells, and a Poisson p-value for enrichment.
+// Enrichment is null where no contacts are expected.
+pub fn write_cell_type_contact_enrichment(
+    outputs: &OutputBundle,
+    output_contact_enrichment: &Option<String>,
+    output_contact_enrichment_fmt: OutputFormat,
+    cell_adjacency: &[(u32, u32)],
+    cluster_assignments: &[u32],
+    ncomponents: usize,
+) {
+    if let Some(output_contact_enrichment) = output_contact_enrichment {
+        let enrichment =
+            cell_type_contact_enrichment(cell_adjacency, cluster_assignments, ncomponents);
+
+        let schema = Schema::from(vec![
+            Field::new("type_i", DataType::UInt16, false),
+            Field::new("type_j", DataType::UInt16, false),
+            Field::new("observed_contacts", DataType::UInt32, false),
+            Field::new("expected_contacts", DataType::Float32, false),
+            Field::new("enrichment", DataType::Float32, true),
+            Field::new("p_value", DataType::Float32, false),
+        ]);
+
+        let columns: Vec<Arc<dyn arrow2::array::Array>> = vec![
+            Arc::new(array::UInt16Array::from_values(
+                enrichment.iter().map(|e| e.type_i as u16),
+            )),
+            Arc::new(array::UInt16Array::from_values(
+                enrichment.iter().map(|e| e.type_j as u16),
+            )),
+            Arc::new(array::UInt32Array::from_values(
+                enrichment.iter().map(|e| e.observed),
+            )),
+            Arc::new(array::Float32Array::from_values(
+                enrichment.iter().map(|e| e.expected as f32),
+            )),
+            Arc::new(array::Float32Array::from_iter(
+                enrichment.iter().map(|e| e.enrichment.map(|v| v as f32)),
+            )),
+            Arc::new(array::Float32Array::from_values(
+                enrichment.iter().map(|e| e.p_value as f32),
+            )),
+        ];
+
+        let chunk = arrow2::chunk::Chunk::new(columns);
+        write_table(
+            outputs,
+            output_contact_enrichment,
+            output_contact_enrichment_fmt,
+            schema,
+            chunk,
+        );
+    }
+}
+
+// For each pair of adjacent cells, count the transcripts in voxels on their
+// shared boundary and the fraction whose maximum posterior assignment agrees
+// with the cell whose voxel contains them.
+pub fn write_cell_boundary_purity(
+    outputs: &OutputBundle,
+    output_cell_boundary_purity: &Option<String>,
+    output_cell_boundary_purity_fmt: OutputFormat,
+    sampler: &VoxelSampler,
+    cell_assignments: &[(u32, f32)],
+) {
+    if let Some(output_cell_boundary_purity) = output_cell_boundary_purity {
+        // (cell_a, cell_b) -> (boundary transcripts, correctly assigned)
+        let mut pair_counts: BTreeMap<(u32, u32), (u32, u32)> = BTreeMap::new();
+        for (i, cell, neighbor_cell) in sampler.boundary_transcripts() {
+            let pair = (cell.min(neighbor_cell), cell.max(neighbor_cell));
+            let counts = pair_counts.entry(pair).or_insert((0, 0));
+            counts.0 += 1;
+            if cell_assignments[i].0 == cell {
+                counts.1 += 1;
+            }
+        }
+
+        let schema = Schema::from(vec![
+            Field::new("cell_a", DataType::UInt32, false),
+            Field::new("cell_b", DataType::UInt32, false),
+            Field::new("boundary_transcripts", DataType::UInt32, false),
+            Field::new("purity", DataType::Float32, false),
+        ]);
+
+        let columns: Vec<Arc<dyn arrow2::array::Array>> = vec![
+            Arc::new(array::UInt32Array::from_values(
+                pair_counts.keys().map(|(a, _)| *a),
+            )),
+            Arc::new(array::UInt32Array::from_values(
+                pair_counts.keys().map(|(_, b)| *b),
+            )),
+            Arc::new(array::UInt32Array::from_values(
+                pair_counts.values().map(|(n, _)| *n),
+            )),
+            Arc::new(array::Float32Array::from_values(
+                pair_counts
+                    .values()
+                    .map(|(n, correct)| *correct as f32 / *n as f32),
+            )),
+        ];
+
+        let chunk = arrow2::chunk::Chunk::new(columns);
+        write_table(
+            outputs,
+            output_cell_boundary_purity,
+            output_cell_boundary_purity_fmt,
+            schema,
+            chunk,
+        );
+    }
+}
+
+// Write a 2D array in NumPy's .npy format.
+fn write_npy_f32(outputs: &OutputBundle, filename: &str, values: &Array2<f32>) {
+    let (nrows, ncols) = values.dim();
+    let mut header = format!(
+        "{{'descr': '<f4', 'fortran_order': False, 'shape': ({}, {}), }}",
+        nrows, ncols
+    );
+    // magic string, version, and header length take 10 bytes, and the header
+    // is padded so the data is 64 byte aligned.
+    let padding = 64 - (10 + header.len() + 1) % 64;
+    header.push_str(&" ".repeat(padding % 64));
+    header.push('\n');
+
+    let mut output = std::io::BufWriter::new(outputs.open(filename));
+    output.write_all(b"\x93NUMPY\x01\x00").unwrap();
+    output
+        .write_all(&(header.len() as u16).to_le_bytes())
+        .unwrap();
+    output.write_all(header.as_bytes()).unwrap();
+    for value in values.iter() {
+        output.write_all(&value.to_le_bytes()).unwrap();
+    }
+    Box::new(output)
+        .finish()
+        .unwrap_or_else(|err| panic!("Error writing {}: {}", filename, err));
+}
+
+// Write a raster of local cluster diversity, measured by the Shannon entropy
+// of the cluster composition of cells within `bandwidth` of each pixel, to
+// `spatial_entropy.npy` in the given directory. The grid position is written
+// to `spatial_entropy.json`.
+pub fn write_spatial_entropy_map(
+    outputs: &OutputBundle,
+    output_spatial_entropy_dir: &Option<String>,
+    cell_centroids: &[(f32, f32, f32)],
+    cluster_assignments: &[u32],
+    ncomponents: usize,
+    bandwidth: f32,
+    pixel_size: f32,
+) {
+    if let Some(output_spatial_entropy_dir) = output_spatial_entropy_dir {
+        outputs.create_dir(output_spatial_entropy_dir);
+        let path = |filename: &str| {
+            std::path::Path::new(output_spatial_entropy_dir)
+                .join(filename)
+                .to_str()
+                .unwrap()
+                .to_string()
+        };
+
+        let (entropy, (x0, y0)) = spatial_entropy_grid(
+            cell_centroids,
+            cluster_assignments,
+            ncomponents,
+            bandwidth,
+            pixel_size,
+        );
+        write_npy_f32(outputs, &path("spatial_entropy.npy"), &entropy);
+
+        let mut grid = json::JsonValue::new_object();
+        grid.insert("x0", x0).unwrap();
+        grid.insert("y0", y0).unwrap();
+        grid.insert("pixel_size", pixel_size).unwrap();
+        grid.insert("bandwidth", bandwidth).unwrap();
+        let filename = path("spatial_entropy.json");
+        let mut output = outputs.open(&filename);
+        grid.write_pretty(&mut output, 2)
+            .and_then(|_| output.finish())
+            .unwrap_or_else(|err| panic!("Error writing {}: {}", filename, err));
+    }
+}
+
+pub fn write_cell_count_trace(
+    outputs: &OutputBundle,
+    output_cell_count_trace: &Option<String>,
+    output_cell_count_trace_fmt: OutputFormat,
+    trace: &[(u32, u32)],
+) {
+    if let Some(output_cell_count_trace) = output_cell_count_trace {
+        let schema = Schema::from(vec![
+            Field::new("iteration", DataType::UInt32, false),
+            Field::new("n_active_cells", DataType::UInt32, false),
+        ]);
+
+        let columns: Vec<Arc<dyn arrow2::array::Array>> = vec![
+            Arc::new(array::UInt32Array::from_values(
+                trace.iter().map(|(iteration, _)| *iteration),
+            )),
+            Arc::new(array::UInt32Array::from_values(
+                trace.iter().map(|(_, n_active_cells)| *n_active_cells),
+            )),
+        ];
+
+        let chunk = arrow2::chunk::Chunk::new(columns);
+        write_table(
+            outputs,
+            output_cell_count_trace,
+            output_cell_count_trace_fmt,
+            schema,
+            chunk,
+        );
+    }
+}
+
+// Per-gene summary of how far rates and dispersions moved between two points
+// in sampling, as a quick check that the sampler is not stuck at its
+// initialization.
+pub fn write_parameter_change_summary(
+    outputs: &OutputBundle,
+    output_parameter_change_summary: &Option<String>,
+    output_parameter_change_summary_fmt: OutputFormat,
+    params_init: &ParamsSnapshot,
+    params: &ModelParams,
+    transcript_names: &[String],
+) {
+    if let Some(output_parameter_change_summary) = output_parameter_change_summary {
+        // mean and max absolute change along each row
+        let change_stats = |a: &Array2<f32>, b: &Array2<f32>| -> (Vec<f32>, Vec<f32>) {
+            Zip::from(a.rows())
+                .and(b.rows())
+                .map_collect(|a, b| {
+                    let mut sum = 0.0;
+                    let mut max = 0.0_f32;
+                    for (x, y) in a.iter().zip(b.iter()) {
+                        let d = (x - y).abs();
+                        sum += d;
+                        max = max.max(d);
+                    }
+                    (sum / a.len().max(1) as f32, max)
+                })
+                .into_iter()
+                .unzip()
+        };
+
+        // λ is [ngenes, ncells], r is [ncomponents, ngenes]
+        let (mean_dλ, max_dλ) = change_stats(&params_init.λ, &params.λ);
+        let (mean_dr, max_dr) =
+            change_stats(&params_init.r.t().to_owned(), &params.r.t().to_owned());
+
+        let schema = Schema::from(vec![
+            Field::new("gene", DataType::Utf8, false),
+            Field::new("mean_abs_Δλ", DataType::Float32, false),
+            Field::new("max_abs_Δλ", DataType::Float32, false),
+            Field::new("mean_abs_Δr", DataType::Float32, false),
+            Field::new("max_abs_Δr", DataType::Float32, false),
+        ]);
+
+        let columns: Vec<Arc<dyn arrow2::array::Array>> = vec![
+            Arc::new(array::Utf8Array::<i32>::from_iter_values(
+                transcript_names.iter().cloned(),
+            )),
+            Arc::new(array::Float32Array::from_vec(mean_dλ)),
+            Arc::new(array::Float32Array::from_vec(max_dλ)),
+            Arc::new(array::Float32Array::from_vec(mean_dr)),
+            Arc::new(array::Float32Array::from_vec(max_dr)),
+        ];
+
+        let chunk = arrow2::chunk::Chunk::new(columns);
+        write_table(
+            outputs,
+            output_parameter_change_summary,
+            output_parameter_change_summary_fmt,
+            schema,
+            chunk,
+        );
+    }
+}
+
+// Compare the prior mean of λ and r to their posterior means for each gene.
+// Priors are shared across genes, so the prior columns are constant.
+pub fn write_prior_posterior_comparison(
+    outputs: &OutputBundle,
+    output_prior_posterior: &Option<String>,
+    output_prior_posterior_fmt: OutputFormat,
+    priors: &ModelPriors,
+    params: &ModelParams,
+    transcript_names: &[String],
+) {
+    if let Some(output_prior_posterior) = output_prior_posterior {
+        let ngenes = transcript_names.len();
+        let prior_λ_mean = priors.λ_mean();
+        let prior_r_mean = priors.r_mean();
+
+        // λ is [ngenes, ncells], r is [ncomponents, ngenes]
+        let posterior_λ_mean = params.λ.mean_axis(Axis(1)).unwrap();
+        let posterior_r_mean = params.r.mean_axis(Axis(0)).unwrap();
+
+        let schema = Schema::from(vec![
+            Field::new("gene", DataType::Utf8, false),
+            Field::new("prior_λ_mean", DataType::Float32, false),
+            Field::new("posterior_λ_mean", DataType::Float32, false),
+            Field::new("prior_r_mean", DataType::Float32, false),
+            Field::new("posterior_r_mean", DataType::Float32, false),
+            Field::new("λ_update_ratio", DataType::Float32, false),
+            Field::new("r_update_ratio", DataType::Float32, false),
+        ]);
+
+        let columns: Vec<Arc<dyn arrow2::array::Array>> = vec![
+            Arc::new(array::Utf8Array::<i32>::from_iter_values(
+                transcript_names.iter().cloned(),
+            )),
+            Arc::new(array::Float32Array::from_vec(vec![prior_λ_mean; ngenes])),
+            Arc::new(array::Float32Array::from_values(
+                posterior_λ_mean.iter().cloned(),
+            )),
+            Arc::new(array::Float32Array::from_vec(vec![prior_r_mean; ngenes])),
+            Arc::new(array::Float32Array::from_values(
+                posterior_r_mean.iter().cloned(),
+            )),
+            Arc::new(array::Float32Array::from_values(
+                posterior_λ_mean.iter().map(|&λ| λ / prior_λ_mean),
+            )),
+            Arc::new(array::Float32Array::from_values(
+                posterior_r_mean.iter().map(|&r| r / prior_r_mean),
+            )),
+        ];
+
+        let chunk = arrow2::chunk::Chunk::new(columns);
+        write_table(
+            outputs,
+            output_prior_posterior,
+            output_prior_posterior_fmt,
+            schema,
+            chunk,
+        );
+    }
+}
+
+pub fn write_assignment_instability(
+    outputs: &OutputBundle,
+    output_assignment_instability: &Option<String>,
+    output_assignment_instability_fmt: OutputFormat,
+    tracker: &TranscriptAssignmentTracker,
+    ncells: usize,
+) {
+    if let Some(output_assignment_instability) = output_assignment_instability {
+        let (n_changes, fraction_unstable, instability_score) = tracker.instability(ncells);
+
+        let schema = Schema::from(vec![
+            Field::new("cell", DataType::UInt32, false),
+            Field::new("n_assignment_changes", DataType::UInt32, false),
+            Field::new("fraction_unstable", DataType::Float32, false),
+            Field::new("instability_score", DataType::Float32, false),
+        ]);
+
+        let columns: Vec<Arc<dyn arrow2::array::Array>> = vec![
+            Arc::new(array::UInt32Array::from_values(0..ncells as u32)),
+            Arc::new(array::UInt32Array::from_vec(n_changes)),
+            Arc::new(array::Float32Array::from_vec(fraction_unstable)),
+            Arc::new(array::Float32Array::from_vec(instability_score)),
+        ];
+
+        let chunk = arrow2::chunk::Chunk::new(columns);
+        write_table(
+            outputs,
+            output_assignment_instability,
+            output_assignment_instability_fmt,
+            schema,
+            chunk,
+        );
+    }
+}
+
+pub fn write_component_params(
+    outputs: &OutputBundle,
+    output_component_params: &Option<String>,
+    output_component_params_fmt: OutputFormat,
+    params: &ModelParams,
+    transcript_names: &[String],
+) {
+    if let Some(output_component_params) = output_component_params {
+        // What does this look like: rows for each gene, columns for α1, β1, α2, β2, etc.
+        let α = &params.r;
+        let φ = &params.φ;
+        let β = φ.map(|φ| (-φ).exp());
+
+        let ncomponents = params.ncomponents();
+
+        let mut fields = Vec::new();
+        fields.push(Field::new("gene", DataType::Utf8, false));
+        for i in 0..ncomponents {
+            fields.push(Field::new(&format!("α_{}", i), DataType::Float32, false));
+            fields.push(Field::new(&format!("β_{}", i), DataType::Float32, false));
+        }
+        let schema = Schema::from(fields);
+
+        let mut columns: Vec<Arc<dyn arrow2::array::Array>> = Vec::new();
+        columns.push(Arc::new(array::Utf8Array::<i32>::from_iter_values(
+            transcript_names.iter().cloned(),
+        )));
+        Zip::from(α.rows()).and(β.rows()).for_each(|α, β| {
+            columns.push(Arc::new(array::Float32Array::from_values(
+                α.iter().cloned(),
+            )));
+            columns.push(Arc::new(array::Float32Array::from_values(
+                β.iter().cloned(),
+            )));
+        });
+
+        let chunk = arrow2::chunk::Chunk::new(columns);
+        write_table(
+            outputs,
+            output_component_params,
+            output_component_params_fmt,
+            schema,
+            chunk,
+        );
+    }
+}
+
+pub fn write_dispersion_variance(
+    outputs: &OutputBundle,
+    output_dispersion_variance: &Option<String>,
+    output_dispersion_variance_fmt: OutputFormat,
+    params: &ModelParams,
+    transcript_names: &[String],
+) {
+    if let Some(output_dispersion_variance) = output_dispersion_variance {
+        let r_variance = params
+            .r_variance
+            .as_ref()
+            .expect("No recorded samples from which to compute dispersion variance");
+
+        let ncomponents = params.ncomponents();
+
+        let mut fields = Vec::new();
+        fields.push(Field::new("gene", DataType::Utf8, false));
+        for i in 0..ncomponents {
+            fields.push(Field::new(format!("α_{}", i), DataType::Float32, false));
+        }
+        let schema = Schema::from(fields);
+
+        let mut columns: Vec<Arc<dyn arrow2::array::Array>> = Vec::new();
+        columns.push(Arc::new(array::Utf8Array::<i32>::from_iter_values(
+            transcript_names.iter().cloned(),
+        )));
+        for row in r_variance.rows() {
+            columns.push(Arc::new(array::Float32Array::from_values(
+                row.iter().cloned(),
+            )));
+        }
+
+        let chunk = arrow2::chunk::Chunk::new(columns);
+        write_table(
+            outputs,
+            output_dispersion_variance,
+            output_dispersion_variance_fmt,
+            schema,
+            chunk,
+        );
+    }
+}
+
+// Assign cells to fovs by finding the most common transcript fov of the
+// assigned transcripts.
+fn cell_fov_vote(
+    ncells: usize,
+    nfovs: usize,
+    cell_assignments: &[(u32, f32)],
+    fovs: &[u32],
+) -> Vec<u32> {
+    let mut fov_votes = Array2::<u32>::zeros((ncells, nfovs));
+    for (fov, (cell, _)) in fovs.iter().zip(cell_assignments) {
+        if *cell != BACKGROUND_CELL {
+            fov_votes[[*cell as usize, *fov as usize]] += 1;
+        }
+    }
+
+    fov_votes
+        .outer_iter()
+        .map(|votes| {
+            let mut winner = u32::MAX;
+            let mut winner_count: u32 = 0;
+            for (fov, count) in votes.iter().enumerate() {
+                if *count > winner_count {
+                    winner_count = *count;
+                    winner = fov as u32;
+                }
+            }
+            winner
+        })
+        .collect::<Vec<u32>>()
+}
+
+// Find the span (number of layers from lowest to highest, inclusive) and the
+// most populous z-layer of the transcripts assigned to each cell. Cells with
+// no assigned transcripts have a span of 0 and no dominant layer.
+fn cell_layer_stats(
+    params: &ModelParams,
+    cell_assignments: &[(u32, f32)],
+) -> (Vec<i32>, Vec<Option<i32>>) {
+    let ncells = params.ncells();
+    let nlayers = params.nlayers();
+    let mut layer_counts = Array2::<u32>::zeros((ncells, nlayers));
+    for (pos, (cell, _)) in params.transcript_positions.iter().zip(cell_assignments) {
+        if *cell != BACKGROUND_CELL {
+            layer_counts[[*cell as usize, params.zlayer(pos.2)]] += 1;
+        }
+    }
+
+    layer_counts
+        .outer_iter()
+        .map(|counts| {
+            let mut min_layer = None;
+            let mut max_layer = None;
+            let mut dominant_layer = None;
+            let mut dominant_count: u32 = 0;
+            for (layer, count) in counts.iter().enumerate() {
+                if *count == 0 {
+                    continue;
+                }
+                min_layer.get_or_insert(layer as i32);
+                max_layer = Some(layer as i32);
+                if *count > dominant_count {
+                    dominant_count = *count;
+                    dominant_layer = Some(layer as i32);
+                }
+            }
+
+            let span = match (min_layer, max_layer) {
+                (Some(min_layer), Some(max_layer)) => max_layer - min_layer + 1,
+                _ => 0,
+            };
+            (span, dominant_layer)
+        })
+        .unzip()
+}
+
+// Perimeter of each cell's polygon divided by sqrt(4π · area). This is 1 for a
+// circle and grows as shapes become more irregular.
+pub fn cell_shape_indices(polygons: &[MultiPolygon<f32>]) -> Vec<Option<f32>> {
+    polygons
+        .par_iter()
+        .map(|polygon| {
+            let area = polygon.unsigned_area();
+            let perimeter: f32 = polygon
+                .iter()
+                .map(|polygon| {
+                    polygon.exterior().euclidean_length()
+                        + polygon
+                            .interiors()
+                            .iter()
+                            .map(|interior| interior.euclidean_length())
+                            .sum::<f32>()
+                })
+                .sum();
+            if area > 0.0 {
+                Some(perimeter / (4.0 * std::f32::consts::PI * area).sqrt())
+            } else {
+                None
+            }
+        })
+        .collect()
+}
+
+// Standardize shape indices against the mean and standard deviation of cells
+// in the same cluster.
+fn shape_index_z_scores(
+    params: &ModelParams,
+    cell_shape_indices: &[Option<f32>],
+) -> Vec<Option<f32>> {
+    let ncomponents = params.ncomponents();
+    let mut sums = vec![0.0; ncomponents];
+    let mut sums_sq = vec![0.0; ncomponents];
+    let mut counts = vec![0; ncomponents];
+    for (&z, shape_index) in params.z.iter().zip(cell_shape_indices) {
+        if let Some(shape_index) = shape_index {
+            sums[z as usize] += *shape_index as f64;
+            sums_sq[z as usize] += (*shape_index as f64).powi(2);
+            counts[z as usize] += 1;
+        }
+    }
+
+    params
+        .z
+        .iter()
+        .zip(cell_shape_indices)
+        .map(|(&z, shape_index)| {
+            let shape_index = (*shape_index)? as f64;
+            let n = counts[z as usize] as f64;
+            let μ = sums[z as usize] / n;
+            let σ = (sums_sq[z as usize] / n - μ * μ).max(0.0).sqrt();
+            if σ > 0.0 {
+                Some(((shape_index - μ) / σ) as f32)
+            } else {
+                Some(0.0)
+            }
+        })
+        .collect()
+}
+
+// Cells with any voxel this close to an FOV edge are flagged as boundary cells.
+const FOV_BOUNDARY_MARGIN: f32 = 5.0;
+
+// Distance from a point to the nearest edge of any FOV bounding box.
+fn fov_edge_distance(x: f32, y: f32, fov_bounds: &[(f32, f32, f32, f32)]) -> f32 {
+    fov_bounds
+        .iter()
+        .map(|&(x_min, y_min, x_max, y_max)| {
+            let dx = (x_min - x).max(x - x_max);
+            let dy = (y_min - y).max(y - y_max);
+            if dx <= 0.0 && dy <= 0.0 {
+                // inside, so the nearest edge is the closest side
+                -dx.max(dy)
+            } else {
+                (dx.max(0.0).powi(2) + dy.max(0.0).powi(2)).sqrt()
+            }
+        })
+        .fold(f32::INFINITY, f32::min)
+}
+
+// Count thresholds for the `n_genes_detected_*` cell metadata columns.
+const GENE_DETECTION_THRESHOLDS: [u32; 3] = [1, 5, 10];
+
+// Number of genes in each cell with a count of at least `threshold`.
+fn genes_detected(counts: &Array2<u32>, threshold: u32) -> Vec<u16> {
+    counts
+        .axis_iter(Axis(1))
+        .map(|cell_counts| {
+            cell_counts
+                .iter()
+                .filter(|&&count| count >= threshold)
+                .count()
+                .min(u16::MAX as usize) as u16
+        })
+        .collect()
+}
+
+// Fields and columns shared by the cell metadata tables. `centroid_z` is
+// omitted when no z coordinates are given, as in z-projected output.
+#[allow(clippy::too_many_arguments)]
+fn cell_metadata_columns(
+    params: &ModelParams,
+    cell_centroids_xy: &[(f32, f32)],
+    cell_centroids_z: Option<&[f32]>,
+    cell_isolation_scores: &[f32],
+    cell_shape_indices: &[Option<f32>],
+    cell_voxel_counts: &[u32],
+    cell_neighbor_similarity: &[f32],
+    cell_assignment_stable: Option<&[bool]>,
+    cell_spatial_outlier_scores: Option<&[f32]>,
+    cell_fov_edges: Option<(&[f32], &[bool])>,
+    counts: &Array2<u32>,
+    cell_assignments: &[(u32, f32)],
+    fovs: &[u32],
+    fov_names: &[String],
+) -> (Vec<Field>, Vec<Arc<dyn arrow2::array::Array>>) {
+    let ncells = cell_centroids_xy.len();
+    let nfovs = fov_names.len();
+    let cell_fovs = cell_fov_vote(ncells, nfovs, cell_assignments, fovs);
+    let (layer_spans, dominant_layers) = cell_layer_stats(params, cell_assignments);
+    let shape_index_z_scores = shape_index_z_scores(params, cell_shape_indices);
+
+    let mut fields = vec![
+        Field::new("cell", DataType::UInt32, false),
+        Field::new("centroid_x", DataType::Float32, false),
+        Field::new("centroid_y", DataType::Float32, false),
+    ];
+    let mut columns: Vec<Arc<dyn arrow2::array::Array>> = vec![
+        Arc::new(array::UInt32Array::from_values(0..params.ncells() as u32)),
+        Arc::new(array::Float32Array::from_values(
+            cell_centroids_xy.iter().map(|(x, _)| *x),
+        )),
+        Arc::new(array::Float32Array::from_values(
+            cell_centroids_xy.iter().map(|(_, y)| *y),
+        )),
+    ];
+
+    if let Some(cell_centroids_z) = cell_centroids_z {
+        fields.push(Field::new("centroid_z", DataType::Float32, false));
+        columns.push(Arc::new(array::Float32Array::from_slice(cell_centroids_z)));
+    }
+
+    fields.extend([
+        Field::new("fov", DataType::Utf8, true),
+        Field::new("cluster", DataType::UInt16, false),
+        Field::new("volume", DataType::Float32, false),
+        Field::new("population", DataType::UInt64, false),
+        Field::new("layer_span", DataType::Int32, false),
+        Field::new("dominant_layer", DataType::Int32, true),
+        Field::new("isolation_score", DataType::Float32, false),
+        Field::new("shape_index", DataType::Float32, true),
+        Field::new("shape_index_z_score", DataType::Float32, true),
+        Field::new("n_voxels", DataType::UInt32, false),
+        Field::new(
+            "mean_neighbor_expression_similarity",
+            DataType::Float32,
+            false,
+        ),
+    ]);
+    columns.extend([
+        Arc::new(array::Utf8Array::<i32>::from_iter(cell_fovs.iter().map(
+            |fov| {
+                if *fov == u32::MAX {
+                    None
+                } else {
+                    Some(fov_names[*fov as usize].clone())
+                }
+            },
+        ))) as Arc<dyn arrow2::array::Array>,
+        Arc::new(array::UInt16Array::from_values(
+            params.z.iter().map(|&z| z as u16),
+        )),
+        Arc::new(array::Float32Array::from_values(
+            params.cell_volume.iter().cloned(),
+        )),
+        Arc::new(array::UInt64Array::from_values(
+            params.cell_population.iter().map(|&p| p as u64),
+        )),
+        Arc::new(array::Int32Array::from_vec(layer_spans)),
+        Arc::new(array::Int32Array::from(dominant_layers)),
+        Arc::new(array::Float32Array::from_slice(cell_isolation_scores)),
+        Arc::new(array::Float32Array::from(cell_shape_indices.to_vec())),
+        Arc::new(array::Float32Array::from(shape_index_z_scores)),
+        Arc::new(array::UInt32Array::from_slice(cell_voxel_counts)),
+        Arc::new(array::Float32Array::from_slice(cell_neighbor_similarity)),
+    ]);
+
+    for threshold in GENE_DETECTION_THRESHOLDS {
+        fields.push(Field::new(
+            format!("n_genes_detected_{}", threshold),
+            DataType::UInt16,
+            false,
+        ));
+        columns.push(Arc::new(array::UInt16Array::from_vec(genes_detected(
+            counts, threshold,
+        ))));
+    }
+
+    if let Some(cell_assignment_stable) = cell_assignment_stable {
+        fields.push(Field::new("assignment_stable", DataType::Boolean, false));
+        columns.push(Arc::new(array::BooleanArray::from_slice(
+            cell_assignment_stable,
+        )));
+    }
+
+    if let Some(cell_spatial_outlier_scores) = cell_spatial_outlier_scores {
+        fields.push(Field::new(
+            "spatial_outlier_score",
+            DataType::Float32,
+            false,
+        ));
+        columns.push(Arc::new(array::Float32Array::from_slice(
+            cell_spatial_outlier_scores,
+        )));
+    }
+
+    if let Some((cell_fov_edge_distances, cell_fov_boundary)) = cell_fov_edges {
+        fields.extend([
+            Field::new("fov_edge_distance_um", DataType::Float32, false),
+            Field::new("is_fov_boundary_cell", DataType::Boolean, false),
+        ]);
+        columns.extend([
+            Arc::new(array::Float32Array::from_slice(cell_fov_edge_distances))
+                as Arc<dyn arrow2::array::Array>,
+            Arc::new(array::BooleanArray::from_slice(cell_fov_boundary)),
+        ]);
+    }
+
+    (fields, columns)
+}
+
+#[allow(clippy::too_many_arguments)]
+pub fn write_cell_metadata(
+    outputs: &OutputBundle,
+    output_cell_metadata: &Option<String>,
+    output_cell_metadata_fmt: OutputFormat,
+    params: &ModelParams,
+    sampler: &VoxelSampler,
+    cell_centroids: &[(f32, f32, f32)],
+    cell_isolation_scores: &[f32],
+    cell_shape_indices: &[Option<f32>],
+    cell_neighbor_similarity: &[f32],
+    cell_assignment_stable: Option<&[bool]>,
+    cell_spatial_outlier_scores: Option<&[f32]>,
+    fov_bounds: &[(f32, f32, f32, f32)],
+    counts: &Array2<u32>,
+    cell_assignments: &[(u32, f32)],
+    fovs: &[u32],
+    fov_names: &[String],
+) {
+    if let Some(output_cell_metadata) = output_cell_metadata {
+        let cell_centroids_xy = cell_centroids
+            .iter()
+            .map(|(x, y, _)| (*x, *y))
+            .collect::<Vec<_>>();
+        let cell_centroids_z = cell_centroids
+            .iter()
+            .map(|(_, _, z)| *z)
+            .collect::<Vec<_>>();
+        let cell_voxel_counts = sampler.cell_voxel_counts();
+
+        let cell_fov_edge_distances = cell_centroids_xy
+            .iter()
+            .map(|&(x, y)| fov_edge_distance(x, y, fov_bounds))
+            .collect::<Vec<_>>();
+        let cell_fov_boundary = sampler
+            .cell_min_voxel_distance(|x, y| fov_edge_distance(x, y, fov_bounds))
+            .iter()
+            .map(|&d| d <= FOV_BOUNDARY_MARGIN)
+            .collect::<Vec<_>>();
+
+        let (fields, columns) = cell_metadata_columns(
+            params,
+            &cell_centroids_xy,
+            Some(&cell_centroids_z),
+            cell_isolation_scores,
+            cell_shape_indices,
+            &cell_voxel_counts,
+            cell_neighbor_similarity,
+            cell_assignment_stable,
+            cell_spatial_outlier_scores,
+            if fov_bounds.is_empty() {
+                None
+            } else {
+                Some((&cell_fov_edge_distances, &cell_fov_boundary))
+            },
+            counts,
+            cell_assignments,
+            fovs,
+            fov_names,
+        );
+
+        let schema = with_schema_version(Schema::from(fields), CELL_METADATA_SCHEMA_VERSION);
+        let chunk = arrow2::chunk::Chunk::new(columns);
+
+        write_table(
+            outputs,
+            output_cell_metadata,
+            output_cell_metadata_fmt,
+            schema,
+            chunk,
+        );
+    }
+}
+
+// Choose a 2D position for each cell, collapsing the z axis.
+fn z_projected_centroids(
+    projection: ZProjection,
+    sampler: &VoxelSampler,
+    cell_centroids: &[(f32, f32, f32)],
+    cell_assignments: &[(u32, f32)],
+    transcript_positions: &[(f32, f32, f32)],
+) -> Vec<(f32, f32)> {
+    let ncells = cell_centroids.len();
+    let layer_centroids = sampler.cell_layer_centroids();
+
+    // Pick the non-empty layer closest to `k`, falling back to the 3D centroid.
+    let layer_centroid = |cell: usize, k: usize| -> (f32, f32) {
+        layer_centroids[cell]
+            .iter()
+            .enumerate()
+            .filter(|(_, (_, _, count))| *count > 0)
+            .min_by_key(|(l, _)| (*l as i64 - k as i64).abs())
+            .map(|(_, (x, y, _))| (*x, *y))
+            .unwrap_or((cell_centroids[cell].0, cell_centroids[cell].1))
+    };
+
+    match projection {
+        ZProjection::MeanPosition => cell_centroids.iter().map(|(x, y, _)| (*x, *y)).collect(),
+        ZProjection::MaxIntensity => (0..ncells)
+            .map(|cell| {
+                let k = layer_centroids[cell]
+                    .iter()
+                    .enumerate()
+                    .max_by_key(|(_, (_, _, count))| *count)
+                    .map(|(k, _)| k)
+                    .unwrap_or(0);
+                layer_centroid(cell, k)
+            })
+            .collect(),
+        ZProjection::MedianZ => {
+            let mut cell_zs = vec![Vec::new(); ncells];
+            for (&(cell, _), &(_, _, z)) in cell_assignments.iter().zip(transcript_positions) {
+                if cell != BACKGROUND_CELL {
+                    cell_zs[cell as usize].push(z);
+                }
+            }
+
+            cell_zs
+                .iter_mut()
+                .enumerate()
+                .map(|(cell, zs)| {
+                    if zs.is_empty() {
+                        return (cell_centroids[cell].0, cell_centroids[cell].1);
+                    }
+                    zs.sort_by(|a, b| a.partial_cmp(b).unwrap());
+                    let median_z = zs[zs.len() / 2];
+                    layer_centroid(cell, sampler.z_to_voxel_layer(median_z))
+                })
+                .collect()
+        }
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+pub fn write_z_projection_metadata(
+    outputs: &OutputBundle,
+    output_z_projection_metadata: &Option<String>,
+    output_z_projection_metadata_fmt: OutputFormat,
+    projection: ZProjection,
+    params: &ModelParams,
+    sampler: &VoxelSampler,
+    cell_centroids: &[(f32, f32, f32)],
+    cell_isolation_scores: &[f32],
+    cell_shape_indices: &[Option<f32>],
+    cell_neighbor_similarity: &[f32],
+    cell_assignment_stable: Option<&[bool]>,
+    cell_spatial_outlier_scores: Option<&[f32]>,
+    counts: &Array2<u32>,
+    cell_assignments: &[(u32, f32)],
+    fovs: &[u32],
+    fov_names: &[String],
+) {
+    if let Some(output_z_projection_metadata) = output_z_projection_metadata {
+        let cell_centroids_xy = z_projected_centroids(
+            projection,
+            sampler,
+            cell_centroids,
+            cell_assignments,
+            &params.transcript_positions,
+        );
+        let cell_voxel_counts = sampler.cell_voxel_counts();
+
+        let (fields, columns) = cell_metadata_columns(
+            params,
+            &cell_centroids_xy,
+            None,
+            cell_isolation_scores,
+            cell_shape_indices,
+            &cell_voxel_counts,
+            cell_neighbor_similarity,
+            cell_assignment_stable,
+            cell_spatial_outlier_scores,
+            None,
+            counts,
+            cell_assignments,
+            fovs,
+            fov_names,
+        );
+
+        let schema = with_schema_version(Schema::from(fields), CELL_METADATA_SCHEMA_VERSION);
+        let chunk = arrow2::chunk::Chunk::new(columns);
+
+        write_table(
+            outputs,
+            output_z_projection_metadata,
+            output_z_projection_metadata_fmt,
+            schema,
+            chunk,
+        );
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+pub fn write_transcript_metadata(
+    outputs: &OutputBundle,
+    output_transcript_metadata: &Option<String>,
+    output_transcript_metadata_fmt: SinkFormat,
+    transcripts: &[Transcript],
+    transcript_positions: &[(f32, f32, f32)],
+    transcript_names: &[String],
+    cell_assignments: &[(u32, f32)],
+    transcript_state: &Array1<TranscriptState>,
+    duplicate_flag: &Array1<bool>,
+    true_positive_probability: &[f32],
+    tp_threshold: Option<f32>,
+    fovs: &[u32],
+    fov_names: &[String],
+    hex_binner: &HexBinner,
+) {
+    if let Some(output_transcript_metadata) = output_transcript_metadata {
+        let mut sink = TranscriptSink::open(
+            outputs,
+            output_transcript_metadata,
+            output_transcript_metadata_fmt,
+        )
+        .unwrap_or_else(|err| panic!("Error opening {}: {}", output_transcript_metadata, err));
+
+        // Only write transcripts passing the true positive threshold
+        for (i, transcript) in transcripts.iter().enumerate() {
+            if tp_threshold.is_some_and(|t| true_positive_probability[i] < t) {
+                continue;
+            }
+            let (x, y, _z) = transcript_positions[i];
+            sink.write_transcript_row(
+                transcript,
+                transcript_positions[i],
+                &transcript_names[transcript.gene as usize],
+                &fov_names[fovs[i] as usize],
+                cell_assignments[i],
+                transcript_state[i],
+                hex_binner.bin(x, y),
+                duplicate_flag[i],
+                true_positive_probability[i],
+            )
+            .unwrap_or_else(|err| panic!("Error writing {}: {}", output_transcript_metadata, err));
+        }
+        sink.finish()
+            .unwrap_or_else(|err| panic!("Error writing {}: {}", output_transcript_metadata, err));
+    }
+}
+
+// Number of equal width bins between 0 and 1 in assignment probability histograms.
+const ASSIGNMENT_PROBABILITY_BINS: usize = 10;
+
+// Write, for each gene, a histogram of the assignment probabilities of its
+// transcripts.
+pub fn write_gene_assignment_distribution(
+    outputs: &OutputBundle,
+    output_gene_assignment_distribution: &Option<String>,
+    output_gene_assignment_distribution_fmt: OutputFormat,
+    transcripts: &[Transcript],
+    cell_assignments: &[(u32, f32)],
+    transcript_names: &[String],
+) {
+    if let Some(output_gene_assignment_distribution) = output_gene_assignment_distribution {
+        let ngenes = transcript_names.len();
+        let mut histograms = Array2::<u32>::zeros((ngenes, ASSIGNMENT_PROBABILITY_BINS));
+        for (transcript, &(_, pr)) in transcripts.iter().zip(cell_assignments) {
+            let bin = ((pr * ASSIGNMENT_PROBABILITY_BINS as f32) as usize)
+                .min(ASSIGNMENT_PROBABILITY_BINS - 1);
+            histograms[[transcript.gene as usize, bin]] += 1;
+        }
+
+        let mut fields = vec![Field::new("gene", DataType::Utf8, false)];
+        let mut columns: Vec<Arc<dyn arrow2::array::Array>> = vec![Arc::new(
+            array::Utf8Array::<i32>::from_iter_values(transcript_names.iter().cloned()),
+        )];
+        for (i, histogram_column) in histograms.columns().into_iter().enumerate() {
+            fields.push(Field::new(
+                format!(
+                    "bin_{}_{:.1}_to_{:.1}",
+                    i,
+                    i as f32 / ASSIGNMENT_PROBABILITY_BINS as f32,
+                    (i + 1) as f32 / ASSIGNMENT_PROBABILITY_BINS as f32
+                )
+                .replace('.', "_"),
+                DataType::UInt32,
+                false,
+            ));
+            columns.push(Arc::new(array::UInt32Array::from_values(
+                histogram_column.iter().cloned(),
+            )));
+        }
+
+        let schema = Schema::from(fields);
+        let chunk = arrow2::chunk::Chunk::new(columns);
+        write_table(
+            outputs,
+            output_gene_assignment_distribution,
+            output_gene_assignment_distribution_fmt,
+            schema,
+            chunk,
+        );
+    }
+}
+
+// Pseudocount added to rates before computing log2 enrichment over background.
+const ENRICHMENT_PSEUDOCOUNT: f32 = 1e-6;
+
+// Write a JSON object giving, for each component, the log2 enrichment over
+// background of the `n_top_genes` most enriched genes, e.g.
+// `{"component_0": {"gene_a": 3.2, "gene_b": 2.9, ...}, ...}`.
+pub fn write_component_fingerprints_json(
+    outputs: &OutputBundle,
+    output_path: &str,
+    params: &ModelParams,
+    transcript_names: &[String],
+    n_top_genes: usize,
+) {
+    let λ_components = params.component_mean_rates();
+    let λ_bg = params.λ_bg.mean_axis(Axis(1)).unwrap();
+
+    let mut fingerprints = json::JsonValue::new_object();
+    for (k, λ_component) in λ_components.outer_iter().enumerate() {
+        let mut enrichment: Vec<(usize, f32)> = λ_component
+            .iter()
+            .zip(λ_bg.iter())
+            .map(|(&λ, &λ_bg)| {
+                ((λ + ENRICHMENT_PSEUDOCOUNT) / (λ_bg + ENRICHMENT_PSEUDOCOUNT)).log2()
+            })
+            .enumerate()
+            .filter(|(_, e)| e.is_finite())
+            .collect();
+        enrichment.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap());
+
+        let mut fingerprint = json::JsonValue::new_object();
+        for (gene, e) in enrichment.iter().take(n_top_genes) {
+            fingerprint.insert(&transcript_names[*gene], *e).unwrap();
+        }
+        fingerprints
+            .insert(&format!("component_{}", k), fingerprint)
+            .unwrap();
+    }
+
+    let mut output = outputs.open(output_path);
+    fingerprints
+        .write_pretty(&mut output, 2)
+        .and_then(|_| output.finish())
+        .unwrap_or_else(|err| panic!("Error writing {}: {}", output_path, err));
+}
+
+// Write the top `n_top_markers` genes of each component, ranked by log2 fold
+// change of the component's mean rate over the mean rate of the other
+// components. Specificity is the component's share of the summed rates.
+pub fn write_marker_genes(
+    outputs: &OutputBundle,
+    output_marker_genes: &Option<String>,
+    output_marker_genes_fmt: OutputFormat,
+    params: &ModelParams,
+    transcript_names: &[String],
+    n_top_markers: usize,
+) {
+    if let Some(output_marker_genes) = output_marker_genes {
+        let n_top_markers = n_top_markers.min(u8::MAX as usize);
+        let λ_components = params.component_mean_rates();
+        let λ_total = λ_components.sum_axis(Axis(0));
+        let nothers = (params.ncomponents() - 1).max(1) as f32;
+
+        let mut components = Vec::new();
+        let mut ranks = Vec::new();
+        let mut genes = Vec::new();
+        let mut log2fcs = Vec::new();
+        let mut mean_rates = Vec::new();
+        let mut specificities = Vec::new();
+        for (k, λ_component) in λ_components.outer_iter().enumerate() {
+            let mut markers: Vec<(usize, f32)> = λ_component
+                .iter()
+                .zip(λ_total.iter())
+                .map(|(&λ, &total)| {
+                    let λ_others = (total - λ) / nothers;
+                    ((λ + ENRICHMENT_PSEUDOCOUNT) / (λ_others + ENRICHMENT_PSEUDOCOUNT)).log2()
+                })
+                .enumerate()
+                .filter(|(_, log2fc)| log2fc.is_finite())
+                .collect();
+            markers.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap());
+
+            for (rank, &(gene, log2fc)) in markers.iter().take(n_top_markers).enumerate() {
+                components.push(k as u16);
+                ranks.push(rank as u8 + 1);
+                genes.push(transcript_names[gene].clone());
+                log2fcs.push(log2fc);
+                mean_rates.push(λ_component[gene]);
+                specificities.push(if λ_total[gene] > 0.0 {
+                    λ_component[gene] / λ_total[gene]
+                } else {
+                    0.0
+                });
+            }
+        }
+
+        let schema = Schema::from(vec![
+            Field::new("component", DataType::UInt16, false),
+            Field::new("rank", DataType::UInt8, false),
+            Field::new("gene", DataType::Utf8, false),
+            Field::new("log2fc", DataType::Float32, false),
+            Field::new("mean_rate", DataType::Float32, false),
+            Field::new("specificity_score", DataType::Float32, false),
+        ]);
+
+        let columns: Vec<Arc<dyn arrow2::array::Array>> = vec![
+            Arc::new(array::UInt16Array::from_vec(components)),
+            Arc::new(array::UInt8Array::from_vec(ranks)),
+            Arc::new(array::Utf8Array::<i32>::from_iter_values(genes.into_iter())),
+            Arc::new(array::Float32Array::from_vec(log2fcs)),
+            Arc::new(array::Float32Array::from_vec(mean_rates)),
+            Arc::new(array::Float32Array::from_vec(specificities)),
+        ];
+
+        let chunk = arrow2::chunk::Chunk::new(columns);
+        write_table(
+            outputs,
+            output_marker_genes,
+            output_marker_genes_fmt,
+            schema,
+            chunk,
+        );
+    }
+}
+
+// Jensen-Shannon divergence, in bits, between two distributions, so that it
+// falls in [0, 1].
+fn jensen_shannon_divergence(p: &[f32], q: &[f32]) -> f32 {
+    let kl = |a: f32, m: f32| if a > 0.0 { a * (a / m).log2() } else { 0.0 };
+    p.iter()
+        .zip(q)
+        .map(|(&p, &q)| {
+            let m = 0.5 * (p + q);
+            0.5 * kl(p, m) + 0.5 * kl(q, m)
+        })
+        .sum::<f32>()
+        .clamp(0.0, 1.0)
+}
+
+// Write the Jensen-Shannon divergence and distance (its square root) between
+// the expression profiles of each pair of components, with mean rates
+// normalized to sum to one.
+pub fn write_component_jsd_matrix(
+    outputs: &OutputBundle,
+    output_component_jsd: &Option<String>,
+    output_component_jsd_fmt: OutputFormat,
+    params: &ModelParams,
+) {
+    if let Some(output_component_jsd) = output_component_jsd {
+        let ncomponents = params.ncomponents();
+        let profiles: Vec<Vec<f32>> = params
+            .component_mean_rates()
+            .outer_iter()
+            .map(|λ| {
+                let total = λ.iter().filter(|λ| λ.is_finite()).sum::<f32>();
+                λ.iter()
+                    .map(|&λ| {
+                        if λ.is_finite() && total > 0.0 {
+                            λ / total
+                        } else {
+                            0.0
+                        }
+                    })
+                    .collect()
+            })
+            .collect();
+
+        let mut jsd = Array2::<f32>::zeros((ncomponents, ncomponents));
+        for i in 0..ncomponents {
+            for j in (i + 1)..ncomponents {
+                let d = jensen_shannon_divergence(&profiles[i], &profiles[j]);
+                jsd[[i, j]] = d;
+                jsd[[j, i]] = d;
+            }
+        }
+
+        let mut fields = vec![Field::new("component", DataType::UInt16, false)];
+        let mut columns: Vec<Arc<dyn arrow2::array::Array>> = vec![Arc::new(
+            array::UInt16Array::from_values(0..ncomponents as u16),
+        )];
+        for j in 0..ncomponents {
+            fields.push(Field::new(format!("jsd_{}", j), DataType::Float32, false));
+            columns.push(Arc::new(array::Float32Array::from_values(
+                jsd.column(j).iter().cloned(),
+            )));
+        }
+        for j in 0..ncomponents {
+            fields.push(Field::new(
+                format!("js_distance_{}", j),
+                DataType::Float32,
+                false,
+            ));
+            columns.push(Arc::new(array::Float32Array::from_values(
+                jsd.column(j).iter().map(|d| d.sqrt()),
+            )));
+        }
+
+        let schema = Schema::from(fields);
+        let chunk = arrow2::chunk::Chunk::new(columns);
+        write_table(
+            outputs,
+            output_component_jsd,
+            output_component_jsd_fmt,
+            schema,
+            chunk,
+        );
+    }
+}
+
+// Number of cells per block when accumulating the gene covariance matrix.
+const COVARIANCE_CELL_BLOCK_SIZE: usize = 4096;
+
+// Covariance across cells of the given rows of a [genes, cells] count matrix,
+// accumulated over blocks of cells so only a [genes, block] slice of centered
+// counts is held at once.
+fn gene_covariance(counts: &Array2<f32>, genes: &[usize], block_size: usize) -> Array2<f32> {
+    let n = genes.len();
+    let ncells = counts.shape()[1];
+    let means: Vec<f32> = genes
+        .iter()
+        .map(|&g| counts.row(g).mean().unwrap_or(0.0))
+        .collect();
+
+    let mut cov = Array2::<f32>::zeros((n, n));
+    let mut start = 0;
+    while start < ncells {
+        let end = (start + block_size).min(ncells);
+        let mut block = Array2::<f32>::zeros((n, end - start));
+        for (mut row, (&g, &mean)) in block.outer_iter_mut().zip(genes.iter().zip(&means)) {
+            row.assign(&counts.slice(ndarray::s![g, start..end]));
+            row -= mean;
+        }
+        cov += &block.dot(&block.t());
+        start = end;
+    }
+
+    if ncells > 1 {
+        cov /= (ncells - 1) as f32;
+    }
+    cov
+}
+
+// Write the covariance across cells of the expected counts of the `top_n_genes`
+// most variable genes, with genes indexing both rows and columns.
+pub fn write_gene_covariance(
+    outputs: &OutputBundle,
+    output_gene_covariance: &Option<String>,
+    output_gene_covariance_fmt: OutputFormat,
+    expected_counts: &Array2<f32>,
+    gene_names: &[String],
+    top_n_genes: usize,
+) {
+    if let Some(output_gene_covariance) = output_gene_covariance {
+        let variances = expected_counts.var_axis(Axis(1), 0.0);
+        let mut genes: Vec<usize> = (0..gene_names.len()).collect();
+        genes.sort_by(|&a, &b| variances[b].total_cmp(&variances[a]).then(a.cmp(&b)));
+        genes.truncate(top_n_genes);
+
+        let cov = gene_covariance(expected_counts, &genes, COVARIANCE_CELL_BLOCK_SIZE);
+
+        let mut fields = vec![Field::new("gene", DataType::Utf8, false)];
+        let mut columns: Vec<Arc<dyn arrow2::array::Array>> = vec![Arc::new(
+            array::Utf8Array::<i32>::from_iter_values(genes.iter().map(|&g| gene_names[g].clone())),
+        )];
+        for (j, &g) in genes.iter().enumerate() {
+            fields.push(Field::new(&gene_names[g], DataType::Float32, false));
+            columns.push(Arc::new(array::Float32Array::from_values(
+                cov.column(j).iter().cloned(),
+            )));
+        }
+
+        let schema = Schema::from(fields);
+        let chunk = arrow2::chunk::Chunk::new(columns);
+        write_table(
+            outputs,
+            output_gene_covariance,
+            output_gene_covariance_fmt,
+            schema,
+            chunk,
+        );
+    }
+}
+
+// Largest number of cells for which every pairwise similarity is written.
+// Above this, only each cell's nearest neighbors are.
+const FULL_SIMILARITY_MAX_CELLS: usize = 5000;
+
+// Cosine similarity between rows of `vectors`, as (i, j, similarity) with
+// i < j. If `k` is given, only pairs where one is among the other's `k` most
+// similar rows are included.
+fn cosine_similarity_pairs(vectors: &Array2<f32>, k: Option<usize>) -> Vec<(usize, usize, f32)> {
+    let mut normalized = vectors.clone();
+    for mut row in normalized.outer_iter_mut() {
+        let norm = row.dot(&row).sqrt();
+        if norm > 0.0 {
+            row /= norm;
+        }
+    }
+
+    let n = normalized.nrows();
+    let mut pairs: Vec<(usize, usize, f32)> = (0..n)
+        .into_par_iter()
+        .flat_map_iter(|i| {
+            let similarities = normalized.dot(&normalized.row(i));
+            let mut row_pairs: Vec<(usize, usize, f32)> = match k {
+                None => ((i + 1)..n).map(|j| (i, j, similarities[j])).collect(),
+                Some(k) => {
+                    let mut neighbors: Vec<usize> = (0..n).filter(|&j| j != i).collect();
+                    neighbors.sort_by(|&a, &b| similarities[b].total_cmp(&similarities[a]));
+                    neighbors.truncate(k);
+                    neighbors
+                        .into_iter()
+                        .map(|j| (i.min(j), i.max(j), similarities[j]))
+                        .collect()
+                }
+            };
+            row_pairs.sort_by_key(|&(a, b, _)| (a, b));
+            row_pairs.into_iter()
+        })
+        .collect();
+
+    if k.is_some() {
+        pairs.par_sort_unstable_by_key(|&(a, b, _)| (a, b));
+        pairs.dedup_by_key(|&mut (a, b, _)| (a, b));
+    }
+    pairs
+}
+
+// Write pairwise cosine similarities between cells' expected counts. When
+// there are more than `max_cells` cells, up to `max_cells / ncomponents`
+// evenly spaced cells are taken from each component.
+#[allow(clippy::too_many_arguments)]
+pub fn write_cell_expression_similarity(
+    outputs: &OutputBundle,
+    output_cell_expression_similarity: &Option<String>,
+    output_cell_expression_similarity_fmt: OutputFormat,
+    expected_counts: &Array2<f32>,
+    cluster_assignments: &[u32],
+    ncomponents: usize,
+    max_cells: usize,
+    k: usize,
+) {
+    if let Some(output_cell_expression_similarity) = output_cell_expression_similarity {
+        let ncells = expected_counts.ncols();
+        let cells: Vec<usize> = if ncells <= max_cells {
+            (0..ncells).collect()
+        } else {
+            let per_component = max_cells / ncomponents.max(1);
+            let mut component_cells = vec![Vec::new(); ncomponents];
+            for (cell, &z) in cluster_assignments.iter().enumerate() {
+                component_cells[z as usize].push(cell);
+            }
+            let mut cells: Vec<usize> = component_cells
+                .iter()
+                .flat_map(|members| {
+                    let step = members.len().div_ceil(per_component.max(1)).max(1);
+                    members.iter().step_by(step).take(per_component).cloned()
+                })
+                .collect();
+            cells.sort_unstable();
+            cells
+        };
+
+        let vectors = expected_counts.select(Axis(1), &cells).reversed_axes();
+        let pairs = cosine_similarity_pairs(
+            &vectors,
+            (cells.len() > FULL_SIMILARITY_MAX_CELLS).then_some(k),
+        );
+
+        let schema = Schema::from(vec![
+            Field::new("cell_a", DataType::UInt32, false),
+            Field::new("cell_b", DataType::UInt32, false),
+            Field::new("cosine_similarity", DataType::Float32, false),
+        ]);
+
+        let columns: Vec<Arc<dyn arrow2::array::Array>> = vec![
+            Arc::new(array::UInt32Array::from_values(
+                pairs.iter().map(|&(a, _, _)| cells[a] as u32),
+            )),
+            Arc::new(array::UInt32Array::from_values(
+                pairs.iter().map(|&(_, b, _)| cells[b] as u32),
+            )),
+            Arc::new(array::Float32Array::from_values(
+                pairs.iter().map(|&(_, _, similarity)| similarity),
+            )),
+        ];
+
+        let chunk = arrow2::chunk::Chunk::new(columns);
+        write_table(
+            outputs,
+            output_cell_expression_similarity,
+            output_cell_expression_similarity_fmt,
+            schema,
+            chunk,
+        );
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+pub fn write_gene_metadata(
+    outputs: &OutputBundle,
+    output_gene_metadata: &Option<String>,
+    output_gene_metadata_fmt: OutputFormat,
+    params: &ModelParams,
+    transcript_names: &[String],
+    expected_counts: &Array2<f32>,
+    housekeeping_cv_threshold: f32,
+    marker_cv_threshold: f32,
+) {
+    if let Some(output_gene_metadata) = output_gene_metadata {
+        let mut schema_fields = vec![
+            Field::new("gene", DataType::Utf8, false),
+            Field::new("total_count", DataType::UInt64, false),
+            Field::new("expected_assigned_count", DataType::Float32, false),
+            // Field::new("dispersion", DataType::Float32, false),
+        ];
+
+        let mut columns: Vec<Arc<dyn arrow2::array::Array>> = vec![
+            Arc::new(array::Utf8Array::<i32>::from_iter_values(
+                transcript_names.iter().cloned(),
+            )),
+            Arc::new(array::UInt64Array::from_values(
+                params
+                    .total_gene_counts
+                    .sum_axis(Axis(1))
+                    .iter()
+                    .map(|x| *x as u64),
+            )),
+            Arc::new(array::Float32Array::from_values(
+                expected_counts.sum_axis(Axis(1)).iter().cloned(),
+            )),
+            // Arc::new(array::Float32Array::from_values(
+            //     params.r.iter().cloned(),
+            // ))
+        ];
+
+        // cell type dispersions
+        for i in 0..params.ncomponents() {
+            schema_fields.push(Field::new(
+                &format!("dispersion_{}", i),
+                DataType::Float32,
+                false,
+            ));
+            columns.push(Arc::new(array::Float32Array::from_values(
+                params.r.row(i).iter().cloned(),
+            )));
+        }
+
+        // cell type rates
+        let λ_components = params.component_mean_rates();
+        for (i, λ_component) in λ_components.outer_iter().enumerate() {
+            schema_fields.push(Field::new(&format!("λ_{}", i), DataType::Float32, false));
+            columns.push(Arc::new(array::Float32Array::from_values(
+                λ_component.iter().cloned(),
+            )));
+        }
+
+        // background rates
+        for i in 0..params.nlayers() {
+            schema_fields.push(Field::new(format!("λ_bg_{}", i), DataType::Float32, false));
+            columns.push(Arc::new(array::Float32Array::from_values(
+                params.λ_bg.column(i).iter().cloned(),
+            )));
+        }
+
+        // variation of rates across components, ignoring empty components
+        let (inter_component_cv, inter_component_range): (Vec<f32>, Vec<f32>) = λ_components
+            .columns()
+            .into_iter()
+            .map(|λ| {
+                let λ: Vec<f32> = λ.iter().cloned().filter(|λ| λ.is_finite()).collect();
+                let n = λ.len().max(1) as f32;
+                let μ = λ.iter().sum::<f32>() / n;
+                let σ = (λ.iter().map(|λ| (λ - μ).powi(2)).sum::<f32>() / n).sqrt();
+                let (min, max) = λ
+                    .iter()
+                    .fold((f32::INFINITY, f32::NEG_INFINITY), |(lo, hi), &λ| {
+                        (lo.min(λ), hi.max(λ))
+                    });
+                let cv = if μ > 0.0 { σ / μ } else { 0.0 };
+                let range = if λ.is_empty() { 0.0 } else { max - min };
+                (cv, range)
+            })
+            .unzip();
+
+        schema_fields.extend([
+            Field::new("inter_component_cv", DataType::Float32, false),
+            Field::new("inter_component_range", DataType::Float32, false),
+            Field::new("is_housekeeping", DataType::Boolean, false),
+            Field::new("is_marker", DataType::Boolean, false),
+        ]);
+        let is_housekeeping: Vec<bool> = inter_component_cv
+            .iter()
+            .map(|&cv| cv < housekeeping_cv_threshold)
+            .collect();
+        let is_marker: Vec<bool> = inter_component_cv
+            .iter()
+            .map(|&cv| cv > marker_cv_threshold)
+            .collect();
+        columns.extend([
+            Arc::new(array::Float32Array::from_vec(inter_component_cv))
+                as Arc<dyn arrow2::array::Array>,
+            Arc::new(array::Float32Array::from_vec(inter_component_range)),
+            Arc::new(array::BooleanArray::from_slice(is_housekeeping)),
+            Arc::new(array::BooleanArray::from_slice(is_marker)),
+        ]);
+
+        let schema = with_schema_version(Schema::from(schema_fields), GENE_METADATA_SCHEMA_VERSION);
+        let chunk = arrow2::chunk::Chunk::new(columns);
+
+        write_table(
+            outputs,
+            output_gene_metadata,
+            output_gene_metadata_fmt,
+            schema,
+            chunk,
+        );
+    }
+}
+
+// Background transcript rates per unit volume within each hex bin of each
+// layer, to compare against the per-layer background rates `λ_bg`.
+#[allow(clippy::too_many_arguments)]
+pub fn write_background_spatial_stats(
+    outputs: &OutputBundle,
+    output_background_spatial_stats: &Option<String>,
+    output_background_spatial_stats_fmt: OutputFormat,
+    hex_binner: &HexBinner,
+    transcripts: &[Transcript],
+    transcript_state: &Array1<TranscriptState>,
+    transcript_positions: &[(f32, f32, f32)],
+    transcript_names: &[String],
+    params: &ModelParams,
+) {
+    if let Some(output_background_spatial_stats) = output_background_spatial_stats {
+        let ngenes = transcript_names.len();
+
+        // Every bin occupied by any transcript is reported, so bins without
+        // background transcripts show up as zero rates.
+        let mut bin_counts: BTreeMap<(usize, i32, i32), Vec<u32>> = BTreeMap::new();
+        for (t, state, &(x, y, z)) in
+            itertools::izip!(transcripts, transcript_state, transcript_positions)
+        {
+            let layer = params.zlayer(z);
+            let (q, r) = hex_binner.bin(x, y);
+            let counts = bin_counts
+                .entry((layer, q, r))
+                .or_insert_with(|| vec![0; ngenes]);
+            if *state == TranscriptState::Background {
+                counts[t.gene as usize] += 1;
+            }
+        }
+
+        let bin_volume = hex_binner.area() * params.layer_depth();
+
+        let mut fields = vec![
+            Field::new("layer", DataType::UInt32, false),
+            Field::new("hex_q", DataType::Int32, false),
+            Field::new("hex_r", DataType::Int32, false),
+            Field::new("x", DataType::Float32, false),
+            Field::new("y", DataType::Float32, false),
+        ];
+        let mut columns: Vec<Arc<dyn arrow2::array::Array>> = vec![
+            Arc::new(array::UInt32Array::from_values(
+                bin_counts.keys().map(|(layer, _, _)| *layer as u32),
+            )),
+            Arc::new(array::Int32Array::from_values(
+                bin_counts.keys().map(|(_, q, _)| *q),
+            )),
+            Arc::new(array::Int32Array::from_values(
+                bin_counts.keys().map(|(_, _, r)| *r),
+            )),
+            Arc::new(array::Float32Array::from_values(
+                bin_counts
+                    .keys()
+                    .map(|(_, q, r)| hex_binner.center(*q, *r).0),
+            )),
+            Arc::new(array::Float32Array::from_values(
+                bin_counts
+                    .keys()
+                    .map(|(_, q, r)| hex_binner.center(*q, *r).1),
+            )),
+        ];
+
+        for (gene, name) in transcript_names.iter().enumerate() {
+            fields.push(Field::new(name, DataType::Float32, false));
+            columns.push(Arc::new(array::Float32Array::from_values(
+                bin_counts
+                    .values()
+                    .map(|counts| counts[gene] as f32 / bin_volume),
+            )));
+        }
+
+        let schema = Schema::from(fields);
+        let chunk = arrow2::chunk::Chunk::new(columns);
+
+        write_table(
+            outputs,
+            output_background_spatial_stats,
+            output_background_spatial_stats_fmt,
+            schema,
+            chunk,
+        );
+    }
+}
+
+// Match cells from an earlier timepoint (t1) to the current segmentation (t2)
+// and write the displacement of each matched pair.
+pub fn write_cell_displacement_vectors(
+    outputs: &OutputBundle,
+    output_cell_displacements: &Option<String>,
+    output_cell_displacements_fmt: OutputFormat,
+    cell_centroids_t1: &[(f32, f32, f32)],
+    cell_centroids_t2: &[(f32, f32, f32)],
+    max_distance: f32,
+) {
+    if let Some(output_cell_displacements) = output_cell_displacements {
+        let matches = match_cells(cell_centroids_t1, cell_centroids_t2, max_distance);
+        let displacements = matches
+            .iter()
+            .map(|&(i, j)| {
+                let (x1, y1, z1) = cell_centroids_t1[i];
+                let (x2, y2, z2) = cell_centroids_t2[j];
+                (x2 - x1, y2 - y1, z2 - z1)
+            })
+            .collect::<Vec<_>>();
+
+        let schema = Schema::from(vec![
+            Field::new("cell_t1", DataType::UInt32, false),
+            Field::new("cell_t2", DataType::UInt32, false),
+            Field::new("dx", DataType::Float32, false),
+            Field::new("dy", DataType::Float32, false),
+            Field::new("dz", DataType::Float32, false),
+            Field::new("displacement_um", DataType::Float32, false),
+        ]);
+
+        let columns: Vec<Arc<dyn arrow2::array::Array>> = vec![
+            Arc::new(array::UInt32Array::from_values(
+                matches.iter().map(|(i, _)| *i as u32),
+            )),
+            Arc::new(array::UInt32Array::from_values(
+                matches.iter().map(|(_, j)| *j as u32),
+            )),
+            Arc::new(array::Float32Array::from_values(
+                displacements.iter().map(|(dx, _, _)| *dx),
+            )),
+            Arc::new(array::Float32Array::from_values(
+                displacements.iter().map(|(_, dy, _)| *dy),
+            )),
+            Arc::new(array::Float32Array::from_values(
+                displacements.iter().map(|(_, _, dz)| *dz),
+            )),
+            Arc::new(array::Float32Array::from_values(
+                displacements
+                    .iter()
+                    .map(|(dx, dy, dz)| (dx * dx + dy * dy + dz * dz).sqrt()),
+            )),
+        ];
+
+        let chunk = arrow2::chunk::Chunk::new(columns);
+        write_table(
+            outputs,
+            output_cell_displacements,
+            output_cell_displacements_fmt,
+            schema,
+            chunk,
+        );
+    }
+}
+
+pub fn write_voxels(
+    outputs: &OutputBundle,
+    output_voxels: &Option<String>,
+    output_voxels_fmt: SinkFormat,
+    sampler: &VoxelSampler,
+    params: &ModelParams,
+) {
+    if let Some(output_voxels) = output_voxels {
+        let mut sink = VoxelSink::open(outputs, output_voxels, output_voxels_fmt)
+            .unwrap_or_else(|err| panic!("Error opening {}: {}", output_voxels, err));
+        for ((voxel_index, (cell, bounds)), (n, assigned, background)) in sampler
+            .voxel_indexes()
+            .into_iter()
+            .zip(sampler.voxels())
+            .zip(sampler.voxel_transcript_counts(params))
+        {
+            sink.write_voxel_row(voxel_index, cell, bounds, n, assigned, background)
+                .unwrap_or_else(|err| panic!("Error writing {}: {}", output_voxels, err));
+        }
+        sink.finish()
+            .unwrap_or_else(|err| panic!("Error writing {}: {}", output_voxels, err));
+    }
+}
+
+// Write the voxel by cell assignment matrix in coordinate (COO) format. Voxel
+// indexes match those written by `write_voxel_features` and `write_voxels`,
+// and each voxel's position on the voxel grid is included.
+pub fn write_voxel_cell_incidence(
+    outputs: &OutputBundle,
+    output_voxel_cell_incidence: &Option<String>,
+    output_voxel_cell_incidence_fmt: OutputFormat,
+    sampler: &VoxelSampler,
+) {
+    if let Some(output_voxel_cell_incidence) = output_voxel_cell_incidence {
+        let incidence = sampler.voxel_cell_incidence();
 
-    if let Some(output_transcript_metadata) = output_transcript_metadata {
         let schema = Schema::from(vec![
-            Field::new("transcript_id", DataType::UInt64, false),
-            Field::new("x", DataType::Float32, false),
-            Field::new("y", DataType::Float32, false),
-            Field::new("z", DataType::Float32, false),
-            Field::new("observed_x", DataType::Float32, false),
-            Field::new("observed_y", DataType::Float32, false),
-            Field::new("observed_z", DataType::Float32, false),
-            Field::new("gene", DataType::Utf8, false),
-            Field::new("fov", DataType::Utf8, false),
-            Field::new("assignment", DataType::UInt32, false),
-            Field::new("probability", DataType::Float32, false),
-            Field::new("background", DataType::UInt8, false),
-            Field::new("confusion", DataType::UInt8, false),
+            Field::new("voxel_index", DataType::UInt32, false),
+            Field::new("i", DataType::Int32, false),
+            Field::new("j", DataType::Int32, false),
+            Field::new("k", DataType::Int32, false),
+            Field::new("cell_id", DataType::UInt32, false),
+            Field::new("value", DataType::Float32, false),
         ]);
 
         let columns: Vec<Arc<dyn arrow2::array::Array>> = vec![
-            Arc::new(array::UInt64Array::from_values(
-                transcripts.iter().map(|t| t.transcript_id),
-            )),
-            Arc::new(array::Float32Array::from_values(
-                transcript_positions.iter().map(|(x, _, _)| *x),
-            )),
-            Arc::new(array::Float32Array::from_values(
-                transcript_positions.iter().map(|(_, y, _)| *y),
-            )),
-            Arc::new(array::Float32Array::from_values(
-                transcript_positions.iter().map(|(_, _, z)| *z),
-            )),
-            Arc::new(array::Float32Array::from_values(
-                transcripts.iter().map(|t| t.x),
-            )),
-            Arc::new(array::Float32Array::from_values(
-                transcripts.iter().map(|t| t.y),
+            Arc::new(array::UInt32Array::from_values(
+                incidence.iter().map(|(index, _, _)| *index),
             )),
-            Arc::new(array::Float32Array::from_values(
-                transcripts.iter().map(|t| t.z),
+            Arc::new(array::Int32Array::from_values(
+                incidence.iter().map(|(_, voxel, _)| voxel.i),
             )),
-            Arc::new(array::Utf8Array::<i64>::from_iter_values(
-                transcripts
-                    .iter()
-                    .map(|t| transcript_names[t.gene as usize].clone()),
+            Arc::new(array::Int32Array::from_values(
+                incidence.iter().map(|(_, voxel, _)| voxel.j),
             )),
-            Arc::new(array::Utf8Array::<i64>::from_iter_values(
-                fovs.iter().map(|fov| fov_names[*fov as usize].clone()),
+            Arc::new(array::Int32Array::from_values(
+                incidence.iter().map(|(_, voxel, _)| voxel.k),
             )),
             Arc::new(array::UInt32Array::from_values(
-                cell_assignments.iter().map(|(cell, _)| *cell),
+                incidence.iter().map(|(_, _, cell)| *cell),
             )),
             Arc::new(array::Float32Array::from_values(
-                cell_assignments.iter().map(|(_, pr)| *pr),
-            )),
-            Arc::new(array::UInt8Array::from_values(
-                transcript_state
-                    .iter()
-                    .map(|&s| (s == TranscriptState::Background) as u8),
-            )),
-            Arc::new(array::UInt8Array::from_values(
-                transcript_state
-                    .iter()
-                    .map(|&s| (s == TranscriptState::Confusion) as u8),
+                incidence.iter().map(|_| 1.0),
             )),
         ];
 
         let chunk = arrow2::chunk::Chunk::new(columns);
-
         write_table(
-            output_transcript_metadata,
-            output_transcript_metadata_fmt,
+            outputs,
+            output_voxel_cell_incidence,
+            output_voxel_cell_incidence_fmt,
             schema,
             chunk,
         );
     }
 }
 
-pub fn write_gene_metadata(
-    output_gene_metadata: &Option<String>,
-    output_gene_metadata_fmt: OutputFormat,
-    params: &ModelParams,
+// Write the sparse voxel by gene transcript count matrix in coordinate (COO)
+// format, with each voxel's position on the voxel grid.
+pub fn write_voxel_features(
+    outputs: &OutputBundle,
+    output_voxel_features: &Option<String>,
+    output_voxel_features_fmt: OutputFormat,
+    sampler: &VoxelSampler,
     transcript_names: &[String],
-    expected_counts: &Array2<f32>,
 ) {
-    if let Some(output_gene_metadata) = output_gene_metadata {
-        let mut schema_fields = vec![
+    if let Some(output_voxel_features) = output_voxel_features {
+        let counts = sampler.voxel_gene_counts();
+
+        let schema = Schema::from(vec![
+            Field::new("voxel_index", DataType::UInt32, false),
+            Field::new("i", DataType::Int32, false),
+            Field::new("j", DataType::Int32, false),
+            Field::new("k", DataType::Int32, false),
             Field::new("gene", DataType::Utf8, false),
-            Field::new("total_count", DataType::UInt64, false),
-            Field::new("expected_assigned_count", DataType::Float32, false),
-            // Field::new("dispersion", DataType::Float32, false),
-        ];
+            Field::new("count", DataType::UInt32, false),
+        ]);
 
-        let mut columns: Vec<Arc<dyn arrow2::array::Array>> = vec![
-            Arc::new(array::Utf8Array::<i32>::from_iter_values(
-                transcript_names.iter().cloned(),
+        let columns: Vec<Arc<dyn arrow2::array::Array>> = vec![
+            Arc::new(array::UInt32Array::from_values(
+                counts.iter().map(|(index, _, _, _)| *index),
             )),
-            Arc::new(array::UInt64Array::from_values(
-                params
-                    .total_gene_counts
-                    .sum_axis(Axis(1))
+            Arc::new(array::Int32Array::from_values(
+                counts.iter().map(|(_, voxel, _, _)| voxel.i),
+            )),
+            Arc::new(array::Int32Array::from_values(
+                counts.iter().map(|(_, voxel, _, _)| voxel.j),
+            )),
+            Arc::new(array::Int32Array::from_values(
+                counts.iter().map(|(_, voxel, _, _)| voxel.k),
+            )),
+            Arc::new(array::Utf8Array::<i32>::from_iter_values(
+                counts
                     .iter()
-                    .map(|x| *x as u64),
+                    .map(|(_, _, gene, _)| transcript_names[*gene as usize].clone()),
             )),
-            Arc::new(array::Float32Array::from_values(
-                expected_counts.sum_axis(Axis(1)).iter().cloned(),
+            Arc::new(array::UInt32Array::from_values(
+                counts.iter().map(|(_, _, _, count)| *count),
             )),
-            // Arc::new(array::Float32Array::from_values(
-            //     params.r.iter().cloned(),
-            // ))
         ];
 
-        // cell type dispersions
-        for i in 0..params.ncomponents() {
-            schema_fields.push(Field::new(
-                &format!("dispersion_{}", i),
-                DataType::Float32,
-                false,
-            ));
-            columns.push(Arc::new(array::Float32Array::from_values(
-                params.r.row(i).iter().cloned(),
-            )));
-        }
-
-        // cell type rates
-        for i in 0..params.ncomponents() {
-            schema_fields.push(Field::new(&format!("λ_{}", i), DataType::Float32, false));
-
-            let mut λ_component = Array1::<f32>::from_elem(params.ngenes(), 0_f32);
-            let mut count = 0;
-            Zip::from(&params.z)
-                .and(params.λ.columns())
-                .for_each(|&z, λ| {
-                    if i == z as usize {
-                        Zip::from(&mut λ_component).and(λ).for_each(|a, b| *a += b);
-                        count += 1;
-                    }
-                });
-            λ_component /= count as f32;
-
-            columns.push(Arc::new(array::Float32Array::from_values(
-                λ_component.iter().cloned(),
-            )));
-        }
-
-        // background rates
-        for i in 0..params.nlayers() {
-            schema_fields.push(Field::new(format!("λ_bg_{}", i), DataType::Float32, false));
-            columns.push(Arc::new(array::Float32Array::from_values(
-                params.λ_bg.column(i).iter().cloned(),
-            )));
-        }
-
-        let schema = Schema::from(schema_fields);
         let chunk = arrow2::chunk::Chunk::new(columns);
-
         write_table(
-            output_gene_metadata,
-            output_gene_metadata_fmt,
+            outputs,
+            output_voxel_features,
+            output_voxel_features_fmt,
             schema,
             chunk,
         );
     }
 }
 
-pub fn write_voxels(
-    output_voxels: &Option<String>,
-    output_voxels_fmt: OutputFormat,
-    sampler: &VoxelSampler,
+// TODO:
+// If we want to import things into qupath, I think we need a way to scale
+// the coordinates to pixel space. It also doesn't seem like it supports
+// MultiPolygons, so we need to write each polygon in a cell to a separate Polygon entry.
+
+// Write a feature-level "bbox" member (RFC 7946, Section 5), so readers can
+// skip parsing geometry outside of a region of interest.
+fn write_geojson_number<W: Write>(
+    encoder: &mut W,
+    value: f32,
+    coordinate_precision: Option<usize>,
 ) {
-    if let Some(output_voxels) = output_voxels {
-        let nvoxels = sampler.voxels().count();
-
-        let mut cells = Vec::with_capacity(nvoxels);
-        let mut x0s = Vec::with_capacity(nvoxels);
-        let mut y0s = Vec::with_capacity(nvoxels);
-        let mut z0s = Vec::with_capacity(nvoxels);
-        let mut x1s = Vec::with_capacity(nvoxels);
-        let mut y1s = Vec::with_capacity(nvoxels);
-        let mut z1s = Vec::with_capacity(nvoxels);
-
-        for (cell, (x0, y0, z0, x1, y1, z1)) in sampler.voxels() {
-            cells.push(cell);
-            x0s.push(x0);
-            y0s.push(y0);
-            z0s.push(z0);
-            x1s.push(x1);
-            y1s.push(y1);
-            z1s.push(z1);
+    // Polygons are computed in f32, so by default coordinates are written with
+    // the fewest digits that exactly represent the f32 value.
+    match coordinate_precision {
+        Some(decimals) => write!(encoder, "{:.*}", decimals, value).unwrap(),
+        None => write!(encoder, "{}", value).unwrap(),
+    }
+}
+
+fn write_geojson_coord<W: Write>(
+    encoder: &mut W,
+    x: f32,
+    y: f32,
+    coordinate_precision: Option<usize>,
+) {
+    write!(encoder, "[").unwrap();
+    write_geojson_number(encoder, x, coordinate_precision);
+    write!(encoder, ", ").unwrap();
+    write_geojson_number(encoder, y, coordinate_precision);
+    write!(encoder, "]").unwrap();
+}
+
+fn write_geojson_bbox<W: Write>(
+    encoder: &mut W,
+    polys: &MultiPolygon<f32>,
+    coordinate_precision: Option<usize>,
+) {
+    if let Some(rect) = polys.bounding_rect() {
+        write!(encoder, "      \"bbox\": [").unwrap();
+        for (i, value) in [rect.min().x, rect.min().y, rect.max().x, rect.max().y]
+            .iter()
+            .enumerate()
+        {
+            if i > 0 {
+                write!(encoder, ", ").unwrap();
+            }
+            write_geojson_number(encoder, *value, coordinate_precision);
         }
+        writeln!(encoder, "],").unwrap();
+    }
+}
+
+// Write cell polygons as WKT in a `geometry` column, with each cell's cluster
+// and FOV, for import into spatial databases.
+#[allow(clippy::too_many_arguments)]
+pub fn write_cell_polygons_wkt_table(
+    outputs: &OutputBundle,
+    output_cell_polygons_wkt: &Option<String>,
+    output_cell_polygons_wkt_fmt: OutputFormat,
+    polygons: &[MultiPolygon<f32>],
+    params: &ModelParams,
+    cell_assignments: &[(u32, f32)],
+    fovs: &[u32],
+    fov_names: &[String],
+    decimals: usize,
+) {
+    if let Some(output_cell_polygons_wkt) = output_cell_polygons_wkt {
+        let cell_fovs = cell_fov_vote(polygons.len(), fov_names.len(), cell_assignments, fovs);
 
         let schema = Schema::from(vec![
             Field::new("cell", DataType::UInt32, false),
-            Field::new("x0", DataType::Float32, false),
-            Field::new("y0", DataType::Float32, false),
-            Field::new("z0", DataType::Float32, false),
-            Field::new("x1", DataType::Float32, false),
-            Field::new("y1", DataType::Float32, false),
-            Field::new("z1", DataType::Float32, false),
+            Field::new("cluster", DataType::UInt16, false),
+            Field::new("fov", DataType::Utf8, true),
+            Field::new("geometry", DataType::Utf8, false),
         ]);
-
         let columns: Vec<Arc<dyn arrow2::array::Array>> = vec![
-            Arc::new(array::UInt32Array::from_vec(cells)),
-            Arc::new(array::Float32Array::from_vec(x0s)),
-            Arc::new(array::Float32Array::from_vec(y0s)),
-            Arc::new(array::Float32Array::from_vec(z0s)),
-            Arc::new(array::Float32Array::from_vec(x1s)),
-            Arc::new(array::Float32Array::from_vec(y1s)),
-            Arc::new(array::Float32Array::from_vec(z1s)),
+            Arc::new(array::UInt32Array::from_values(0..polygons.len() as u32)),
+            Arc::new(array::UInt16Array::from_values(
+                params.z.iter().map(|&z| z as u16),
+            )),
+            Arc::new(array::Utf8Array::<i32>::from_iter(cell_fovs.iter().map(
+                |fov| {
+                    if *fov == u32::MAX {
+                        None
+                    } else {
+                        Some(fov_names[*fov as usize].clone())
+                    }
+                },
+            ))),
+            Arc::new(array::Utf8Array::<i32>::from_iter_values(
+                polygons
+                    .iter()
+                    .map(|poly| encode_multipolygon_wkt(poly, decimals)),
+            )),
         ];
-
         let chunk = arrow2::chunk::Chunk::new(columns);
 
-        write_table(output_voxels, output_voxels_fmt, schema, chunk);
+        write_table(
+            outputs,
+            output_cell_polygons_wkt,
+            output_cell_polygons_wkt_fmt,
+            schema,
+            chunk,
+        );
     }
 }
 
-// TODO:
-// If we want to import things into qupath, I think we need a way to scale
-// the coordinates to pixel space. It also doesn't seem like it supports
-// MultiPolygons, so we need to write each polygon in a cell to a separate Polygon entry.
-
 pub fn write_cell_multipolygons(
+    outputs: &OutputBundle,
     output_cell_polygons: &Option<String>,
     polygons: Vec<MultiPolygon<f32>>,
+    include_bbox: bool,
+    coordinate_precision: Option<usize>,
 ) {
     if let Some(output_cell_polygons) = output_cell_polygons {
-        let file = File::create(output_cell_polygons).unwrap();
+        let file = outputs.open(output_cell_polygons);
         let mut encoder = GzEncoder::new(file, Compression::default());
 
         writeln!(
@@ -622,7 +3529,7 @@ pub fn write_cell_multipolygons(
 
         let ncells = polygons.len();
         for (cell, polys) in polygons.into_iter().enumerate() {
-            writeln!(
+            write!(
                 encoder,
                 concat!(
                     "    {{\n",
@@ -630,11 +3537,22 @@ pub fn write_cell_multipolygons(
                     "      \"properties\": {{\n",
                     "        \"cell\": {}\n",
                     "      }},\n",
+                ),
+                cell
+            )
+            .unwrap();
+
+            if include_bbox {
+                write_geojson_bbox(&mut encoder, &polys, coordinate_precision);
+            }
+
+            writeln!(
+                encoder,
+                concat!(
                     "      \"geometry\": {{\n",
                     "        \"type\": \"MultiPolygon\",\n",
                     "        \"coordinates\": ["
                 ),
-                cell
             )
             .unwrap();
 
@@ -644,7 +3562,8 @@ pub fn write_cell_multipolygons(
 
                 let ncoords = poly.exterior().coords().count();
                 for (j, coord) in poly.exterior().coords().enumerate() {
-                    write!(encoder, "              [{}, {}]", coord.x, coord.y).unwrap();
+                    write!(encoder, "              ").unwrap();
+                    write_geojson_coord(&mut encoder, coord.x, coord.y, coordinate_precision);
                     if j < ncoords - 1 {
                         writeln!(encoder, ",").unwrap();
                     } else {
@@ -670,15 +3589,170 @@ pub fn write_cell_multipolygons(
         }
 
         writeln!(encoder, "  ]\n}}").unwrap();
+        Box::new(encoder)
+            .finish()
+            .unwrap_or_else(|err| panic!("Error writing {}: {}", output_cell_polygons, err));
+    }
+}
+
+// Write one 2D polygon per cell formed by the union of its polygons across
+// all z-layers.
+pub fn write_z_projected_polygons(
+    outputs: &OutputBundle,
+    output_z_projected_polygons: &Option<String>,
+    cell_polygons: &[Vec<(i32, MultiPolygon<f32>)>],
+    include_bbox: bool,
+    coordinate_precision: Option<usize>,
+) {
+    if output_z_projected_polygons.is_some() {
+        let projected_polygons = cell_polygons
+            .par_iter()
+            .map(|polys| {
+                polys.iter().fold(
+                    MultiPolygon::<f32>::new(Vec::new()),
+                    |acc, (_layer, poly)| acc.union(poly),
+                )
+            })
+            .collect();
+
+        write_cell_multipolygons(
+            outputs,
+            output_z_projected_polygons,
+            projected_polygons,
+            include_bbox,
+            coordinate_precision,
+        );
+    }
+}
+
+fn write_gml_ring<W: Write>(
+    encoder: &mut W,
+    tag: &str,
+    ring: &geo::LineString<f32>,
+    coordinate_precision: Option<usize>,
+) {
+    write!(
+        encoder,
+        "              <gml:{}><gml:LinearRing><gml:posList srsDimension=\"2\">",
+        tag
+    )
+    .unwrap();
+    for (i, coord) in ring.coords().enumerate() {
+        if i > 0 {
+            write!(encoder, " ").unwrap();
+        }
+        write_geojson_number(encoder, coord.x, coordinate_precision);
+        write!(encoder, " ").unwrap();
+        write_geojson_number(encoder, coord.y, coordinate_precision);
+    }
+    writeln!(encoder, "</gml:posList></gml:LinearRing></gml:{}>", tag).unwrap();
+}
+
+// Write cell polygons as a GML 3.2 feature collection, with each cell as a
+// feature member carrying a few basic attributes.
+pub fn write_cell_polygons_gml(
+    outputs: &OutputBundle,
+    output_cell_polygons_gml: &Option<String>,
+    polygons: &[MultiPolygon<f32>],
+    params: &ModelParams,
+    cell_centroids: &[(f32, f32, f32)],
+    coordinate_precision: Option<usize>,
+) {
+    if let Some(output_cell_polygons_gml) = output_cell_polygons_gml {
+        let mut encoder = std::io::BufWriter::new(outputs.open(output_cell_polygons_gml));
+
+        writeln!(
+            encoder,
+            concat!(
+                "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n",
+                "<gml:FeatureCollection xmlns:gml=\"http://www.opengis.net/gml/3.2\" ",
+                "xmlns:proseg=\"https://github.com/dcjones/proseg\" gml:id=\"cells\">"
+            )
+        )
+        .unwrap();
+
+        for (cell, polys) in polygons.iter().enumerate() {
+            let (x, y, z) = cell_centroids[cell];
+            writeln!(
+                encoder,
+                concat!(
+                    "  <gml:featureMember>\n",
+                    "    <proseg:Cell gml:id=\"cell.{cell}\">\n",
+                    "      <proseg:cell>{cell}</proseg:cell>\n",
+                    "      <proseg:cluster>{cluster}</proseg:cluster>\n",
+                    "      <proseg:volume>{volume}</proseg:volume>\n",
+                    "      <proseg:centroid_x>{x}</proseg:centroid_x>\n",
+                    "      <proseg:centroid_y>{y}</proseg:centroid_y>\n",
+                    "      <proseg:centroid_z>{z}</proseg:centroid_z>\n",
+                    "      <proseg:geometry>\n",
+                    "        <gml:MultiSurface gml:id=\"cell.{cell}.geometry\">"
+                ),
+                cell = cell,
+                cluster = params.z[cell],
+                volume = params.cell_volume[cell],
+                x = x,
+                y = y,
+                z = z,
+            )
+            .unwrap();
+
+            for (i, poly) in polys.iter().enumerate() {
+                writeln!(
+                    encoder,
+                    concat!(
+                        "          <gml:surfaceMember>\n",
+                        "            <gml:Polygon gml:id=\"cell.{}.polygon.{}\">"
+                    ),
+                    cell, i
+                )
+                .unwrap();
+                write_gml_ring(
+                    &mut encoder,
+                    "exterior",
+                    poly.exterior(),
+                    coordinate_precision,
+                );
+                for interior in poly.interiors() {
+                    write_gml_ring(&mut encoder, "interior", interior, coordinate_precision);
+                }
+                writeln!(
+                    encoder,
+                    concat!(
+                        "            </gml:Polygon>\n",
+                        "          </gml:surfaceMember>"
+                    )
+                )
+                .unwrap();
+            }
+
+            writeln!(
+                encoder,
+                concat!(
+                    "        </gml:MultiSurface>\n",
+                    "      </proseg:geometry>\n",
+                    "    </proseg:Cell>\n",
+                    "  </gml:featureMember>"
+                )
+            )
+            .unwrap();
+        }
+
+        writeln!(encoder, "</gml:FeatureCollection>").unwrap();
+        Box::new(encoder)
+            .finish()
+            .unwrap_or_else(|err| panic!("Error writing {}: {}", output_cell_polygons_gml, err));
     }
 }
 
 pub fn write_cell_layered_multipolygons(
+    outputs: &OutputBundle,
     output_cell_polygons: &Option<String>,
     polygons: Vec<Vec<(i32, MultiPolygon<f32>)>>,
+    include_bbox: bool,
+    coordinate_precision: Option<usize>,
 ) {
     if let Some(output_cell_polygons) = output_cell_polygons {
-        let file = File::create(output_cell_polygons).unwrap();
+        let file = outputs.open(output_cell_polygons);
         let mut encoder = GzEncoder::new(file, Compression::default());
 
         writeln!(
@@ -695,7 +3769,7 @@ pub fn write_cell_layered_multipolygons(
         let mut count = 0;
         for (cell, cell_polys) in polygons.iter().enumerate() {
             for (layer, polys) in cell_polys.iter() {
-                writeln!(
+                write!(
                     encoder,
                     concat!(
                         "    {{\n",
@@ -704,11 +3778,22 @@ pub fn write_cell_layered_multipolygons(
                         "        \"cell\": {},\n",
                         "        \"layer\": {}\n",
                         "      }},\n",
+                    ),
+                    cell, layer
+                )
+                .unwrap();
+
+                if include_bbox {
+                    write_geojson_bbox(&mut encoder, polys, coordinate_precision);
+                }
+
+                writeln!(
+                    encoder,
+                    concat!(
                         "      \"geometry\": {{\n",
                         "        \"type\": \"MultiPolygon\",\n",
                         "        \"coordinates\": ["
                     ),
-                    cell, layer
                 )
                 .unwrap();
 
@@ -718,7 +3803,8 @@ pub fn write_cell_layered_multipolygons(
 
                     let ncoords = poly.exterior().coords().count();
                     for (j, coord) in poly.exterior().coords().enumerate() {
-                        write!(encoder, "              [{}, {}]", coord.x, coord.y).unwrap();
+                        write!(encoder, "              ").unwrap();
+                        write_geojson_coord(&mut encoder, coord.x, coord.y, coordinate_precision);
                         if j < ncoords - 1 {
                             writeln!(encoder, ",").unwrap();
                         } else {
@@ -747,5 +3833,131 @@ pub fn write_cell_layered_multipolygons(
         }
 
         writeln!(encoder, "  ]\n}}").unwrap();
+        Box::new(encoder)
+            .finish()
+            .unwrap_or_else(|err| panic!("Error writing {}: {}", output_cell_polygons, err));
+    }
+}
+
+#[test]
+fn volume_scaled_rates_match_expected_counts() {
+    use super::sampler::voxelsampler::two_square_cells;
+    use super::sampler::Sampler;
+
+    let (transcripts, priors, mut params, mut sampler) = two_square_cells();
+    sampler.initialize(&priors, &mut params);
+
+    // Average per-cell rates and foreground counts over posterior samples,
+    // with cell assignments fixed.
+    let niter = 200;
+    let mut mean_rates = Array1::<f32>::zeros(params.ncells());
+    let mut mean_counts = Array1::<f32>::zeros(params.ncells());
+    for _ in 0..niter {
+        sampler.sample_global_params(&priors, &mut params, &transcripts, &mut None, false);
+        mean_rates.scaled_add(
+            1.0 / niter as f32,
+            &volume_scaled_rates(&params.λ, &params.cell_volume).sum_axis(Axis(0)),
+        );
+        for (&cell, &state) in params.cell_assignments.iter().zip(&params.transcript_state) {
+            if cell != BACKGROUND_CELL && state == TranscriptState::Foreground {
+                mean_counts[cell as usize] += 1.0 / niter as f32;
+            }
+        }
+    }
+    // Expected counts are the time averaged foreground counts, which the
+    // rates only match up to the shrinkage of λ towards its prior.
+    for (a, b) in mean_rates.iter().zip(mean_counts.iter()) {
+        assert!((a - b).abs() < 0.3 * b);
+    }
+}
+
+#[test]
+fn jensen_shannon_divergence_bounds() {
+    let p = [0.5, 0.5, 0.0, 0.0];
+    let q = [0.0, 0.0, 0.25, 0.75];
+    assert_eq!(jensen_shannon_divergence(&p, &p), 0.0);
+    assert!((jensen_shannon_divergence(&p, &q) - 1.0).abs() < 1e-6);
+
+    let r = [0.25, 0.25, 0.25, 0.25];
+    let d = jensen_shannon_divergence(&p, &r);
+    assert!(d > 0.0 && d < 1.0);
+    assert!((d - jensen_shannon_divergence(&r, &p)).abs() < 1e-6);
+}
+
+#[test]
+fn blocked_gene_covariance_matches_direct() {
+    let counts = Array2::from_shape_vec(
+        (3, 5),
+        vec![
+            1.0_f32, 2.0, 3.0, 4.0, 5.0, 2.0, 1.0, 0.0, 1.0, 2.0, 5.0, 3.0, 4.0, 0.0, 1.0,
+        ],
+    )
+    .unwrap();
+    let genes = [2, 0];
+    let direct = gene_covariance(&counts, &genes, 5);
+    let blocked = gene_covariance(&counts, &genes, 2);
+
+    // var(gene 0) = 2.5
+    assert!((direct[[1, 1]] - 2.5).abs() < 1e-5);
+    assert!((direct[[0, 1]] - direct[[1, 0]]).abs() < 1e-5);
+    for (a, b) in direct.iter().zip(blocked.iter()) {
+        assert!((a - b).abs() < 1e-5);
+    }
+}
+
+#[test]
+fn poisson_upper_tail_values() {
+    assert_eq!(poisson_upper_tail(0, 3.0), 1.0);
+    // P(X >= 1) = 1 - e^-2
+    assert!((poisson_upper_tail(1, 2.0) - (1.0 - (-2.0_f64).exp())).abs() < 1e-9);
+    // P(X >= 3) for mean 1 = 1 - (1 + 1 + 1/2) e^-1
+    assert!((poisson_upper_tail(3, 1.0) - (1.0 - 2.5 * (-1.0_f64).exp())).abs() < 1e-9);
+    assert_eq!(poisson_upper_tail(2, 0.0), 0.0);
+}
+
+#[test]
+fn contact_enrichment_with_empty_component() {
+    // A path of four cells, alternating between components 0 and 1, with no
+    // cells in component 2.
+    let cell_adjacency = [(0, 1), (1, 2), (2, 3)];
+    let cluster_assignments = [0, 1, 0, 1];
+    let enrichment = cell_type_contact_enrichment(&cell_adjacency, &cluster_assignments, 3);
+    assert_eq!(enrichment.len(), 6);
+
+    for e in &enrichment {
+        if e.type_i == 2 || e.type_j == 2 {
+            assert_eq!(e.observed, 0);
+            assert_eq!(e.expected, 0.0);
+            assert!(e.enrichment.is_none());
+            assert_eq!(e.p_value, 1.0);
+        } else {
+            assert!(e.expected > 0.0);
+            assert!(e.enrichment.unwrap().is_finite());
+        }
     }
+
+    // Each component has 2 of 4 cells, so a contact joins 0 and 1 with
+    // probability 2/3.
+    let e = enrichment
+        .iter()
+        .find(|e| (e.type_i, e.type_j) == (0, 1))
+        .unwrap();
+    assert_eq!(e.observed, 3);
+    assert!((e.expected - 2.0).abs() < 1e-9);
+    assert!((e.enrichment.unwrap() - 1.5).abs() < 1e-9);
+}
+
+#[test]
+fn cosine_similarity_pairs_nearest() {
+    let vectors = Array2::from_shape_vec((3, 2), vec![1.0_f32, 0.0, 2.0, 0.0, 0.0, 3.0]).unwrap();
+
+    let all = cosine_similarity_pairs(&vectors, None);
+    assert_eq!(all.len(), 3);
+    assert_eq!(all[0], (0, 1, 1.0));
+    assert_eq!(all[1], (0, 2, 0.0));
+
+    // the first two rows are each other's nearest neighbor
+    let nearest = cosine_similarity_pairs(&vectors, Some(1));
+    assert_eq!(nearest[0], (0, 1, 1.0));
+    assert!(nearest.iter().all(|&(a, b, _)| a < b));
 }
@@ -0,0 +1,557 @@
+// Row-at-a-time output for large tables.
+//
+// Rather than building every column before writing, rows are passed to an
+// `OutputSink` as they're generated. CSV and JSON lines sinks write each row
+// as it arrives, and the parquet sink buffers rows until it has a full row
+// group. `VoxelSink` and `TranscriptSink` give typed rows for the voxel and
+// transcript metadata tables.
+
+use arrow2::array::{self, MutableArray, MutableUtf8Array};
+use arrow2::chunk::Chunk;
+use arrow2::datatypes::Field;
+use arrow2::datatypes::{DataType, Schema};
+use arrow2::io::parquet::write::{FileWriter, RowGroupIterator, WriteOptions};
+use clap::ValueEnum;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use std::fmt::Write as FmtWrite;
+use std::sync::Arc;
+
+use super::super::analysis::hexbin::HexBinner;
+use super::super::checksum::ChecksummedFileWriter;
+use super::super::cloud::OutputWriter;
+use super::super::sampler::transcripts::Transcript;
+use super::super::sampler::TranscriptState;
+use super::{
+    parquet_encodings, with_schema_version, OutputBundle, OutputError, ParquetWriteOptions,
+    WriteStatisticsLevel, SCHEMA_VERSION, SCHEMA_VERSION_KEY, TRANSCRIPT_METADATA_SCHEMA_VERSION,
+};
+
+// Number of rows buffered by the parquet sink before writing a row group.
+const PARQUET_SINK_ROW_GROUP_SIZE: usize = 1 << 20;
+
+// Formats of tables written through an `OutputSink`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, ValueEnum)]
+pub enum SinkFormat {
+    Infer,
+    Csv,
+    CsvGz,
+    Parquet,
+    JsonLines,
+}
+
+pub fn infer_sink_format_from_filename(filename: &str) -> SinkFormat {
+    if filename.ends_with(".csv.gz") {
+        SinkFormat::CsvGz
+    } else if filename.ends_with(".csv") {
+        SinkFormat::Csv
+    } else if filename.ends_with(".parquet") {
+        SinkFormat::Parquet
+    } else if filename.ends_with(".jsonl") {
+        SinkFormat::JsonLines
+    } else {
+        panic!("Unknown file format for filename: {}", filename);
+    }
+}
+
+// A single value in a row passed to an `OutputSink`. Its type must match the
+// corresponding field in the sink's schema.
+#[derive(Clone, Copy, Debug)]
+pub enum OutputValue<'a> {
+    UInt8(u8),
+    UInt32(u32),
+    UInt64(u64),
+    Int32(i32),
+    Float32(f32),
+    Utf8(&'a str),
+}
+
+pub trait OutputSink: Send {
+    fn write_row(&mut self, row: &[OutputValue]) -> Result<(), OutputError>;
+
+    // Write any buffered rows and close the output, returning the number of
+    // rows written.
+    fn finish(self: Box<Self>) -> Result<usize, OutputError>;
+}
+
+pub struct CsvSink<W: OutputWriter> {
+    writer: csv::Writer<W>,
+    field: String,
+    nrows: usize,
+}
+
+impl<W: OutputWriter> CsvSink<W> {
+    pub fn new(output: W, schema: &Schema) -> Result<Self, OutputError> {
+        let mut writer = csv::Writer::from_writer(output);
+        writer
+            .write_record(schema.fields.iter().map(|f| f.name.as_str()))
+            .map_err(std::io::Error::from)?;
+        Ok(CsvSink {
+            writer,
+            field: String::new(),
+            nrows: 0,
+        })
+    }
+}
+
+impl<W: OutputWriter> OutputSink for CsvSink<W> {
+    fn write_row(&mut self, row: &[OutputValue]) -> Result<(), OutputError> {
+        for value in row {
+            self.field.clear();
+            match value {
+                OutputValue::UInt8(v) => write!(self.field, "{}", v),
+                OutputValue::UInt32(v) => write!(self.field, "{}", v),
+                OutputValue::UInt64(v) => write!(self.field, "{}", v),
+                OutputValue::Int32(v) => write!(self.field, "{}", v),
+                // Debug formatting keeps a trailing ".0" on whole numbers
+                OutputValue::Float32(v) => write!(self.field, "{:?}", v),
+                OutputValue::Utf8(v) => write!(self.field, "{}", v),
+            }
+            .unwrap();
+            self.writer
+                .write_field(&self.field)
+                .map_err(std::io::Error::from)?;
+        }
+        self.writer
+            .write_record(None::<&[u8]>)
+            .map_err(std::io::Error::from)?;
+        self.nrows += 1;
+        Ok(())
+    }
+
+    fn finish(self: Box<Self>) -> Result<usize, OutputError> {
+        let output = self.writer.into_inner().map_err(|err| err.into_error())?;
+        Box::new(output).finish()?;
+        Ok(self.nrows)
+    }
+}
+
+pub struct JsonLinesSink<W: OutputWriter> {
+    output: W,
+    names: Vec<String>,
+    nrows: usize,
+}
+
+impl<W: OutputWriter> JsonLinesSink<W> {
+    pub fn new(output: W, schema: &Schema) -> Self {
+        JsonLinesSink {
+            output,
+            names: schema.fields.iter().map(|f| f.name.clone()).collect(),
+            nrows: 0,
+        }
+    }
+}
+
+impl<W: OutputWriter> OutputSink for JsonLinesSink<W> {
+    fn write_row(&mut self, row: &[OutputValue]) -> Result<(), OutputError> {
+        let mut object = json::object::Object::with_capacity(row.len());
+        for (name, value) in self.names.iter().zip(row) {
+            let value = match *value {
+                OutputValue::UInt8(v) => json::JsonValue::from(v),
+                OutputValue::UInt32(v) => json::JsonValue::from(v),
+                OutputValue::UInt64(v) => json::JsonValue::from(v),
+                OutputValue::Int32(v) => json::JsonValue::from(v),
+                // go through the shortest f32 representation to avoid
+                // printing widening error
+                OutputValue::Float32(v) => {
+                    json::JsonValue::from(v.to_string().parse::<f64>().unwrap())
+                }
+                OutputValue::Utf8(v) => json::JsonValue::from(v),
+            };
+            object.insert(name, value);
+        }
+        writeln!(self.output, "{}", object.dump())?;
+        self.nrows += 1;
+        Ok(())
+    }
+
+    fn finish(self: Box<Self>) -> Result<usize, OutputError> {
+        Box::new(self.output).finish()?;
+        Ok(self.nrows)
+    }
+}
+
+enum ColumnBuilder {
+    UInt8(Vec<u8>),
+    UInt32(Vec<u32>),
+    UInt64(Vec<u64>),
+    Int32(Vec<i32>),
+    Float32(Vec<f32>),
+    Utf8(MutableUtf8Array<i32>),
+    LargeUtf8(MutableUtf8Array<i64>),
+}
+
+impl ColumnBuilder {
+    fn new(data_type: &DataType) -> Self {
+        match data_type {
+            DataType::UInt8 => ColumnBuilder::UInt8(Vec::new()),
+            DataType::UInt32 => ColumnBuilder::UInt32(Vec::new()),
+            DataType::UInt64 => ColumnBuilder::UInt64(Vec::new()),
+            DataType::Int32 => ColumnBuilder::Int32(Vec::new()),
+            DataType::Float32 => ColumnBuilder::Float32(Vec::new()),
+            DataType::Utf8 => ColumnBuilder::Utf8(MutableUtf8Array::new()),
+            DataType::LargeUtf8 => ColumnBuilder::LargeUtf8(MutableUtf8Array::new()),
+            _ => panic!("Unsupported data type in output sink: {:?}", data_type),
+        }
+    }
+
+    fn push(&mut self, value: &OutputValue) {
+        match (self, value) {
+            (ColumnBuilder::UInt8(column), OutputValue::UInt8(v)) => column.push(*v),
+            (ColumnBuilder::UInt32(column), OutputValue::UInt32(v)) => column.push(*v),
+            (ColumnBuilder::UInt64(column), OutputValue::UInt64(v)) => column.push(*v),
+            (ColumnBuilder::Int32(column), OutputValue::Int32(v)) => column.push(*v),
+            (ColumnBuilder::Float32(column), OutputValue::Float32(v)) => column.push(*v),
+            (ColumnBuilder::Utf8(column), OutputValue::Utf8(v)) => column.push(Some(*v)),
+            (ColumnBuilder::LargeUtf8(column), OutputValue::Utf8(v)) => column.push(Some(*v)),
+            (_, value) => panic!("Output value doesn't match schema: {:?}", value),
+        }
+    }
+
+    // Take the buffered values as an array, leaving the builder empty.
+    fn take(&mut self) -> Arc<dyn array::Array> {
+        match self {
+            ColumnBuilder::UInt8(column) => {
+                Arc::new(array::UInt8Array::from_vec(std::mem::take(column)))
+            }
+            ColumnBuilder::UInt32(column) => {
+                Arc::new(array::UInt32Array::from_vec(std::mem::take(column)))
+            }
+            ColumnBuilder::UInt64(column) => {
+                Arc::new(array::UInt64Array::from_vec(std::mem::take(column)))
+            }
+            ColumnBuilder::Int32(column) => {
+                Arc::new(array::Int32Array::from_vec(std::mem::take(column)))
+            }
+            ColumnBuilder::Float32(column) => {
+                Arc::new(array::Float32Array::from_vec(std::mem::take(column)))
+            }
+            ColumnBuilder::Utf8(column) => column.as_arc(),
+            ColumnBuilder::LargeUtf8(column) => column.as_arc(),
+        }
+    }
+}
+
+enum ParquetOutput {
+    Direct(FileWriter<Box<dyn OutputWriter>>),
+    Checksummed(ChecksummedFileWriter<Box<dyn OutputWriter>>),
+}
+
+pub struct ParquetSink {
+    schema: Schema,
+    options: WriteOptions,
+    columns: Vec<ColumnBuilder>,
+    output: ParquetOutput,
+    nbuffered: usize,
+    nrows: usize,
+}
+
+impl ParquetSink {
+    pub fn new(
+        output: Box<dyn OutputWriter>,
+        schema: Schema,
+        parquet_options: ParquetWriteOptions,
+    ) -> Result<Self, OutputError> {
+        let options = parquet_options.arrow2_options();
+        let output = if parquet_options.write_statistics == WriteStatisticsLevel::PageLevel {
+            ParquetOutput::Checksummed(ChecksummedFileWriter::try_new(
+                output,
+                schema.clone(),
+                options,
+            )?)
+        } else {
+            ParquetOutput::Direct(FileWriter::try_new(output, schema.clone(), options)?)
+        };
+
+        Ok(ParquetSink {
+            columns: schema
+                .fields
+                .iter()
+                .map(|f| ColumnBuilder::new(&f.data_type))
+                .collect(),
+            schema,
+            options,
+            output,
+            nbuffered: 0,
+            nrows: 0,
+        })
+    }
+
+    fn write_row_group(&mut self) -> Result<(), OutputError> {
+        let chunk = Chunk::new(self.columns.iter_mut().map(|c| c.take()).collect());
+        let row_groups = RowGroupIterator::try_new(
+            vec![Ok(chunk)].into_iter(),
+            &self.schema,
+            self.options,
+            parquet_encodings(&self.schema),
+        )?;
+        for group in row_groups {
+            match &mut self.output {
+                ParquetOutput::Direct(writer) => writer.write(group?)?,
+                ParquetOutput::Checksummed(writer) => writer.write(group?)?,
+            }
+        }
+        self.nbuffered = 0;
+        Ok(())
+    }
+}
+
+impl OutputSink for ParquetSink {
+    fn write_row(&mut self, row: &[OutputValue]) -> Result<(), OutputError> {
+        for (column, value) in self.columns.iter_mut().zip(row) {
+            column.push(value);
+        }
+        self.nbuffered += 1;
+        self.nrows += 1;
+        if self.nbuffered >= PARQUET_SINK_ROW_GROUP_SIZE {
+            self.write_row_group()?;
+        }
+        Ok(())
+    }
+
+    fn finish(mut self: Box<Self>) -> Result<usize, OutputError> {
+        if self.nbuffered > 0 || self.nrows == 0 {
+            self.write_row_group()?;
+        }
+        match self.output {
+            ParquetOutput::Direct(mut writer) => {
+                writer.end(None)?;
+                writer.into_inner().finish()?;
+            }
+            ParquetOutput::Checksummed(mut writer) => {
+                writer.end()?;
+                writer.into_inner().finish()?;
+            }
+        }
+        Ok(self.nrows)
+    }
+}
+
+// Records the number of rows written to the table when the sink is finished,
+// as `write_table` does.
+struct RecordedSink<'a> {
+    outputs: &'a OutputBundle,
+    filename: String,
+    parquet: bool,
+    sink: Box<dyn OutputSink>,
+}
+
+impl OutputSink for RecordedSink<'_> {
+    fn write_row(&mut self, row: &[OutputValue]) -> Result<(), OutputError> {
+        self.sink.write_row(row)
+    }
+
+    fn finish(self: Box<Self>) -> Result<usize, OutputError> {
+        let nrows = self.sink.finish()?;
+        self.outputs
+            .record_table(&self.filename, self.parquet, nrows);
+        Ok(nrows)
+    }
+}
+
+// Open a sink writing a table with the given schema to `filename`.
+pub fn open_sink<'a>(
+    outputs: &'a OutputBundle,
+    filename: &str,
+    fmt: SinkFormat,
+    schema: Schema,
+) -> Result<Box<dyn OutputSink + 'a>, OutputError> {
+    let fmt = match fmt {
+        SinkFormat::Infer => infer_sink_format_from_filename(filename),
+        _ => fmt,
+    };
+
+    let schema = if schema.metadata.contains_key(SCHEMA_VERSION_KEY) {
+        schema
+    } else {
+        with_schema_version(schema, SCHEMA_VERSION)
+    };
+
+    let file = outputs.open(filename);
+    let sink: Box<dyn OutputSink> = match fmt {
+        SinkFormat::Csv => Box::new(CsvSink::new(file, &schema)?),
+        SinkFormat::CsvGz => Box::new(CsvSink::new(
+            GzEncoder::new(file, Compression::default()),
+            &schema,
+        )?),
+        SinkFormat::JsonLines => Box::new(JsonLinesSink::new(file, &schema)),
+        SinkFormat::Parquet => Box::new(ParquetSink::new(file, schema, outputs.parquet_options)?),
+        SinkFormat::Infer => panic!("Cannot infer output format for filename: {}", filename),
+    };
+
+    Ok(Box::new(RecordedSink {
+        outputs,
+        filename: filename.to_string(),
+        parquet: fmt == SinkFormat::Parquet,
+        sink,
+    }))
+}
+
+// Sink for the cell voxel table, with a row for every voxel assigned to a
+// cell.
+pub struct VoxelSink<'a> {
+    sink: Box<dyn OutputSink + 'a>,
+}
+
+impl<'a> VoxelSink<'a> {
+    pub fn open(
+        outputs: &'a OutputBundle,
+        filename: &str,
+        fmt: SinkFormat,
+    ) -> Result<Self, OutputError> {
+        let schema = Schema::from(vec![
+            Field::new("voxel_index", DataType::UInt32, false),
+            Field::new("cell", DataType::UInt32, false),
+            Field::new("x0", DataType::Float32, false),
+            Field::new("y0", DataType::Float32, false),
+            Field::new("z0", DataType::Float32, false),
+            Field::new("x1", DataType::Float32, false),
+            Field::new("y1", DataType::Float32, false),
+            Field::new("z1", DataType::Float32, false),
+            Field::new("n_transcripts", DataType::UInt32, false),
+            Field::new("n_assigned", DataType::UInt32, false),
+            Field::new("n_background", DataType::UInt32, false),
+            Field::new("transcript_density_per_um3", DataType::Float32, false),
+        ]);
+        Ok(VoxelSink {
+            sink: open_sink(outputs, filename, fmt, schema)?,
+        })
+    }
+
+    // Write a voxel with the given bounds, (x0, y0, z0, x1, y1, z1), and
+    // numbers of transcripts in total, assigned to the cell, and background.
+    pub fn write_voxel_row(
+        &mut self,
+        voxel_index: u32,
+        cell: u32,
+        bounds: (f32, f32, f32, f32, f32, f32),
+        n_transcripts: u32,
+        n_assigned: u32,
+        n_background: u32,
+    ) -> Result<(), OutputError> {
+        let (x0, y0, z0, x1, y1, z1) = bounds;
+        let volume = (x1 - x0) * (y1 - y0) * (z1 - z0);
+        self.sink.write_row(&[
+            OutputValue::UInt32(voxel_index),
+            OutputValue::UInt32(cell),
+            OutputValue::Float32(x0),
+            OutputValue::Float32(y0),
+            OutputValue::Float32(z0),
+            OutputValue::Float32(x1),
+            OutputValue::Float32(y1),
+            OutputValue::Float32(z1),
+            OutputValue::UInt32(n_transcripts),
+            OutputValue::UInt32(n_assigned),
+            OutputValue::UInt32(n_background),
+            OutputValue::Float32(n_transcripts as f32 / volume),
+        ])
+    }
+
+    pub fn finish(self) -> Result<usize, OutputError> {
+        self.sink.finish()
+    }
+}
+
+// Sink for the transcript metadata table.
+pub struct TranscriptSink<'a> {
+    sink: Box<dyn OutputSink + 'a>,
+}
+
+impl<'a> TranscriptSink<'a> {
+    pub fn open(
+        outputs: &'a OutputBundle,
+        filename: &str,
+        fmt: SinkFormat,
+    ) -> Result<Self, OutputError> {
+        let schema = with_schema_version(
+            Schema::from(vec![
+                Field::new("transcript_id", DataType::UInt64, false),
+                Field::new("x", DataType::Float32, false),
+                Field::new("y", DataType::Float32, false),
+                Field::new("z", DataType::Float32, false),
+                Field::new("observed_x", DataType::Float32, false),
+                Field::new("observed_y", DataType::Float32, false),
+                Field::new("observed_z", DataType::Float32, false),
+                Field::new("gene", DataType::Utf8, false),
+                Field::new("fov", DataType::Utf8, false),
+                Field::new("assignment", DataType::UInt32, false),
+                Field::new("probability", DataType::Float32, false),
+                Field::new("background", DataType::UInt8, false),
+                Field::new("confusion", DataType::UInt8, false),
+                Field::new("hex_bin_q", DataType::Int32, false),
+                Field::new("hex_bin_r", DataType::Int32, false),
+                Field::new("hex_bin_id", DataType::UInt64, false),
+                Field::new("is_duplicate", DataType::UInt8, false),
+                Field::new("true_positive_probability", DataType::Float32, false),
+            ]),
+            TRANSCRIPT_METADATA_SCHEMA_VERSION,
+        );
+        Ok(TranscriptSink {
+            sink: open_sink(outputs, filename, fmt, schema)?,
+        })
+    }
+
+    // Write a transcript at its inferred `position`, assigned to `cell` with
+    // probability `pr`, and in the hex bin with axial coordinates (q, r).
+    #[allow(clippy::too_many_arguments)]
+    pub fn write_transcript_row(
+        &mut self,
+        transcript: &Transcript,
+        position: (f32, f32, f32),
+        gene: &str,
+        fov: &str,
+        (cell, pr): (u32, f32),
+        state: TranscriptState,
+        (q, r): (i32, i32),
+        is_duplicate: bool,
+        true_positive_probability: f32,
+    ) -> Result<(), OutputError> {
+        let (x, y, z) = position;
+        self.sink.write_row(&[
+            OutputValue::UInt64(transcript.transcript_id),
+            OutputValue::Float32(x),
+            OutputValue::Float32(y),
+            OutputValue::Float32(z),
+            OutputValue::Float32(transcript.x),
+            OutputValue::Float32(transcript.y),
+            OutputValue::Float32(transcript.z),
+            OutputValue::Utf8(gene),
+            OutputValue::Utf8(fov),
+            OutputValue::UInt32(cell),
+            OutputValue::Float32(pr),
+            OutputValue::UInt8((state == TranscriptState::Background) as u8),
+            OutputValue::UInt8((state == TranscriptState::Confusion) as u8),
+            OutputValue::Int32(q),
+            OutputValue::Int32(r),
+            OutputValue::UInt64(HexBinner::bin_id(q, r)),
+            OutputValue::UInt8(is_duplicate as u8),
+            OutputValue::Float32(true_positive_probability),
+        ])
+    }
+
+    pub fn finish(self) -> Result<usize, OutputError> {
+        self.sink.finish()
+    }
+}
+
+#[test]
+fn csv_sink_writes_rows() {
+    let schema = Schema::from(vec![
+        arrow2::datatypes::Field::new("cell", DataType::UInt32, false),
+        arrow2::datatypes::Field::new("x", DataType::Float32, false),
+        arrow2::datatypes::Field::new("gene", DataType::Utf8, false),
+    ]);
+    let mut output = Vec::new();
+    let mut sink: Box<dyn OutputSink> = Box::new(CsvSink::new(&mut output, &schema).unwrap());
+    sink.write_row(&[
+        OutputValue::UInt32(3),
+        OutputValue::Float32(2.0),
+        OutputValue::Utf8("a,b"),
+    ])
+    .unwrap();
+    assert_eq!(sink.finish().unwrap(), 1);
+    assert_eq!(
+        String::from_utf8(output).unwrap(),
+        "cell,x,gene\n3,2.0,\"a,b\"\n"
+    );
+}
@@ -1,12 +1,14 @@
 mod connectivity;
-pub mod voxelsampler;
 pub mod hull;
 mod math;
 pub mod polyagamma;
 mod polygons;
 mod sampleset;
 pub mod transcripts;
+pub mod voxelsampler;
 
+use super::cloud::OutputWriter;
+use super::output::OutputBundle;
 use core::fmt::Debug;
 use flate2::write::GzEncoder;
 use flate2::Compression;
@@ -25,10 +27,10 @@ use polyagamma::PolyaGamma;
 use rand::{thread_rng, Rng};
 use rand_distr::{Dirichlet, Distribution, Gamma, Normal, StandardNormal};
 use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
 use std::cell::RefCell;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::f32;
-use std::fs::File;
 use std::io::Write;
 use std::iter::Iterator;
 use thread_local::ThreadLocal;
@@ -60,7 +62,7 @@ fn chunkquad(x: f32, y: f32, xmin: f32, ymin: f32, chunk_size: f32, nxchunks: us
     (chunk, quad)
 }
 
-#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+#[derive(PartialEq, Eq, Clone, Copy, Debug, Serialize, Deserialize)]
 pub enum TranscriptState {
     Background,
     Foreground,
@@ -131,7 +133,52 @@ pub struct ModelPriors {
     pub enforce_connectivity: bool,
 }
 
+impl ModelPriors {
+    // Prior mean of the NB dispersion r, taking the rate h of its gamma prior
+    // at the mean of h's own prior.
+    pub fn r_mean(&self) -> f32 {
+        self.e_r * self.f_h / self.e_h
+    }
+
+    // Prior mean of an expression rate λ ~ Gamma(r, exp(-φ)), with
+    // φ ~ Normal(0, 1/γ) so that E[exp(φ)] = exp(1/(2γ)).
+    pub fn λ_mean(&self) -> f32 {
+        self.r_mean() * (0.5 / self.γ).exp()
+    }
+}
+
+// Copy of the rate and dispersion parameters at a point during sampling,
+// used to report how far they move.
+pub struct ParamsSnapshot {
+    pub λ: Array2<f32>,
+    pub r: Array2<f32>,
+}
+
 // Model global parameters.
+// Sampled state of `ModelParams`, from which sampling can be resumed. Counts
+// and other quantities derived from it are recomputed on restore.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct ModelState {
+    pub transcript_positions: Vec<(f32, f32, f32)>,
+    pub cell_assignments: Vec<CellIndex>,
+    pub cell_assignment_time: Vec<u32>,
+    pub cell_volume: Array1<f32>,
+    pub transcript_state: Vec<TranscriptState>,
+    pub z: Vec<u32>,
+    pub π: Vec<f32>,
+    pub μ_volume: Array1<f32>,
+    pub σ_volume: Array1<f32>,
+    pub h: f32,
+    pub φ: Array2<f32>,
+    pub μ_φ: Array2<f32>,
+    pub σ_φ: Array2<f32>,
+    pub r: Array2<f32>,
+    pub λ: Array2<f32>,
+    pub λ_bg: Array2<f32>,
+    pub λ_c: Array1<f32>,
+    pub t: u32,
+}
+
 pub struct ModelParams {
     pub transcript_positions: Vec<(f32, f32, f32)>,
     proposed_transcript_positions: Vec<(f32, f32, f32)>,
@@ -237,6 +284,16 @@ pub struct ModelParams {
     // [ngenes] confusion: rate at which we halucinate transcripts within cells
     pub λ_c: Array1<f32>,
 
+    // running moments of λ and r across recorded samples
+    λ_moments: Option<RunningVariance>,
+    r_moments: Option<RunningVariance>,
+
+    // [ngenes, ncells] posterior variance of λ
+    pub λ_variance: Option<Array2<f32>>,
+
+    // [ncomponents, ngenes] posterior variance of r
+    pub r_variance: Option<Array2<f32>>,
+
     // time, which is incremented after every iteration
     t: u32,
 }
@@ -382,6 +439,10 @@ impl ModelParams {
             λ: Array2::<f32>::from_elem((ngenes, ncells), 0.1),
             λ_bg: Array2::<f32>::from_elem((ngenes, nlayers), 0.0),
             λ_c: Array1::<f32>::from_elem(ngenes, 1e-4),
+            λ_moments: None,
+            r_moments: None,
+            λ_variance: None,
+            r_variance: None,
             t: 0,
         }
     }
@@ -390,7 +451,80 @@ impl ModelParams {
         self.π.len()
     }
 
-    fn zlayer(&self, z: f32) -> usize {
+    pub fn state(&self) -> ModelState {
+        ModelState {
+            transcript_positions: self.transcript_positions.clone(),
+            cell_assignments: self.cell_assignments.clone(),
+            cell_assignment_time: self.cell_assignment_time.clone(),
+            cell_volume: self.cell_volume.clone(),
+            transcript_state: self.transcript_state.to_vec(),
+            z: self.z.to_vec(),
+            π: self.π.clone(),
+            μ_volume: self.μ_volume.clone(),
+            σ_volume: self.σ_volume.clone(),
+            h: self.h,
+            φ: self.φ.clone(),
+            μ_φ: self.μ_φ.clone(),
+            σ_φ: self.σ_φ.clone(),
+            r: self.r.clone(),
+            λ: self.λ.clone(),
+            λ_bg: self.λ_bg.clone(),
+            λ_c: self.λ_c.clone(),
+            t: self.t,
+        }
+    }
+
+    // Replace the sampled state with one saved from a run on the same
+    // transcripts and model dimensions.
+    pub fn restore_state(&mut self, state: ModelState, transcripts: &[Transcript]) {
+        assert_eq!(state.cell_assignments.len(), transcripts.len());
+        assert_eq!(state.z.len(), self.ncells());
+        assert_eq!(state.φ.dim(), self.φ.dim());
+        assert_eq!(state.λ_bg.dim(), self.λ_bg.dim());
+
+        self.proposed_transcript_positions = state.transcript_positions.clone();
+        self.transcript_positions = state.transcript_positions;
+        self.cell_assignments = state.cell_assignments;
+        self.cell_assignment_time = state.cell_assignment_time;
+        self.cell_volume = state.cell_volume;
+        self.transcript_state = Array1::from_vec(state.transcript_state);
+        self.prev_transcript_state = self.transcript_state.clone();
+        self.z = Array1::from_vec(state.z);
+        self.π = state.π;
+        self.μ_volume = state.μ_volume;
+        self.σ_volume = state.σ_volume;
+        self.h = state.h;
+        self.φ = state.φ;
+        self.μ_φ = state.μ_φ;
+        self.σ_φ = state.σ_φ;
+        self.r = state.r;
+        self.λ = state.λ;
+        self.λ_bg = state.λ_bg;
+        self.λ_c = state.λ_c;
+        self.t = state.t;
+
+        self.lgamma_r = self.r.mapv(lgammaf);
+        self.cell_population.fill(0);
+        for &cell in &self.cell_assignments {
+            if cell != BACKGROUND_CELL {
+                self.cell_population[cell as usize] += 1;
+            }
+        }
+        self.recompute_counts(transcripts);
+    }
+
+    pub fn snapshot(&self) -> ParamsSnapshot {
+        ParamsSnapshot {
+            λ: self.λ.clone(),
+            r: self.r.clone(),
+        }
+    }
+
+    pub fn layer_depth(&self) -> f32 {
+        self.layer_depth
+    }
+
+    pub fn zlayer(&self, z: f32) -> usize {
         let layer = ((z - self.z0) / self.layer_depth).max(0.0) as usize;
         layer.min(self.nlayers() - 1)
     }
@@ -442,6 +576,69 @@ impl ModelParams {
         self.total_gene_counts.shape()[1]
     }
 
+    // Probability that each transcript is a true positive: assigned to a cell
+    // (with the given assignment probability) and, under the current rates,
+    // generated by that cell rather than background or confusion.
+    pub fn true_positive_probabilities(
+        &self,
+        transcripts: &[Transcript],
+        cell_assignments: &[(u32, f32)],
+    ) -> Vec<f32> {
+        transcripts
+            .par_iter()
+            .zip(&self.transcript_positions)
+            .zip(cell_assignments)
+            .map(|((t, position), &(cell, pr))| {
+                if cell == BACKGROUND_CELL {
+                    return 0.0;
+                }
+                let gene = t.gene as usize;
+                let layer = self.zlayer(position.2);
+
+                let λ_cell = self.λ[[gene, cell as usize]];
+                let λ_bg = self.λ_bg[[gene, layer]];
+                let λ_c = self.λ_c[gene];
+                let λ = λ_cell + λ_bg + λ_c;
+
+                (1.0 - λ_bg / λ) * (1.0 - λ_c / λ) * pr.min(1.0)
+            })
+            .collect()
+    }
+
+    // Mean expression rate of the cells currently assigned to each component.
+    pub fn component_mean_rates(&self) -> Array2<f32> {
+        let mut λ_components = Array2::<f32>::zeros((self.ncomponents(), self.ngenes()));
+        let mut counts = vec![0; self.ncomponents()];
+        Zip::from(&self.z).and(self.λ.columns()).for_each(|&z, λ| {
+            let mut λ_component = λ_components.row_mut(z as usize);
+            Zip::from(&mut λ_component).and(λ).for_each(|a, b| *a += b);
+            counts[z as usize] += 1;
+        });
+
+        for (mut λ_component, count) in λ_components.outer_iter_mut().zip(counts) {
+            λ_component /= count as f32;
+        }
+
+        λ_components
+    }
+
+    // Accumulate the current values of λ and r into running estimates of
+    // their posterior variance.
+    fn record_parameter_moments(&mut self) {
+        self.λ_moments
+            .get_or_insert_with(|| RunningVariance::new(self.λ.dim()))
+            .push(&self.λ);
+        self.r_moments
+            .get_or_insert_with(|| RunningVariance::new(self.r.dim()))
+            .push(&self.r);
+    }
+
+    // Compute posterior variances from recorded samples, if any were recorded.
+    pub fn finish_parameter_variance(&mut self) {
+        self.λ_variance = self.λ_moments.as_ref().map(|m| m.variance());
+        self.r_variance = self.r_moments.as_ref().map(|m| m.variance());
+    }
+
     pub fn log_likelihood(&self, priors: &ModelPriors) -> f32 {
         // iterate over cells
         let mut ll = Zip::from(self.λ.columns())
@@ -526,6 +723,7 @@ impl ModelParams {
 
     pub fn write_cell_hulls(
         &self,
+        outputs: &OutputBundle,
         transcripts: &[Transcript],
         counts: &Array2<u32>,
         filename: &str,
@@ -539,7 +737,7 @@ impl ModelParams {
             }
         }
 
-        let file = File::create(filename).unwrap();
+        let file = outputs.open(filename);
         let mut encoder = GzEncoder::new(file, Compression::default());
         writeln!(
             encoder,
@@ -601,6 +799,45 @@ impl ModelParams {
         }
 
         writeln!(encoder, "\n  ]\n}}").unwrap();
+        Box::new(encoder)
+            .finish()
+            .unwrap_or_else(|err| panic!("Error writing {}: {}", filename, err));
+    }
+}
+
+// Welford's online algorithm for the elementwise mean and variance of a
+// sequence of matrices.
+struct RunningVariance {
+    n: u32,
+    mean: Array2<f32>,
+    m2: Array2<f32>,
+}
+
+impl RunningVariance {
+    fn new(dim: (usize, usize)) -> Self {
+        RunningVariance {
+            n: 0,
+            mean: Array2::zeros(dim),
+            m2: Array2::zeros(dim),
+        }
+    }
+
+    fn push(&mut self, x: &Array2<f32>) {
+        self.n += 1;
+        let n = self.n as f32;
+        Zip::from(&mut self.mean)
+            .and(&mut self.m2)
+            .and(x)
+            .par_for_each(|mean, m2, &x| {
+                let δ = x - *mean;
+                *mean += δ / n;
+                *m2 += δ * (x - *mean);
+            });
+    }
+
+    fn variance(&self) -> Array2<f32> {
+        let denom = (self.n.max(2) - 1) as f32;
+        self.m2.map(|m2| m2 / denom)
     }
 }
 
@@ -645,6 +882,165 @@ impl ProposalStats {
     }
 }
 
+// Number of active cells (cells with at least one voxel) after each
+// iteration, along with the number of cells appearing or disappearing between
+// recorded iterations.
+pub struct CellCountTrace {
+    pub trace: Vec<(u32, u32)>,
+    pub n_births: u32,
+    pub n_deaths: u32,
+    active: Vec<bool>,
+}
+
+impl CellCountTrace {
+    pub fn new() -> Self {
+        CellCountTrace {
+            trace: Vec::new(),
+            n_births: 0,
+            n_deaths: 0,
+            active: Vec::new(),
+        }
+    }
+
+    pub fn record(&mut self, iteration: usize, cell_voxel_counts: &[u32]) {
+        if self.active.len() < cell_voxel_counts.len() {
+            self.active.resize(cell_voxel_counts.len(), false);
+        }
+
+        let mut n_active = 0;
+        for (was_active, &count) in self.active.iter_mut().zip(cell_voxel_counts) {
+            let is_active = count > 0;
+            if is_active {
+                n_active += 1;
+            }
+
+            // Cells are all considered active before the first iteration.
+            if !self.trace.is_empty() {
+                if is_active && !*was_active {
+                    self.n_births += 1;
+                } else if !is_active && *was_active {
+                    self.n_deaths += 1;
+                }
+            }
+            *was_active = is_active;
+        }
+
+        self.trace.push((iteration as u32, n_active));
+    }
+}
+
+// Records transcript cell assignments every `interval` iterations over the
+// final iterations of sampling, to find cells whose assignments are still in
+// flux.
+pub struct TranscriptAssignmentTracker {
+    start: usize,
+    interval: usize,
+    nintervals: u32,
+    prev_assignments: Option<Vec<CellIndex>>,
+    // distinct (transcript, cell) pairs where the transcript moved in or out
+    changed: HashSet<(u32, CellIndex)>,
+    // number of moves in or out of each cell
+    change_events: Vec<u32>,
+}
+
+impl TranscriptAssignmentTracker {
+    // Track the last `window` of `total_iterations` iterations.
+    pub fn new(total_iterations: usize, window: usize, interval: usize) -> Self {
+        TranscriptAssignmentTracker {
+            start: total_iterations.saturating_sub(window),
+            interval: interval.max(1),
+            nintervals: 0,
+            prev_assignments: None,
+            changed: HashSet::new(),
+            change_events: Vec::new(),
+        }
+    }
+
+    pub fn record(&mut self, iteration: usize, params: &ModelParams) {
+        if iteration < self.start || !(iteration - self.start).is_multiple_of(self.interval) {
+            return;
+        }
+
+        let assignments = current_assignments(params);
+        if self.change_events.len() < params.ncells() {
+            self.change_events.resize(params.ncells(), 0);
+        }
+
+        if let Some(prev_assignments) = &self.prev_assignments {
+            for (t, (&prev, &cell)) in prev_assignments.iter().zip(&assignments).enumerate() {
+                if prev == cell {
+                    continue;
+                }
+                for c in [prev, cell] {
+                    if c != BACKGROUND_CELL {
+                        self.changed.insert((t as u32, c));
+                        self.change_events[c as usize] += 1;
+                    }
+                }
+            }
+            self.nintervals += 1;
+        }
+
+        self.prev_assignments = Some(assignments);
+    }
+
+    // Per-cell number of distinct transcripts that moved in or out, the
+    // fraction of the cell's transcripts that did so, and the rate of moves
+    // per transcript per recorded interval.
+    pub fn instability(&self, ncells: usize) -> (Vec<u32>, Vec<f32>, Vec<f32>) {
+        let mut n_changes = vec![0_u32; ncells];
+        for &(_, cell) in self.changed.iter() {
+            n_changes[cell as usize] += 1;
+        }
+
+        // transcripts that were assigned to each cell at any point
+        let mut n_members = n_changes.clone();
+        if let Some(assignments) = &self.prev_assignments {
+            for (t, &cell) in assignments.iter().enumerate() {
+                if cell != BACKGROUND_CELL && !self.changed.contains(&(t as u32, cell)) {
+                    n_members[cell as usize] += 1;
+                }
+            }
+        }
+
+        let fraction_unstable = n_changes
+            .iter()
+            .zip(&n_members)
+            .map(|(&n, &m)| if m > 0 { n as f32 / m as f32 } else { 0.0 })
+            .collect();
+
+        let instability_score = (0..ncells)
+            .map(|c| {
+                let events = self.change_events.get(c).cloned().unwrap_or(0);
+                let denom = n_members[c] as f32 * self.nintervals as f32;
+                if denom > 0.0 {
+                    events as f32 / denom
+                } else {
+                    0.0
+                }
+            })
+            .collect();
+
+        (n_changes, fraction_unstable, instability_score)
+    }
+}
+
+// Cell assignments with non-foreground transcripts treated as background.
+fn current_assignments(params: &ModelParams) -> Vec<CellIndex> {
+    params
+        .cell_assignments
+        .iter()
+        .zip(params.transcript_state.iter())
+        .map(|(&cell, &state)| {
+            if state == TranscriptState::Foreground {
+                cell
+            } else {
+                BACKGROUND_CELL
+            }
+        })
+        .collect()
+}
+
 pub struct UncertaintyTracker {
     cell_assignment_duration: HashMap<(usize, CellIndex), u32>,
 }
@@ -1168,6 +1564,10 @@ where
         self.sample_rates(priors, params);
         // println!("  Sample λ: {:?}", t0.elapsed());
 
+        if uncertainty.is_some() {
+            params.record_parameter_moments();
+        }
+
         // TODO:
         // This is the most expensive part. We could sample this less frequently,
         // but we should try to optimize as much as possible.
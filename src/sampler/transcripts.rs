@@ -363,6 +363,38 @@ where
 //     }
 // }
 
+// Read FOV bounding boxes as (x_min, y_min, x_max, y_max) from a CSV file
+// with those columns.
+pub fn read_fov_bounds_csv(path: &str, coordinate_scale: f32) -> Vec<(f32, f32, f32, f32)> {
+    let file = File::open(path).unwrap_or_else(|err| panic!("Unable to open {}: {}", path, err));
+    let mut rdr = match infer_format_from_filename(path) {
+        OutputFormat::CsvGz => {
+            csv::Reader::from_reader(Box::new(GzDecoder::new(file)) as Box<dyn std::io::Read>)
+        }
+        OutputFormat::Csv => csv::Reader::from_reader(Box::new(file) as Box<dyn std::io::Read>),
+        _ => panic!("FOV bounds must be in csv or csv.gz format: {}", path),
+    };
+
+    let headers = rdr.headers().unwrap().clone();
+    let x_min_col = find_column(&headers, "x_min");
+    let y_min_col = find_column(&headers, "y_min");
+    let x_max_col = find_column(&headers, "x_max");
+    let y_max_col = find_column(&headers, "y_max");
+
+    rdr.records()
+        .map(|record| {
+            let record = record.unwrap();
+            let parse = |col: usize| coordinate_scale * record[col].parse::<f32>().unwrap();
+            (
+                parse(x_min_col),
+                parse(y_min_col),
+                parse(x_max_col),
+                parse(y_max_col),
+            )
+        })
+        .collect()
+}
+
 pub fn coordinate_span(transcripts: &Vec<Transcript>) -> (f32, f32, f32, f32, f32, f32) {
     let mut min_x = std::f32::MAX;
     let mut max_x = std::f32::MIN;
@@ -478,39 +510,52 @@ pub fn filter_cellfree_transcripts(
         }
     }
 
+    retain_transcripts(dataset, &mask);
+}
+
+// Keep only the transcripts where `mask` is true.
+pub fn retain_transcripts(dataset: &mut TranscriptDataset, mask: &[bool]) {
     dataset.transcripts.clone_from(
-        &dataset.transcripts
+        &dataset
+            .transcripts
             .iter()
             .zip(mask.iter())
             .filter(|(_, &m)| m)
             .map(|(t, _)| t)
             .cloned()
-            .collect::<Vec<_>>());
+            .collect::<Vec<_>>(),
+    );
 
     dataset.nucleus_assignments.clone_from(
-        &dataset.nucleus_assignments
+        &dataset
+            .nucleus_assignments
             .iter()
             .zip(mask.iter())
             .filter(|(_, &m)| m)
             .map(|(t, _)| t)
             .cloned()
-            .collect::<Vec<_>>());
+            .collect::<Vec<_>>(),
+    );
 
     dataset.cell_assignments.clone_from(
-        &dataset.cell_assignments
+        &dataset
+            .cell_assignments
             .iter()
             .zip(mask.iter())
             .filter(|(_, &m)| m)
             .map(|(t, _)| t)
             .cloned()
-            .collect::<Vec<_>>());
+            .collect::<Vec<_>>(),
+    );
 
     dataset.fovs.clone_from(
-        &dataset.fovs
+        &dataset
+            .fovs
             .iter()
             .zip(mask.iter())
             .filter(|(_, &m)| m)
             .map(|(t, _)| t)
             .cloned()
-            .collect::<Vec<_>>());
+            .collect::<Vec<_>>(),
+    );
 }
@@ -3,16 +3,19 @@ use super::math::relerr;
 use super::polygons::PolygonBuilder;
 use super::sampleset::SampleSet;
 use super::transcripts::{coordinate_span, CellIndex, Transcript, BACKGROUND_CELL};
-use super::{chunkquad, perimeter_bound, ModelParams, ModelPriors, Proposal, Sampler};
+use super::{
+    chunkquad, perimeter_bound, ModelParams, ModelPriors, Proposal, Sampler, TranscriptState,
+};
 
 // use hexx::{Hex, HexLayout, HexOrientation, Vec2};
 // use arrow;
 use geo::geometry::{MultiPolygon, Polygon};
 use geo::BooleanOps;
 use itertools::Itertools;
-use ndarray::Array2;
+use ndarray::{Array2, Axis};
 use rand::{thread_rng, Rng};
 use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
 use std::cell::RefCell;
 use std::cmp::{Ord, Ordering, PartialEq, PartialOrd};
 use std::collections::{HashMap, HashSet};
@@ -58,7 +61,7 @@ fn clip_z_position(position: (f32, f32, f32), zmin: f32, zmax: f32) -> (f32, f32
     )
 }
 
-#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub struct Voxel {
     pub i: i32,
     pub j: i32,
@@ -199,7 +202,7 @@ impl Ord for Voxel {
     }
 }
 
-#[derive(Debug)]
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub struct VoxelLayout {
     origin: (f32, f32, f32),
     size: (f32, f32, f32),
@@ -338,6 +341,30 @@ impl VoxelCellMap {
 }
 
 // Initial binning of the transcripts
+// Voxel of each transcript in the initial voxel layout, with voxels `scale`
+// wide and `zlayers` layers.
+pub fn transcript_voxels(transcripts: &Vec<Transcript>, scale: f32, zlayers: usize) -> Vec<Voxel> {
+    let (_, _, _, _, zmin, zmax) = coordinate_span(transcripts);
+
+    let mut height = zmax - zmin;
+    if height == 0.0 {
+        height = 1.0;
+    }
+
+    let voxel_height = height / zlayers as f32;
+
+    let voxel_size = scale;
+    let layout = VoxelLayout {
+        origin: (0.0, 0.0, zmin),
+        size: (voxel_size, voxel_size, voxel_height),
+    };
+
+    transcripts
+        .par_iter()
+        .map(|t| layout.world_pos_to_voxel(clip_z_position((t.x, t.y, t.z), zmin, zmax)))
+        .collect()
+}
+
 fn bin_transcripts(
     transcripts: &Vec<Transcript>,
     scale: f32,
@@ -358,14 +385,10 @@ fn bin_transcripts(
         size: (voxel_size, voxel_size, voxel_height),
     };
 
-    let mut voxel_transcripts = transcripts
-        .par_iter()
+    let mut voxel_transcripts = transcript_voxels(transcripts, scale, zlayers)
+        .into_iter()
         .enumerate()
-        .map(|(i, t)| {
-            let position = clip_z_position((t.x, t.y, t.z), zmin, zmax);
-            let voxel = layout.world_pos_to_voxel(position);
-            (voxel, i)
-        })
+        .map(|(i, voxel)| (voxel, i))
         .collect::<Vec<_>>();
 
     voxel_transcripts.par_sort_unstable_by_key(|(voxel, _)| *voxel);
@@ -418,6 +441,16 @@ fn voxel_assignments(voxel_bins: &Vec<VoxelBin>, cell_assignments: &[CellIndex])
     voxel_cells
 }
 
+// Voxels and their assignment to cells, from which a `VoxelSampler` can be
+// restored.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct VoxelState {
+    pub layout: VoxelLayout,
+    pub voxel_layers: usize,
+    pub voxel_volume: f32,
+    pub voxel_cells: Vec<(Voxel, CellIndex)>,
+}
+
 pub struct VoxelSampler {
     chunkquad: ChunkQuadMap,
     transcript_genes: Vec<u32>,
@@ -585,8 +618,6 @@ impl VoxelSampler {
     // Allocate a new RectBinSampler with the same state as this one, but
     // grid resolution doubled (i.e. rect size halved).
     pub fn double_resolution(&self, params: &ModelParams, double_z_layers: bool) -> VoxelSampler {
-        let nchunks = self.mismatch_edges[0].len();
-        let ngenes = self.proposals[0].genepop.shape()[0];
         let voxel_volume = if double_z_layers {
             self.voxel_volume / 8.0
         } else {
@@ -599,9 +630,6 @@ impl VoxelSampler {
             self.chunkquad.layout.double_resolution()
         };
 
-        let proposals = vec![VoxelProposal::new(ngenes, self.nlayers); nchunks];
-        let connectivity_checker = ThreadLocal::new();
-
         let mut voxel_cells = VoxelCellMap::new();
 
         // 1.3s
@@ -641,6 +669,30 @@ impl VoxelSampler {
         }
         // println!("cubebins: {:?}", t0.elapsed());
 
+        let voxellayers = if double_z_layers {
+            self.voxel_layers * 2
+        } else {
+            self.voxel_layers
+        };
+
+        self.with_voxel_cells(params, layout, voxellayers, voxel_volume, voxel_cells)
+    }
+
+    // Allocate a new VoxelSampler with the same transcripts and chunks as this
+    // one, but the given voxels and their assignment to cells.
+    fn with_voxel_cells(
+        &self,
+        params: &ModelParams,
+        layout: VoxelLayout,
+        voxellayers: usize,
+        voxel_volume: f32,
+        voxel_cells: VoxelCellMap,
+    ) -> VoxelSampler {
+        let nchunks = self.mismatch_edges[0].len();
+        let ngenes = self.proposals[0].genepop.shape()[0];
+        let proposals = vec![VoxelProposal::new(ngenes, self.nlayers); nchunks];
+        let connectivity_checker = ThreadLocal::new();
+
         // initialize mismatch_edges
         let mut mismatch_edges = [Vec::new(), Vec::new(), Vec::new(), Vec::new()];
         for chunks in mismatch_edges.iter_mut() {
@@ -649,12 +701,6 @@ impl VoxelSampler {
             }
         }
 
-        let voxellayers = if double_z_layers {
-            self.voxel_layers * 2
-        } else {
-            self.voxel_layers
-        };
-
         let cell_population =
             Array2::from_elem((voxellayers, self.cell_population.shape()[1]), 0.0_f32);
         let cell_perimeter =
@@ -710,6 +756,41 @@ impl VoxelSampler {
         sampler
     }
 
+    pub fn state(&self) -> VoxelState {
+        let mut voxel_cells = self
+            .voxel_cells
+            .iter()
+            .filter(|(_, &cell)| cell != BACKGROUND_CELL)
+            .map(|(&voxel, &cell)| (voxel, cell))
+            .collect::<Vec<_>>();
+        voxel_cells.sort_unstable();
+
+        VoxelState {
+            layout: self.chunkquad.layout.clone(),
+            voxel_layers: self.voxel_layers,
+            voxel_volume: self.voxel_volume,
+            voxel_cells,
+        }
+    }
+
+    // Allocate a new VoxelSampler with voxels saved from a run on the same
+    // transcripts, for use with model parameters restored from the same run.
+    pub fn restore_state(&self, params: &ModelParams, state: &VoxelState) -> VoxelSampler {
+        let mut voxel_cells = VoxelCellMap::new();
+        for &(voxel, cell) in &state.voxel_cells {
+            assert!((cell as usize) < self.ncells());
+            voxel_cells.insert(voxel, cell);
+        }
+
+        self.with_voxel_cells(
+            params,
+            state.layout.clone(),
+            state.voxel_layers,
+            state.voxel_volume,
+            voxel_cells,
+        )
+    }
+
     fn recompute_cell_volume(&mut self, priors: &ModelPriors, params: &mut ModelParams) {
         // recompute cell areas as the sum of rect areas
         params.cell_volume.fill(0.0_f32);
@@ -797,6 +878,93 @@ impl VoxelSampler {
             .map(|(voxel, cell)| (*cell, self.chunkquad.layout.voxel_to_world_coords(*voxel)));
     }
 
+    // For each voxel assigned to a cell, in the same order as `voxels`, the
+    // number of transcripts it contains, the number of those assigned to the
+    // voxel's cell, and the number assigned to the background.
+    pub fn voxel_transcript_counts(&self, params: &ModelParams) -> Vec<(u32, u32, u32)> {
+        let mut counts: HashMap<Voxel, (u32, u32, u32)> = HashMap::new();
+        for (i, &voxel) in self.transcript_voxels.iter().enumerate() {
+            let cell = self.voxel_cells.get(voxel);
+            if cell == BACKGROUND_CELL {
+                continue;
+            }
+            let state = params.transcript_state[i];
+            let count = counts.entry(voxel).or_insert((0, 0, 0));
+            count.0 += 1;
+            if state == TranscriptState::Foreground && params.cell_assignments[i] == cell {
+                count.1 += 1;
+            }
+            if state == TranscriptState::Background {
+                count.2 += 1;
+            }
+        }
+
+        self.voxel_cells
+            .iter()
+            .filter(|(_, &cell)| cell != BACKGROUND_CELL)
+            .map(|(voxel, _)| counts.get(voxel).cloned().unwrap_or((0, 0, 0)))
+            .collect()
+    }
+
+    // Index of each voxel, in the same order as `voxels`.
+    pub fn voxel_indexes(&self) -> Vec<u32> {
+        let index = self.indexed_voxels();
+        self.voxel_cells
+            .iter()
+            .filter(|(_, &cell)| cell != BACKGROUND_CELL)
+            .map(|(voxel, _)| index[voxel])
+            .collect()
+    }
+
+    // Voxels that are either assigned to a cell or contain transcripts, in
+    // sorted order. Positions in this list are used as voxel indexes.
+    fn indexed_voxels(&self) -> HashMap<Voxel, u32> {
+        let mut voxels: Vec<Voxel> = self
+            .voxel_cells
+            .iter()
+            .filter(|(_, &cell)| cell != BACKGROUND_CELL)
+            .map(|(voxel, _)| *voxel)
+            .chain(self.transcript_voxels.iter().cloned())
+            .collect();
+        voxels.par_sort_unstable();
+        voxels.dedup();
+        voxels
+            .iter()
+            .enumerate()
+            .map(|(index, voxel)| (*voxel, index as u32))
+            .collect()
+    }
+
+    // Non-zero entries of the voxel by cell assignment matrix as
+    // (voxel index, voxel, cell) triples.
+    pub fn voxel_cell_incidence(&self) -> Vec<(u32, Voxel, CellIndex)> {
+        let index = self.indexed_voxels();
+        let mut voxel_cells: Vec<(u32, Voxel, CellIndex)> = self
+            .voxel_cells
+            .iter()
+            .filter(|(_, &cell)| cell != BACKGROUND_CELL)
+            .map(|(voxel, &cell)| (index[voxel], *voxel, cell))
+            .collect();
+        voxel_cells.par_sort_unstable();
+        voxel_cells
+    }
+
+    // Non-zero transcript counts per voxel as (voxel index, voxel, gene,
+    // count).
+    pub fn voxel_gene_counts(&self) -> Vec<(u32, Voxel, u32, u32)> {
+        let index = self.indexed_voxels();
+        let mut counts: HashMap<(Voxel, u32), u32> = HashMap::new();
+        for (voxel, &gene) in self.transcript_voxels.iter().zip(&self.transcript_genes) {
+            *counts.entry((*voxel, gene)).or_insert(0) += 1;
+        }
+        let mut counts: Vec<(u32, Voxel, u32, u32)> = counts
+            .iter()
+            .map(|(&(voxel, gene), &count)| (index[&voxel], voxel, gene, count))
+            .collect();
+        counts.par_sort_unstable();
+        counts
+    }
+
     pub fn cell_centroids(&self) -> Vec<(f32, f32, f32)> {
         let mut centroids = vec![(0.0, 0.0, 0.0); self.ncells()];
         let mut counts = vec![0; self.ncells()];
@@ -821,6 +989,155 @@ impl VoxelSampler {
         centroids
     }
 
+    // Per-cell, per-layer xy centroid and voxel count. [ncells][voxel layers]
+    pub fn cell_layer_centroids(&self) -> Vec<Vec<(f32, f32, u32)>> {
+        let mut centroids = vec![vec![(0.0, 0.0, 0); self.voxel_layers]; self.ncells()];
+        for (&voxel, &cell) in self.voxel_cells.iter() {
+            if cell == BACKGROUND_CELL {
+                continue;
+            }
+            let (x, y, _) = self.chunkquad.layout.voxel_to_world_pos(voxel);
+            let centroid = &mut centroids[cell as usize][voxel.k as usize];
+            centroid.0 += x;
+            centroid.1 += y;
+            centroid.2 += 1;
+        }
+
+        for cell_centroids in centroids.iter_mut() {
+            for centroid in cell_centroids.iter_mut() {
+                if centroid.2 > 0 {
+                    centroid.0 /= centroid.2 as f32;
+                    centroid.1 /= centroid.2 as f32;
+                }
+            }
+        }
+
+        centroids
+    }
+
+    // Voxel layer containing the given z coordinate.
+    pub fn z_to_voxel_layer(&self, z: f32) -> usize {
+        let k = self.chunkquad.layout.world_pos_to_voxel((0.0, 0.0, z)).k;
+        k.clamp(0, self.voxel_layers as i32 - 1) as usize
+    }
+
+    // Number of voxels assigned to each cell.
+    pub fn cell_voxel_counts(&self) -> Vec<u32> {
+        self.cell_population
+            .sum_axis(Axis(0))
+            .iter()
+            .map(|&count| count as u32)
+            .collect()
+    }
+
+    // Minimum of `distance` evaluated at the xy center of each of a cell's
+    // voxels. Cells with no voxels get infinity.
+    pub fn cell_min_voxel_distance<F>(&self, distance: F) -> Vec<f32>
+    where
+        F: Fn(f32, f32) -> f32,
+    {
+        let mut min_distances = vec![f32::INFINITY; self.ncells()];
+        for (&voxel, &cell) in self.voxel_cells.iter() {
+            if cell != BACKGROUND_CELL {
+                let (x0, y0, _, x1, y1, _) = self.chunkquad.layout.voxel_to_world_coords(voxel);
+                let d = distance((x0 + x1) / 2.0, (y0 + y1) / 2.0);
+                let min_distance = &mut min_distances[cell as usize];
+                *min_distance = min_distance.min(d);
+            }
+        }
+        min_distances
+    }
+
+    // Transcripts in voxels sharing a face with a voxel of another cell, as
+    // (transcript, cell containing it, neighboring cell), with one entry for
+    // each distinct neighboring cell.
+    pub fn boundary_transcripts(&self) -> Vec<(usize, CellIndex, CellIndex)> {
+        let mut boundary = Vec::new();
+        for (i, &voxel) in self.transcript_voxels.iter().enumerate() {
+            let cell = self.voxel_cells.get(voxel);
+            if cell == BACKGROUND_CELL {
+                continue;
+            }
+
+            let mut neighbor_cells: Vec<CellIndex> = voxel
+                .von_neumann_neighborhood()
+                .iter()
+                .map(|&neighbor| self.voxel_cells.get(neighbor))
+                .filter(|&neighbor_cell| neighbor_cell != cell && neighbor_cell != BACKGROUND_CELL)
+                .collect();
+            neighbor_cells.sort_unstable();
+            neighbor_cells.dedup();
+            boundary.extend(
+                neighbor_cells
+                    .iter()
+                    .map(|&neighbor_cell| (i, cell, neighbor_cell)),
+            );
+        }
+        boundary
+    }
+
+    // Area of the voxel faces shared by each pair of adjacent cells, keyed by
+    // (lower cell, higher cell), along with each cell's total surface area.
+    pub fn cell_shared_boundaries(&self) -> (HashMap<(CellIndex, CellIndex), f32>, Vec<f32>) {
+        let (dx, dy, dz) = self.chunkquad.layout.size;
+        // face areas in the order of `von_neumann_neighborhood`
+        let face_areas = [dy * dz, dy * dz, dx * dz, dx * dz, dx * dy, dx * dy];
+
+        let mut shared = HashMap::new();
+        let mut surface_area = vec![0.0; self.ncells()];
+        for (&voxel, &cell) in self.voxel_cells.iter() {
+            if cell == BACKGROUND_CELL {
+                continue;
+            }
+
+            for (neighbor, &area) in voxel.von_neumann_neighborhood().iter().zip(&face_areas) {
+                let neighbor_cell = self.voxel_cells.get(*neighbor);
+                if neighbor_cell == cell {
+                    continue;
+                }
+                surface_area[cell as usize] += area;
+                if neighbor_cell != BACKGROUND_CELL && cell < neighbor_cell {
+                    *shared.entry((cell, neighbor_cell)).or_insert(0.0) += area;
+                }
+            }
+        }
+
+        (shared, surface_area)
+    }
+
+    // Fraction of each cell's voxels that share a face with a voxel assigned
+    // to a different (non-background) cell.
+    pub fn cell_isolation_scores(&self) -> Vec<f32> {
+        let mut contact_counts = vec![0; self.ncells()];
+        let mut counts = vec![0; self.ncells()];
+        for (&voxel, &cell) in self.voxel_cells.iter() {
+            if cell == BACKGROUND_CELL {
+                continue;
+            }
+
+            counts[cell as usize] += 1;
+            let in_contact = voxel.von_neumann_neighborhood().iter().any(|&neighbor| {
+                let neighbor_cell = self.voxel_cells.get(neighbor);
+                neighbor_cell != cell && neighbor_cell != BACKGROUND_CELL
+            });
+            if in_contact {
+                contact_counts[cell as usize] += 1;
+            }
+        }
+
+        contact_counts
+            .iter()
+            .zip(counts.iter())
+            .map(|(&contact_count, &count)| {
+                if count > 0 {
+                    contact_count as f32 / count as f32
+                } else {
+                    0.0
+                }
+            })
+            .collect()
+    }
+
     pub fn cell_polygons(&self) -> (Vec<CellPolygonLayers>, Vec<CellPolygon>) {
         // Build sets of voxels for each cell
         let mut cell_voxels = vec![HashSet::new(); self.ncells()];
@@ -1528,6 +1845,71 @@ impl Proposal for VoxelProposal {
     }
 }
 
+// Accumulates cell centroids over the final iterations of sampling to give
+// posterior mean centroids that are less sensitive to single-iteration noise.
+pub struct CentroidTracker {
+    start: usize,
+    centroid_sums: Vec<(f64, f64, f64)>,
+    counts: Vec<u32>,
+}
+
+impl CentroidTracker {
+    // Track the last `window` of `total_iterations` iterations.
+    pub fn new(total_iterations: usize, window: usize) -> Self {
+        CentroidTracker {
+            start: total_iterations.saturating_sub(window.max(1)),
+            centroid_sums: Vec::new(),
+            counts: Vec::new(),
+        }
+    }
+
+    pub fn record(&mut self, iteration: usize, sampler: &VoxelSampler) {
+        if iteration < self.start {
+            return;
+        }
+
+        let ncells = sampler.ncells();
+        if self.counts.len() < ncells {
+            self.centroid_sums.resize(ncells, (0.0, 0.0, 0.0));
+            self.counts.resize(ncells, 0);
+        }
+
+        let voxel_counts = sampler.cell_voxel_counts();
+        for (i, ((x, y, z), &voxel_count)) in sampler
+            .cell_centroids()
+            .into_iter()
+            .zip(&voxel_counts)
+            .enumerate()
+        {
+            // empty cells have no centroid in this iteration
+            if voxel_count == 0 {
+                continue;
+            }
+            let sum = &mut self.centroid_sums[i];
+            sum.0 += x as f64;
+            sum.1 += y as f64;
+            sum.2 += z as f64;
+            self.counts[i] += 1;
+        }
+    }
+
+    // Mean centroid of each cell over the iterations in which it was
+    // non-empty. Cells that were never recorded get (0, 0, 0), matching
+    // `VoxelSampler::cell_centroids`.
+    pub fn posterior_mean_centroids(&self, ncells: usize) -> Vec<(f32, f32, f32)> {
+        (0..ncells)
+            .map(|i| match self.counts.get(i) {
+                Some(&count) if count > 0 => {
+                    let (x, y, z) = self.centroid_sums[i];
+                    let n = count as f64;
+                    ((x / n) as f32, (y / n) as f32, (z / n) as f32)
+                }
+                _ => (0.0, 0.0, 0.0),
+            })
+            .collect()
+    }
+}
+
 // We need to exclude cells that can't be initalized with a non-zero number of voxels.
 pub fn filter_sparse_cells(
     scale: f32,
@@ -1584,3 +1966,103 @@ pub fn filter_sparse_cells(
         }
     }
 }
+
+// Two square cells, 4 and 6 units wide, in a single layer, with a model and
+// sampler initialized from their nuclei.
+#[cfg(test)]
+pub fn two_square_cells() -> (Vec<Transcript>, ModelPriors, ModelParams, VoxelSampler) {
+    let mut transcripts = Vec::new();
+    let mut nucleus_assignments = Vec::new();
+    for (cell, (x0, width)) in [(0.0, 4), (20.0, 6)].iter().enumerate() {
+        for i in 0..*width {
+            for j in 0..*width {
+                transcripts.push(Transcript {
+                    transcript_id: transcripts.len() as u64,
+                    x: x0 + i as f32 + 0.5,
+                    y: j as f32 + 0.5,
+                    z: ((i + j) % 2) as f32 * 0.5,
+                    gene: (i % 2) as u32,
+                    fov: 0,
+                });
+                nucleus_assignments.push(cell as CellIndex);
+            }
+        }
+    }
+    let nucleus_population = vec![16, 36];
+
+    let priors = ModelPriors {
+        dispersion: None,
+        burnin_dispersion: None,
+        min_cell_volume: 1e-6,
+        μ_μ_volume: 0.0,
+        σ_μ_volume: 1.0,
+        α_σ_volume: 0.1,
+        β_σ_volume: 0.1,
+        α_θ: 1.0,
+        β_θ: 1.0,
+        e_r: 1.0,
+        e_h: 1.0,
+        f_h: 1.0,
+        γ: 1.0,
+        α_bg: 1.0,
+        β_bg: 1.0,
+        α_c: 1.0,
+        β_c: 1.0,
+        perimeter_eta: 5.3,
+        perimeter_bound: 1.3,
+        nuclear_reassignment_log_prob: 0.5_f32.ln(),
+        nuclear_reassignment_1mlog_prob: 0.5_f32.ln(),
+        prior_seg_reassignment_log_prob: 0.5_f32.ln(),
+        prior_seg_reassignment_1mlog_prob: 0.5_f32.ln(),
+        use_diffusion_model: false,
+        p_diffusion: 0.0,
+        σ_diffusion_proposal: 1.0,
+        σ_diffusion_near: 1.0,
+        σ_diffusion_far: 1.0,
+        σ_z_diffusion_proposal: 1.0,
+        σ_z_diffusion: 1.0,
+        zmin: 0.0,
+        zmax: 0.5,
+        enforce_connectivity: false,
+    };
+
+    let mut params = ModelParams::new(
+        &priors,
+        26.0 * 6.0 * 0.5,
+        0.0,
+        1.0,
+        &transcripts,
+        &nucleus_assignments,
+        &nucleus_population,
+        &nucleus_assignments,
+        1,
+        1,
+        2,
+        2,
+    );
+    let sampler = VoxelSampler::new(
+        &priors,
+        &mut params,
+        &transcripts,
+        2,
+        1,
+        1,
+        0.0,
+        1.0,
+        1.0,
+        10.0,
+    );
+
+    (transcripts, priors, params, sampler)
+}
+
+#[test]
+fn cell_voxel_counts_match_cell_volume() {
+    let (_transcripts, _priors, params, sampler) = two_square_cells();
+
+    let cell_voxel_counts = sampler.cell_voxel_counts();
+    assert_eq!(cell_voxel_counts, vec![16, 36]);
+    for (&n_voxels, &volume) in cell_voxel_counts.iter().zip(params.cell_volume.iter()) {
+        assert!((n_voxels as f32 * sampler.voxel_volume - volume).abs() < 1e-4 * volume);
+    }
+}